@@ -0,0 +1,708 @@
+use std::{collections::{HashMap, HashSet}, error::Error, path::Path};
+
+use meta_store::{
+    Compression, DeletedObject, MetadataStore, NewObject, Object, Placement, Principal, Segment, SegmentMove, StoredObject,
+    TcpfsError, Usage,
+};
+use redb::{
+    backends::InMemoryBackend, Database, DatabaseError, ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition,
+    WriteTransaction,
+};
+
+/*
+TABLES:
+principals        name -> (api key hash, is admin)
+principal_keys    api key hash -> name
+principal_certs   certificate fingerprint -> name
+acl               (bucket, principal) -> permissions
+bucket_settings   bucket -> encrypted
+bucket_compression bucket -> how new objects are stored (meta_store::Compression)
+bucket_sizes      bucket -> sum of its objects' sizes, kept up to date with them
+objects           id -> (bucket, key, path, size, created_at)
+key_index         (bucket, key, id) -> ()       lookups and listing by key, oldest first
+object_paths      (bucket, path) -> id          paths are unique within a bucket
+object_keys       id -> wrapped data key
+object_compression id -> compression, only for compressed objects
+stored_sizes      id -> bytes in the blob, only for compressed objects
+blobs             hash -> (path, size, refcount)
+object_blobs      id -> hash
+segments          id -> (path, size, live bytes, sealed)
+object_segments   id -> (segment, offset, length)
+segment_entries   (segment, offset, id) -> length
+counters          table -> last id handed out, ids are never reused
+everything an object owns is written and removed in the same write transaction
+*/
+
+/// redb has an error type per operation, they all end up as TcpfsError::Db unless one was raised on purpose
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+type ObjectRow = (&'static str, &'static str, &'static str, i64, &'static str);
+type IndexKey = (&'static str, &'static str, u64);
+type SegmentRow = (&'static str, i64, i64, bool);
+
+const PRINCIPALS: TableDefinition<&str, (&str, bool)> = TableDefinition::new("principals");
+const PRINCIPAL_KEYS: TableDefinition<&str, &str> = TableDefinition::new("principal_keys");
+const PRINCIPAL_CERTS: TableDefinition<&str, &str> = TableDefinition::new("principal_certs");
+const ACL: TableDefinition<(&str, &str), u8> = TableDefinition::new("acl");
+const BUCKET_SETTINGS: TableDefinition<&str, bool> = TableDefinition::new("bucket_settings");
+const BUCKET_COMPRESSION: TableDefinition<&str, u8> = TableDefinition::new("bucket_compression");
+const BUCKET_SIZES: TableDefinition<&str, i64> = TableDefinition::new("bucket_sizes");
+const OBJECTS: TableDefinition<u64, ObjectRow> = TableDefinition::new("objects");
+const KEY_INDEX: TableDefinition<IndexKey, ()> = TableDefinition::new("key_index");
+const OBJECT_PATHS: TableDefinition<(&str, &str), u64> = TableDefinition::new("object_paths");
+const OBJECT_KEYS: TableDefinition<u64, &[u8]> = TableDefinition::new("object_keys");
+const OBJECT_COMPRESSION: TableDefinition<u64, u8> = TableDefinition::new("object_compression");
+const STORED_SIZES: TableDefinition<u64, i64> = TableDefinition::new("stored_sizes");
+const BLOBS: TableDefinition<&str, (&str, i64, i64)> = TableDefinition::new("blobs");
+const OBJECT_BLOBS: TableDefinition<u64, &str> = TableDefinition::new("object_blobs");
+const SEGMENTS: TableDefinition<u64, SegmentRow> = TableDefinition::new("segments");
+const OBJECT_SEGMENTS: TableDefinition<u64, (u64, i64, i64)> = TableDefinition::new("object_segments");
+const SEGMENT_ENTRIES: TableDefinition<(u64, i64, u64), i64> = TableDefinition::new("segment_entries");
+const COUNTERS: TableDefinition<&str, u64> = TableDefinition::new("counters");
+
+/// Metadata in a single redb file, no sqlite needed
+pub struct RedbMetadataStore {
+    db: Database,
+}
+
+impl RedbMetadataStore {
+    /// Creates the file and tables if needed
+    pub fn open(path: &Path) -> meta_store::Result<Self> {
+        Ok(Self::init(Database::create(path))?)
+    }
+
+    /// Nothing survives the process, meant for tests
+    pub fn in_memory() -> meta_store::Result<Self> {
+        Ok(Self::init(Database::builder().create_with_backend(InMemoryBackend::new()))?)
+    }
+
+    // read transactions fail on tables that were never created
+    fn init(db: std::result::Result<Database, DatabaseError>) -> Result<Self> {
+        let db = db?;
+        let tx = db.begin_write()?;
+        tx.open_table(PRINCIPALS)?;
+        tx.open_table(PRINCIPAL_KEYS)?;
+        tx.open_table(PRINCIPAL_CERTS)?;
+        tx.open_table(ACL)?;
+        tx.open_table(BUCKET_SETTINGS)?;
+        tx.open_table(BUCKET_COMPRESSION)?;
+        tx.open_table(OBJECTS)?;
+        // files from before the totals were kept get them on first open
+        if tx.open_table(BUCKET_SIZES)?.is_empty()? {
+            let mut sizes = HashMap::new();
+            for entry in tx.open_table(OBJECTS)?.iter()? {
+                let (_, row) = entry?;
+                let (bucket_id, _, _, size, _) = row.value();
+                *sizes.entry(bucket_id.to_string()).or_insert(0) += size;
+            }
+            let mut bucket_sizes = tx.open_table(BUCKET_SIZES)?;
+            for (bucket_id, size) in sizes {
+                bucket_sizes.insert(bucket_id.as_str(), size)?;
+            }
+        }
+        tx.open_table(KEY_INDEX)?;
+        tx.open_table(OBJECT_PATHS)?;
+        tx.open_table(OBJECT_KEYS)?;
+        tx.open_table(OBJECT_COMPRESSION)?;
+        tx.open_table(STORED_SIZES)?;
+        tx.open_table(BLOBS)?;
+        tx.open_table(OBJECT_BLOBS)?;
+        tx.open_table(SEGMENTS)?;
+        tx.open_table(OBJECT_SEGMENTS)?;
+        tx.open_table(SEGMENT_ENTRIES)?;
+        tx.open_table(COUNTERS)?;
+        tx.commit()?;
+        Ok(RedbMetadataStore { db })
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&ReadTransaction) -> Result<T>) -> meta_store::Result<T> {
+        let run = || -> Result<T> { f(&self.db.begin_read()?) };
+        Ok(run()?)
+    }
+
+    /// commits when `f` succeeds, everything it did is dropped otherwise
+    fn write<T>(&self, f: impl FnOnce(&WriteTransaction) -> Result<T>) -> meta_store::Result<T> {
+        let run = || -> Result<T> {
+            let tx = self.db.begin_write()?;
+            let value = f(&tx)?;
+            tx.commit()?;
+            Ok(value)
+        };
+        Ok(run()?)
+    }
+}
+
+fn next_id(tx: &WriteTransaction, counter: &str) -> Result<u64> {
+    let mut counters = tx.open_table(COUNTERS)?;
+    let id = counters.get(counter)?.map(|g| g.value()).unwrap_or(0) + 1;
+    counters.insert(counter, id)?;
+    Ok(id)
+}
+
+fn to_object(id: u64, row: (&str, &str, &str, i64, &str)) -> Object {
+    let (bucket_id, key, path, file_size, _) = row;
+    Object {
+        id: id as i32,
+        bucket_id: bucket_id.to_string(),
+        key: key.to_string(),
+        path: path.to_string(),
+        file_size,
+    }
+}
+
+fn load_object(objects: &impl ReadableTable<u64, ObjectRow>, id: u64) -> Result<Object> {
+    match objects.get(id)? {
+        Some(row) => Ok(to_object(id, row.value())),
+        None => Err(format!("object {} is indexed but missing", id))?,
+    }
+}
+
+/// missing is uncompressed, a number this build doesn't know is an error
+fn compression(value: Option<u8>) -> Result<Compression> {
+    match value {
+        None => Ok(Compression::None),
+        Some(value) => Ok(Compression::from_u8(value).ok_or(format!("unknown compression {}", value))?),
+    }
+}
+
+fn to_segment(id: u64, row: (&str, i64, i64, bool)) -> Segment {
+    let (path, size, live_bytes, _) = row;
+    Segment { id: id as i64, path: path.to_string(), size, live_bytes }
+}
+
+fn load_principal(principals: &impl ReadableTable<&'static str, (&'static str, bool)>, name: &str) -> Result<Option<Principal>> {
+    Ok(principals.get(name)?.map(|row| Principal { name: name.to_string(), is_admin: row.value().1 }))
+}
+
+/// (key, id) of the objects in the bucket whose key starts with `prefix`
+fn keys_with_prefix(index: &impl ReadableTable<IndexKey, ()>, bucket_id: &str, prefix: &str) -> Result<Vec<(String, u64)>> {
+    let mut found = Vec::new();
+    for entry in index.range((bucket_id, prefix, 0)..)? {
+        let (entry, _) = entry?;
+        let (bucket, key, id) = entry.value();
+        if bucket != bucket_id || !key.starts_with(prefix) {
+            break;
+        }
+        found.push((key.to_string(), id));
+    }
+    Ok(found)
+}
+
+/// the oldest object with exactly this key
+fn find_object(index: &impl ReadableTable<IndexKey, ()>, bucket_id: &str, key: &str) -> Result<Option<u64>> {
+    match index.range((bucket_id, key, 0)..)?.next() {
+        Some(entry) => {
+            let (entry, _) = entry?;
+            let (bucket, found, id) = entry.value();
+            Ok((bucket == bucket_id && found == key).then_some(id))
+        }
+        None => Ok(None),
+    }
+}
+
+/// grow the segment by a new entry, `length` can be negative to drop one
+fn update_segment(tx: &WriteTransaction, segment_id: u64, end: i64, length: i64) -> Result<()> {
+    let mut segments = tx.open_table(SEGMENTS)?;
+    let row = segments.get(segment_id)?.map(|g| {
+        let (path, size, live_bytes, sealed) = g.value();
+        (path.to_string(), size, live_bytes, sealed)
+    });
+    if let Some((path, size, live_bytes, sealed)) = row {
+        segments.insert(segment_id, (path.as_str(), size.max(end), live_bytes + length, sealed))?;
+    }
+    Ok(())
+}
+
+/// Drop the object's shared blob reference or segment entry, returns the blobs that leaves
+/// unreferenced. None for objects with their own blob at their path.
+/// shared blobs only go once the last object referring to them does,
+/// packed objects leave dead space behind for compaction
+fn release_placement(tx: &WriteTransaction, id: u64) -> Result<Option<Vec<String>>> {
+    let shared = tx.open_table(OBJECT_BLOBS)?.remove(id)?.map(|g| g.value().to_string());
+    let packed = tx.open_table(OBJECT_SEGMENTS)?.remove(id)?.map(|g| g.value());
+    match (shared, packed) {
+        (Some(hash), _) => {
+            let mut blobs = tx.open_table(BLOBS)?;
+            let blob = blobs.get(hash.as_str())?.map(|g| {
+                let (path, size, refcount) = g.value();
+                (path.to_string(), size, refcount)
+            });
+            match blob {
+                Some((path, _, refcount)) if refcount <= 1 => {
+                    blobs.remove(hash.as_str())?;
+                    Ok(Some(vec![path]))
+                }
+                Some((path, size, refcount)) => {
+                    blobs.insert(hash.as_str(), (path.as_str(), size, refcount - 1))?;
+                    Ok(Some(Vec::new()))
+                }
+                None => Ok(Some(Vec::new())),
+            }
+        }
+        (None, Some((segment_id, offset, length))) => {
+            tx.open_table(SEGMENT_ENTRIES)?.remove((segment_id, offset, id))?;
+            update_segment(tx, segment_id, 0, -length)?;
+            Ok(Some(Vec::new()))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+fn add_bucket_size(tx: &WriteTransaction, bucket_id: &str, delta: i64) -> Result<()> {
+    let mut sizes = tx.open_table(BUCKET_SIZES)?;
+    let size = sizes.get(bucket_id)?.map(|g| g.value()).unwrap_or(0) + delta;
+    sizes.insert(bucket_id, size)?;
+    Ok(())
+}
+
+fn add_segment_entry(tx: &WriteTransaction, segment_id: u64, object_id: u64, offset: i64, length: i64) -> Result<()> {
+    tx.open_table(OBJECT_SEGMENTS)?.insert(object_id, (segment_id, offset, length))?;
+    tx.open_table(SEGMENT_ENTRIES)?.insert((segment_id, offset, object_id), length)?;
+    update_segment(tx, segment_id, offset + length, length)
+}
+
+impl MetadataStore for RedbMetadataStore {
+    fn upsert_principal(&self, name: &str, api_key_hash: &str, is_admin: bool) -> meta_store::Result<()> {
+        self.write(|tx| {
+            let mut principals = tx.open_table(PRINCIPALS)?;
+            let mut keys = tx.open_table(PRINCIPAL_KEYS)?;
+            let owner = keys.get(api_key_hash)?.map(|g| g.value().to_string());
+            if owner.is_some_and(|owner| owner != name) {
+                Err(TcpfsError::Conflict("api key belongs to another principal".to_string()))?;
+            }
+            let old = principals.insert(name, (api_key_hash, is_admin))?.map(|g| g.value().0.to_string());
+            if let Some(old) = old {
+                keys.remove(old.as_str())?;
+            }
+            keys.insert(api_key_hash, name)?;
+            Ok(())
+        })
+    }
+
+    fn get_principal_by_key_hash(&self, api_key_hash: &str) -> meta_store::Result<Option<Principal>> {
+        self.read(|tx| {
+            match tx.open_table(PRINCIPAL_KEYS)?.get(api_key_hash)? {
+                Some(name) => load_principal(&tx.open_table(PRINCIPALS)?, name.value()),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn add_principal_cert(&self, fingerprint: &str, principal: &str) -> meta_store::Result<bool> {
+        self.write(|tx| {
+            if tx.open_table(PRINCIPALS)?.get(principal)?.is_none() {
+                return Ok(false);
+            }
+            tx.open_table(PRINCIPAL_CERTS)?.insert(fingerprint, principal)?;
+            Ok(true)
+        })
+    }
+
+    fn get_principal_by_cert(&self, fingerprint: &str) -> meta_store::Result<Option<Principal>> {
+        self.read(|tx| {
+            match tx.open_table(PRINCIPAL_CERTS)?.get(fingerprint)? {
+                Some(name) => load_principal(&tx.open_table(PRINCIPALS)?, name.value()),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn grant_permissions(&self, bucket_id: &str, principal: &str, permissions: u8) -> meta_store::Result<bool> {
+        self.write(|tx| {
+            if tx.open_table(PRINCIPALS)?.get(principal)?.is_none() {
+                return Ok(false);
+            }
+            let mut acl = tx.open_table(ACL)?;
+            let held = acl.get((bucket_id, principal))?.map(|g| g.value()).unwrap_or(0);
+            acl.insert((bucket_id, principal), held | permissions)?;
+            Ok(true)
+        })
+    }
+
+    fn revoke_permissions(&self, bucket_id: &str, principal: &str, permissions: u8) -> meta_store::Result<()> {
+        self.write(|tx| {
+            let mut acl = tx.open_table(ACL)?;
+            let held = acl.get((bucket_id, principal))?.map(|g| g.value());
+            match held.map(|held| held & !permissions) {
+                Some(0) => {
+                    acl.remove((bucket_id, principal))?;
+                }
+                Some(left) => {
+                    acl.insert((bucket_id, principal), left)?;
+                }
+                None => {}
+            }
+            Ok(())
+        })
+    }
+
+    fn get_permissions(&self, bucket_id: &str, principal: &str) -> meta_store::Result<u8> {
+        self.read(|tx| {
+            let held = tx.open_table(ACL)?.get((bucket_id, principal))?.map(|g| g.value());
+            Ok(held.unwrap_or(0))
+        })
+    }
+
+    fn bucket_has_acl(&self, bucket_id: &str) -> meta_store::Result<bool> {
+        self.read(|tx| {
+            match tx.open_table(ACL)?.range((bucket_id, "")..)?.next() {
+                Some(entry) => Ok(entry?.0.value().0 == bucket_id),
+                None => Ok(false),
+            }
+        })
+    }
+
+    fn set_bucket_encrypted(&self, bucket_id: &str, encrypted: bool) -> meta_store::Result<()> {
+        self.write(|tx| {
+            tx.open_table(BUCKET_SETTINGS)?.insert(bucket_id, encrypted)?;
+            Ok(())
+        })
+    }
+
+    fn is_bucket_encrypted(&self, bucket_id: &str) -> meta_store::Result<bool> {
+        self.read(|tx| {
+            let encrypted = tx.open_table(BUCKET_SETTINGS)?.get(bucket_id)?.map(|g| g.value());
+            Ok(encrypted.unwrap_or(false))
+        })
+    }
+
+    fn set_bucket_compression(&self, bucket_id: &str, compression: Compression) -> meta_store::Result<()> {
+        self.write(|tx| {
+            tx.open_table(BUCKET_COMPRESSION)?.insert(bucket_id, compression as u8)?;
+            Ok(())
+        })
+    }
+
+    fn get_bucket_compression(&self, bucket_id: &str) -> meta_store::Result<Compression> {
+        self.read(|tx| compression(tx.open_table(BUCKET_COMPRESSION)?.get(bucket_id)?.map(|g| g.value())))
+    }
+
+    fn get_usage(&self, bucket_id: Option<&str>) -> meta_store::Result<Usage> {
+        self.read(|tx| {
+            let objects = tx.open_table(OBJECTS)?;
+            let object_blobs = tx.open_table(OBJECT_BLOBS)?;
+            let stored_sizes = tx.open_table(STORED_SIZES)?;
+            let ids: Vec<u64> = match bucket_id {
+                Some(bucket_id) => keys_with_prefix(&tx.open_table(KEY_INDEX)?, bucket_id, "")?
+                    .into_iter()
+                    .map(|(_, id)| id)
+                    .collect(),
+                None => objects.iter()?.map(|entry| Ok(entry?.0.value())).collect::<Result<_>>()?,
+            };
+
+            // shared blobs count once however many objects refer to them
+            let mut usage = Usage::default();
+            let mut shared = HashSet::new();
+            for id in ids {
+                let size = load_object(&objects, id)?.file_size as u64;
+                usage.logical += size;
+                // compressed objects are never shared
+                let stored = stored_sizes.get(id)?.map(|g| g.value() as u64);
+                if let Some(stored) = stored {
+                    usage.compressed_logical += size;
+                    usage.compressed_physical += stored;
+                }
+                match object_blobs.get(id)? {
+                    Some(hash) => {
+                        shared.insert(hash.value().to_string());
+                    }
+                    None => usage.physical += stored.unwrap_or(size),
+                }
+            }
+            let blobs = tx.open_table(BLOBS)?;
+            for hash in shared {
+                if let Some(blob) = blobs.get(hash.as_str())? {
+                    usage.physical += blob.value().1 as u64;
+                }
+            }
+            Ok(usage)
+        })
+    }
+
+    fn get_bucket_size(&self, bucket_id: &str) -> meta_store::Result<u64> {
+        self.read(|tx| Ok(tx.open_table(BUCKET_SIZES)?.get(bucket_id)?.map_or(0, |g| g.value() as u64)))
+    }
+
+    fn insert_object(&self, object: &NewObject) -> meta_store::Result<i64> {
+        self.write(|tx| {
+            let id = next_id(tx, "objects")?;
+            let mut paths = tx.open_table(OBJECT_PATHS)?;
+            if paths.get((object.bucket_id, object.path))?.is_some() {
+                Err(TcpfsError::Conflict(format!("{} is already taken in {}", object.path, object.bucket_id)))?;
+            }
+            paths.insert((object.bucket_id, object.path), id)?;
+            tx.open_table(OBJECTS)?.insert(
+                id,
+                (object.bucket_id, object.key, object.path, object.size, object.created_at),
+            )?;
+            tx.open_table(KEY_INDEX)?.insert((object.bucket_id, object.key, id), ())?;
+            add_bucket_size(tx, object.bucket_id, object.size)?;
+            if let Some(wrapped_key) = object.wrapped_key {
+                tx.open_table(OBJECT_KEYS)?.insert(id, wrapped_key)?;
+            }
+            if object.compression != Compression::None {
+                tx.open_table(OBJECT_COMPRESSION)?.insert(id, object.compression as u8)?;
+                tx.open_table(STORED_SIZES)?.insert(id, object.stored_size)?;
+            }
+            match &object.placement {
+                Placement::Own => {}
+                Placement::Shared { hash, path } => {
+                    let mut blobs = tx.open_table(BLOBS)?;
+                    let (path, size, refcount) = match blobs.get(hash.as_str())? {
+                        Some(blob) => {
+                            let (path, size, refcount) = blob.value();
+                            (path.to_string(), size, refcount)
+                        }
+                        None => (path.clone(), object.size, 0),
+                    };
+                    blobs.insert(hash.as_str(), (path.as_str(), size, refcount + 1))?;
+                    tx.open_table(OBJECT_BLOBS)?.insert(id, hash.as_str())?;
+                }
+                Placement::Packed { segment_id, offset, length } => {
+                    add_segment_entry(tx, *segment_id as u64, id, *offset, *length)?;
+                }
+            }
+            Ok(id as i64)
+        })
+    }
+
+    fn get_object(&self, bucket_id: &str, key: &str) -> meta_store::Result<Option<StoredObject>> {
+        self.read(|tx| {
+            let id = match find_object(&tx.open_table(KEY_INDEX)?, bucket_id, key)? {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            let object = load_object(&tx.open_table(OBJECTS)?, id)?;
+            let wrapped_key = tx.open_table(OBJECT_KEYS)?.get(id)?.map(|g| g.value().to_vec());
+            let compression = compression(tx.open_table(OBJECT_COMPRESSION)?.get(id)?.map(|g| g.value()))?;
+
+            // packed objects are read straight out of their segment
+            let packed = tx.open_table(OBJECT_SEGMENTS)?.get(id)?.map(|g| g.value());
+            let shared = tx.open_table(OBJECT_BLOBS)?.get(id)?.map(|g| g.value().to_string());
+            let (location, range) = match (packed, shared) {
+                (Some((segment_id, offset, length)), _) => match tx.open_table(SEGMENTS)?.get(segment_id)? {
+                    Some(segment) => (segment.value().0.to_string(), Some((offset as u64, length as u64))),
+                    None => Err(format!("segment {} is missing", segment_id))?,
+                },
+                (None, Some(hash)) => match tx.open_table(BLOBS)?.get(hash.as_str())? {
+                    Some(blob) => (blob.value().0.to_string(), None),
+                    None => Err(format!("blob {} is missing", hash))?,
+                },
+                (None, None) => (object.path.clone(), None),
+            };
+            Ok(Some(StoredObject { object, wrapped_key, compression, location, range }))
+        })
+    }
+
+    fn extend_object(&self, object_id: i64, size: i64, new_size: i64, detach: bool) -> meta_store::Result<Option<Vec<String>>> {
+        self.write(|tx| {
+            let mut objects = tx.open_table(OBJECTS)?;
+            let row = objects.get(object_id as u64)?.map(|g| {
+                let (bucket_id, key, path, size, created_at) = g.value();
+                (bucket_id.to_string(), key.to_string(), path.to_string(), size, created_at.to_string())
+            });
+            let (bucket_id, key, path, created_at) = match row {
+                Some((bucket_id, key, path, current, created_at)) if current == size => (bucket_id, key, path, created_at),
+                _ => return Ok(None),
+            };
+            objects.insert(object_id as u64, (bucket_id.as_str(), key.as_str(), path.as_str(), new_size, created_at.as_str()))?;
+            add_bucket_size(tx, &bucket_id, new_size - size)?;
+            match detach {
+                true => Ok(Some(release_placement(tx, object_id as u64)?.unwrap_or_default())),
+                false => Ok(Some(Vec::new())),
+            }
+        })
+    }
+
+    fn delete_object(&self, bucket_id: &str, key: &str) -> meta_store::Result<Option<DeletedObject>> {
+        self.write(|tx| {
+            let id = match find_object(&tx.open_table(KEY_INDEX)?, bucket_id, key)? {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            let object = load_object(&tx.open_table(OBJECTS)?, id)?;
+            tx.open_table(OBJECTS)?.remove(id)?;
+            tx.open_table(KEY_INDEX)?.remove((bucket_id, key, id))?;
+            add_bucket_size(tx, bucket_id, -object.file_size)?;
+            tx.open_table(OBJECT_PATHS)?.remove((bucket_id, object.path.as_str()))?;
+            tx.open_table(OBJECT_KEYS)?.remove(id)?;
+            tx.open_table(OBJECT_COMPRESSION)?.remove(id)?;
+            tx.open_table(STORED_SIZES)?.remove(id)?;
+
+            let garbage = release_placement(tx, id)?.unwrap_or_else(|| vec![object.path.clone()]);
+            Ok(Some(DeletedObject { object, garbage }))
+        })
+    }
+
+    fn get_objects_in_path(&self, bucket_id: &str, prefix: &str) -> meta_store::Result<Vec<Object>> {
+        self.read(|tx| {
+            let objects = tx.open_table(OBJECTS)?;
+            keys_with_prefix(&tx.open_table(KEY_INDEX)?, bucket_id, prefix)?
+                .into_iter()
+                .filter(|(key, _)| !key[prefix.len()..].contains('/'))
+                .map(|(_, id)| load_object(&objects, id))
+                .collect()
+        })
+    }
+
+    fn get_all_objects(&self) -> meta_store::Result<Vec<Object>> {
+        self.read(|tx| {
+            let objects = tx.open_table(OBJECTS)?;
+            let all = objects.iter()?.map(|entry| {
+                let (id, row) = entry?;
+                Ok(to_object(id.value(), row.value()))
+            });
+            all.collect()
+        })
+    }
+
+    fn update_object_path(&self, object_id: i64, path: &str) -> meta_store::Result<()> {
+        self.write(|tx| {
+            let mut objects = tx.open_table(OBJECTS)?;
+            let row = objects.get(object_id as u64)?.map(|g| {
+                let (bucket_id, key, path, size, created_at) = g.value();
+                (bucket_id.to_string(), key.to_string(), path.to_string(), size, created_at.to_string())
+            });
+            let (bucket_id, key, old, size, created_at) = match row {
+                Some(row) => row,
+                None => return Ok(()),
+            };
+            let mut paths = tx.open_table(OBJECT_PATHS)?;
+            paths.remove((bucket_id.as_str(), old.as_str()))?;
+            paths.insert((bucket_id.as_str(), path), object_id as u64)?;
+            objects.insert(object_id as u64, (bucket_id.as_str(), key.as_str(), path, size, created_at.as_str()))?;
+            Ok(())
+        })
+    }
+
+    fn get_active_segment(&self) -> meta_store::Result<Option<Segment>> {
+        self.read(|tx| {
+            for entry in tx.open_table(SEGMENTS)?.iter()?.rev() {
+                let (id, row) = entry?;
+                if !row.value().3 {
+                    return Ok(Some(to_segment(id.value(), row.value())));
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    fn create_segment(&self, path: &str) -> meta_store::Result<Segment> {
+        self.write(|tx| {
+            let id = next_id(tx, "segments")?;
+            tx.open_table(SEGMENTS)?.insert(id, (path, 0, 0, false))?;
+            Ok(Segment { id: id as i64, path: path.to_string(), size: 0, live_bytes: 0 })
+        })
+    }
+
+    fn seal_segment(&self, segment_id: i64) -> meta_store::Result<()> {
+        self.write(|tx| {
+            let mut segments = tx.open_table(SEGMENTS)?;
+            let row = segments.get(segment_id as u64)?.map(|g| {
+                let (path, size, live_bytes, _) = g.value();
+                (path.to_string(), size, live_bytes)
+            });
+            if let Some((path, size, live_bytes)) = row {
+                segments.insert(segment_id as u64, (path.as_str(), size, live_bytes, true))?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_compactable_segments(&self, max_live_ratio: f64) -> meta_store::Result<Vec<Segment>> {
+        self.read(|tx| {
+            let mut found = Vec::new();
+            for entry in tx.open_table(SEGMENTS)?.iter()? {
+                let (id, row) = entry?;
+                let (_, size, live_bytes, sealed) = row.value();
+                if sealed && live_bytes as f64 <= size as f64 * max_live_ratio {
+                    found.push(to_segment(id.value(), row.value()));
+                }
+            }
+            Ok(found)
+        })
+    }
+
+    fn get_segment_entries(&self, segment_id: i64) -> meta_store::Result<Vec<(i64, i64, i64)>> {
+        self.read(|tx| {
+            let segment_id = segment_id as u64;
+            let entries = tx.open_table(SEGMENT_ENTRIES)?;
+            let range = entries.range((segment_id, i64::MIN, 0)..=(segment_id, i64::MAX, u64::MAX))?;
+            range
+                .map(|entry| {
+                    let (entry, length) = entry?;
+                    let (_, offset, object_id) = entry.value();
+                    Ok((object_id as i64, offset, length.value()))
+                })
+                .collect()
+        })
+    }
+
+    fn relocate_segment(&self, segment_id: i64, moves: &[SegmentMove]) -> meta_store::Result<()> {
+        self.write(|tx| {
+            let from = segment_id as u64;
+            for m in moves {
+                let object_id = m.object_id as u64;
+                let entry = tx.open_table(OBJECT_SEGMENTS)?.get(object_id)?.map(|g| g.value());
+                // deleted since the entries were read
+                let (offset, length) = match entry {
+                    Some((segment, offset, length)) if segment == from => (offset, length),
+                    _ => continue,
+                };
+                tx.open_table(SEGMENT_ENTRIES)?.remove((from, offset, object_id))?;
+                add_segment_entry(tx, m.segment_id as u64, object_id, m.offset, length)?;
+            }
+            tx.open_table(SEGMENTS)?.remove(from)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_conformance_in_memory() {
+        meta_store::conformance::run(|| Box::new(RedbMetadataStore::in_memory().unwrap()));
+    }
+
+    #[test]
+    fn test_conformance_on_disk() {
+        let dir = TempDir::new("tcpfs-meta").unwrap();
+        let n = Cell::new(0);
+        meta_store::conformance::run(|| {
+            n.set(n.get() + 1);
+            Box::new(RedbMetadataStore::open(&dir.path().join(format!("{}.redb", n.get()))).unwrap())
+        });
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = TempDir::new("tcpfs-meta").unwrap();
+        let path = dir.path().join("metadata.redb");
+        let store = RedbMetadataStore::open(&path).unwrap();
+        store
+            .insert_object(&NewObject {
+                bucket_id: "b",
+                key: "a",
+                path: "b/a.data",
+                size: 1,
+                created_at: "2024-01-01T00:00:00+00:00",
+                wrapped_key: None,
+                compression: Compression::None,
+                stored_size: 1,
+                placement: Placement::Own,
+            })
+            .unwrap();
+        drop(store);
+
+        let store = RedbMetadataStore::open(&path).unwrap();
+        assert_eq!(store.get_object("b", "a").unwrap().unwrap().location, "b/a.data");
+        assert_eq!(store.get_all_objects().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,55 @@
+-- the schema as first released (buckets and objects only), user_version 0
+-- a .dump of a database created by that version of init_db
+PRAGMA foreign_keys=OFF;
+BEGIN TRANSACTION;
+CREATE TABLE buckets (
+    bucket_id TEXT PRIMARY KEY,   -- UUID of the top-level folder
+    total_size INTEGER DEFAULT 0
+    );
+INSERT INTO buckets VALUES('11111111-2222-3333-4444-555555555555',150);
+CREATE TABLE objects (
+      id INTEGER PRIMARY KEY AUTOINCREMENT,
+      bucket_id TEXT NOT NULL,
+      key TEXT,
+      path TEXT,
+      file_size INTEGER,
+      created_at TEXT NOT NULL,
+      deleted BOOL DEFAULT FALSE,
+    UNIQUE(bucket_id, path),
+    FOREIGN KEY (bucket_id) REFERENCES buckets(bucket_id)
+    ON DELETE CASCADE);
+INSERT INTO objects VALUES(1,'11111111-2222-3333-4444-555555555555','docs/a.txt','/srv/tcpfs/file_store/11111111-2222-3333-4444-555555555555/2024/1/2/3/4/5/678/file.data',100,'2024-01-02T03:04:05.000000678+00:00',0);
+INSERT INTO objects VALUES(2,'11111111-2222-3333-4444-555555555555','docs/b.txt','/srv/tcpfs/file_store/11111111-2222-3333-4444-555555555555/2024/1/2/3/4/6/1/file.data',50,'2024-01-02T03:04:06.000000001+00:00',0);
+PRAGMA writable_schema=ON;
+CREATE TABLE IF NOT EXISTS sqlite_sequence(name,seq);
+DELETE FROM sqlite_sequence;
+INSERT INTO sqlite_sequence VALUES('objects',2);
+CREATE TRIGGER update_total_size_after_insert
+    AFTER INSERT ON objects 
+    FOR EACH ROW
+    BEGIN
+        -- Insert bucket_id into objects if it does not exist, then update the total size
+        INSERT INTO buckets (bucket_id, total_size)
+        VALUES (NEW.bucket_id, NEW.file_size)
+        ON CONFLICT(bucket_id)
+        DO UPDATE SET total_size = total_size + NEW.file_size;
+    END
+;
+CREATE TRIGGER update_total_size_after_update
+    AFTER UPDATE OF file_size ON objects 
+    FOR EACH ROW
+    BEGIN
+        UPDATE buckets 
+        SET total_size = total_size - OLD.file_size + NEW.file_size
+        WHERE bucket_id = NEW.bucket_id;
+    END;
+CREATE TRIGGER update_total_size_after_delete
+    AFTER DELETE ON objects 
+    FOR EACH ROW
+    BEGIN
+        UPDATE buckets 
+        SET total_size = total_size - OLD.file_size
+        WHERE bucket_id = OLD.bucket_id;
+    END;
+PRAGMA writable_schema=OFF;
+COMMIT;
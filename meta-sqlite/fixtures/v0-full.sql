@@ -0,0 +1,144 @@
+-- every table as of the last release before migrations, user_version 0
+-- a .dump of a database created by that version of init_db
+PRAGMA foreign_keys=OFF;
+BEGIN TRANSACTION;
+CREATE TABLE buckets (
+    bucket_id TEXT PRIMARY KEY,   -- UUID of the top-level folder
+    total_size INTEGER DEFAULT 0
+    );
+INSERT INTO buckets VALUES('bucket',135);
+CREATE TABLE objects (
+      id INTEGER PRIMARY KEY AUTOINCREMENT,
+      bucket_id TEXT NOT NULL,
+      key TEXT,
+      path TEXT,
+      file_size INTEGER,
+      created_at TEXT NOT NULL,
+      deleted BOOL DEFAULT FALSE,
+    UNIQUE(bucket_id, path),
+    FOREIGN KEY (bucket_id) REFERENCES buckets(bucket_id)
+    ON DELETE CASCADE);
+INSERT INTO objects VALUES(1,'bucket','plain','bucket/00/11/0011.data',100,'2024-01-01T00:00:00+00:00',0);
+INSERT INTO objects VALUES(2,'bucket','secret','bucket/22/33/2233.data',10,'2024-01-01T00:00:00+00:00',0);
+INSERT INTO objects VALUES(3,'bucket','shared','bucket/44/55/4455.data',20,'2024-01-01T00:00:00+00:00',0);
+INSERT INTO objects VALUES(4,'bucket','packed','bucket/66/77/6677.data',5,'2024-01-01T00:00:00+00:00',0);
+CREATE TABLE principals (
+    principal TEXT PRIMARY KEY,
+    api_key_hash TEXT NOT NULL UNIQUE,
+    is_admin BOOL DEFAULT FALSE
+    );
+INSERT INTO principals VALUES('admin','aa',1);
+INSERT INTO principals VALUES('ci','bb',0);
+CREATE TABLE acl (
+    bucket_id TEXT NOT NULL,
+    principal TEXT NOT NULL,
+    permissions INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (bucket_id, principal),
+    FOREIGN KEY (principal) REFERENCES principals(principal)
+    ON DELETE CASCADE);
+INSERT INTO acl VALUES('bucket','ci',9);
+CREATE TABLE bucket_settings (
+    bucket_id TEXT PRIMARY KEY,
+    encrypted BOOL NOT NULL DEFAULT FALSE
+    );
+INSERT INTO bucket_settings VALUES('bucket',1);
+CREATE TABLE object_keys (
+    object_id INTEGER PRIMARY KEY,
+    wrapped_key BLOB NOT NULL,
+    FOREIGN KEY (object_id) REFERENCES objects(id)
+    ON DELETE CASCADE);
+INSERT INTO object_keys VALUES(2,X'010203');
+CREATE TABLE blobs (
+    hash TEXT PRIMARY KEY,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    refcount INTEGER NOT NULL DEFAULT 0
+    );
+INSERT INTO blobs VALUES('abcd','.blobs/ab/cd/abcd',20,1);
+CREATE TABLE object_blobs (
+    object_id INTEGER PRIMARY KEY,
+    hash TEXT NOT NULL,
+    FOREIGN KEY (object_id) REFERENCES objects(id)
+    ON DELETE CASCADE,
+    FOREIGN KEY (hash) REFERENCES blobs(hash));
+INSERT INTO object_blobs VALUES(3,'abcd');
+CREATE TABLE segments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL DEFAULT 0,
+    live_bytes INTEGER NOT NULL DEFAULT 0,
+    sealed BOOL NOT NULL DEFAULT FALSE
+    );
+INSERT INTO segments VALUES(1,'.segments/0123.seg',5,5,0);
+CREATE TABLE object_segments (
+    object_id INTEGER PRIMARY KEY,
+    segment_id INTEGER NOT NULL,
+    offset INTEGER NOT NULL,
+    length INTEGER NOT NULL,
+    FOREIGN KEY (object_id) REFERENCES objects(id)
+    ON DELETE CASCADE,
+    FOREIGN KEY (segment_id) REFERENCES segments(id));
+INSERT INTO object_segments VALUES(4,1,0,5);
+CREATE TABLE principal_certs (
+    fingerprint TEXT PRIMARY KEY,
+    principal TEXT NOT NULL,
+    FOREIGN KEY (principal) REFERENCES principals(principal)
+    ON DELETE CASCADE);
+INSERT INTO principal_certs VALUES('cc','ci');
+PRAGMA writable_schema=ON;
+CREATE TABLE IF NOT EXISTS sqlite_sequence(name,seq);
+DELETE FROM sqlite_sequence;
+INSERT INTO sqlite_sequence VALUES('objects',4);
+INSERT INTO sqlite_sequence VALUES('segments',1);
+CREATE TRIGGER update_total_size_after_insert
+    AFTER INSERT ON objects 
+    FOR EACH ROW
+    BEGIN
+        -- Insert bucket_id into objects if it does not exist, then update the total size
+        INSERT INTO buckets (bucket_id, total_size)
+        VALUES (NEW.bucket_id, NEW.file_size)
+        ON CONFLICT(bucket_id)
+        DO UPDATE SET total_size = total_size + NEW.file_size;
+    END
+;
+CREATE TRIGGER update_total_size_after_update
+    AFTER UPDATE OF file_size ON objects 
+    FOR EACH ROW
+    BEGIN
+        UPDATE buckets 
+        SET total_size = total_size - OLD.file_size + NEW.file_size
+        WHERE bucket_id = NEW.bucket_id;
+    END;
+CREATE TRIGGER update_total_size_after_delete
+    AFTER DELETE ON objects 
+    FOR EACH ROW
+    BEGIN
+        UPDATE buckets 
+        SET total_size = total_size - OLD.file_size
+        WHERE bucket_id = OLD.bucket_id;
+    END;
+CREATE TRIGGER delete_object_key_after_delete
+    AFTER DELETE ON objects
+    FOR EACH ROW
+    BEGIN
+        DELETE FROM object_keys WHERE object_id = OLD.id;
+    END;
+CREATE TRIGGER release_blob_after_delete
+    AFTER DELETE ON objects
+    FOR EACH ROW
+    BEGIN
+        UPDATE blobs SET refcount = refcount - 1
+        WHERE hash = (SELECT hash FROM object_blobs WHERE object_id = OLD.id);
+        DELETE FROM object_blobs WHERE object_id = OLD.id;
+    END;
+CREATE TRIGGER release_segment_entry_after_delete
+    AFTER DELETE ON objects
+    FOR EACH ROW
+    BEGIN
+        UPDATE segments
+        SET live_bytes = live_bytes - (SELECT length FROM object_segments WHERE object_id = OLD.id)
+        WHERE id = (SELECT segment_id FROM object_segments WHERE object_id = OLD.id);
+        DELETE FROM object_segments WHERE object_id = OLD.id;
+    END;
+PRAGMA writable_schema=OFF;
+COMMIT;
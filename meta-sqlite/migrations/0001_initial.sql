@@ -0,0 +1,153 @@
+-- everything from before schema versions. version 0 databases can have any mix of these
+-- tables depending on which release created them, hence IF NOT EXISTS throughout
+
+-- reference table for buckets (root dirs, and their metadata)
+CREATE TABLE IF NOT EXISTS buckets (
+    bucket_id TEXT PRIMARY KEY,   -- UUID of the top-level folder
+    total_size INTEGER DEFAULT 0
+);
+
+-- reference table for objects on disk
+CREATE TABLE IF NOT EXISTS objects (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    bucket_id TEXT NOT NULL,
+    key TEXT,
+    path TEXT,
+    file_size INTEGER,
+    created_at TEXT NOT NULL,
+    deleted BOOL DEFAULT FALSE,
+    UNIQUE(bucket_id, path),
+    FOREIGN KEY (bucket_id) REFERENCES buckets(bucket_id)
+    ON DELETE CASCADE);
+
+-- Trigger to update the total size when a file reference is inserted, handle case if bucket does not exists (no other files there)
+CREATE TRIGGER IF NOT EXISTS update_total_size_after_insert
+AFTER INSERT ON objects
+FOR EACH ROW
+BEGIN
+    -- Insert bucket_id into objects if it does not exist, then update the total size
+    INSERT INTO buckets (bucket_id, total_size)
+    VALUES (NEW.bucket_id, NEW.file_size)
+    ON CONFLICT(bucket_id)
+    DO UPDATE SET total_size = total_size + NEW.file_size;
+END;
+
+-- Trigger to update the total size when a file reference is updated
+CREATE TRIGGER IF NOT EXISTS update_total_size_after_update
+AFTER UPDATE OF file_size ON objects
+FOR EACH ROW
+BEGIN
+    UPDATE buckets
+    SET total_size = total_size - OLD.file_size + NEW.file_size
+    WHERE bucket_id = NEW.bucket_id;
+END;
+
+-- Trigger to update the total size when a file reference is deleted
+CREATE TRIGGER IF NOT EXISTS update_total_size_after_delete
+AFTER DELETE ON objects
+FOR EACH ROW
+BEGIN
+    UPDATE buckets
+    SET total_size = total_size - OLD.file_size
+    WHERE bucket_id = OLD.bucket_id;
+END;
+
+-- api key holders, keys are stored as a sha256 hex digest
+CREATE TABLE IF NOT EXISTS principals (
+    principal TEXT PRIMARY KEY,
+    api_key_hash TEXT NOT NULL UNIQUE,
+    is_admin BOOL DEFAULT FALSE
+);
+
+-- per bucket permissions, a bitmask of read/write/delete/list
+CREATE TABLE IF NOT EXISTS acl (
+    bucket_id TEXT NOT NULL,
+    principal TEXT NOT NULL,
+    permissions INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (bucket_id, principal),
+    FOREIGN KEY (principal) REFERENCES principals(principal)
+    ON DELETE CASCADE);
+
+-- per bucket options, buckets without a row use the defaults
+CREATE TABLE IF NOT EXISTS bucket_settings (
+    bucket_id TEXT PRIMARY KEY,
+    encrypted BOOL NOT NULL DEFAULT FALSE
+);
+
+-- wrapped data keys of objects that are encrypted at rest
+CREATE TABLE IF NOT EXISTS object_keys (
+    object_id INTEGER PRIMARY KEY,
+    wrapped_key BLOB NOT NULL,
+    FOREIGN KEY (object_id) REFERENCES objects(id)
+    ON DELETE CASCADE);
+
+-- foreign keys aren't enforced, drop the data key along with its object
+CREATE TRIGGER IF NOT EXISTS delete_object_key_after_delete
+AFTER DELETE ON objects
+FOR EACH ROW
+BEGIN
+    DELETE FROM object_keys WHERE object_id = OLD.id;
+END;
+
+-- content addressed blobs shared by identical objects, named by their sha256
+CREATE TABLE IF NOT EXISTS blobs (
+    hash TEXT PRIMARY KEY,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    refcount INTEGER NOT NULL DEFAULT 0
+);
+
+-- objects whose data lives in the blob store instead of at objects.path
+CREATE TABLE IF NOT EXISTS object_blobs (
+    object_id INTEGER PRIMARY KEY,
+    hash TEXT NOT NULL,
+    FOREIGN KEY (object_id) REFERENCES objects(id)
+    ON DELETE CASCADE,
+    FOREIGN KEY (hash) REFERENCES blobs(hash));
+
+-- drop the object's reference, blobs left at refcount 0 are collected by take_unreferenced_blobs
+CREATE TRIGGER IF NOT EXISTS release_blob_after_delete
+AFTER DELETE ON objects
+FOR EACH ROW
+BEGIN
+    UPDATE blobs SET refcount = refcount - 1
+    WHERE hash = (SELECT hash FROM object_blobs WHERE object_id = OLD.id);
+    DELETE FROM object_blobs WHERE object_id = OLD.id;
+END;
+
+-- append only files small objects are packed into, the newest unsealed one takes writes
+CREATE TABLE IF NOT EXISTS segments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL DEFAULT 0,
+    live_bytes INTEGER NOT NULL DEFAULT 0,
+    sealed BOOL NOT NULL DEFAULT FALSE
+);
+
+-- where a packed object lives inside its segment
+CREATE TABLE IF NOT EXISTS object_segments (
+    object_id INTEGER PRIMARY KEY,
+    segment_id INTEGER NOT NULL,
+    offset INTEGER NOT NULL,
+    length INTEGER NOT NULL,
+    FOREIGN KEY (object_id) REFERENCES objects(id)
+    ON DELETE CASCADE,
+    FOREIGN KEY (segment_id) REFERENCES segments(id));
+
+-- deleted packed objects become dead space in their segment until it's compacted
+CREATE TRIGGER IF NOT EXISTS release_segment_entry_after_delete
+AFTER DELETE ON objects
+FOR EACH ROW
+BEGIN
+    UPDATE segments
+    SET live_bytes = live_bytes - (SELECT length FROM object_segments WHERE object_id = OLD.id)
+    WHERE id = (SELECT segment_id FROM object_segments WHERE object_id = OLD.id);
+    DELETE FROM object_segments WHERE object_id = OLD.id;
+END;
+
+-- tls client certificates (sha256 of the DER) that authenticate as a principal
+CREATE TABLE IF NOT EXISTS principal_certs (
+    fingerprint TEXT PRIMARY KEY,
+    principal TEXT NOT NULL,
+    FOREIGN KEY (principal) REFERENCES principals(principal)
+    ON DELETE CASCADE);
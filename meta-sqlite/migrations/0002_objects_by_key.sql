@@ -0,0 +1,2 @@
+-- downloads, deletes and listings look objects up by key, not by path
+CREATE INDEX IF NOT EXISTS objects_by_key ON objects (bucket_id, key);
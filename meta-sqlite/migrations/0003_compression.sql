@@ -0,0 +1,16 @@
+-- how new objects in a bucket are stored, see meta_store::Compression
+ALTER TABLE bucket_settings ADD COLUMN compression INTEGER NOT NULL DEFAULT 0;
+
+-- objects stored compressed, uncompressed ones have no row
+CREATE TABLE IF NOT EXISTS object_compression (
+    object_id INTEGER PRIMARY KEY,
+    compression INTEGER NOT NULL,
+    FOREIGN KEY (object_id) REFERENCES objects(id)
+    ON DELETE CASCADE);
+
+CREATE TRIGGER IF NOT EXISTS delete_object_compression_after_delete
+AFTER DELETE ON objects
+FOR EACH ROW
+BEGIN
+    DELETE FROM object_compression WHERE object_id = OLD.id;
+END;
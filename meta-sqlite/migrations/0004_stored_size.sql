@@ -0,0 +1,3 @@
+-- bytes a compressed object takes on disk, file_size stays what the client uploaded.
+-- NULL for objects compressed before this was tracked, they count at file_size
+ALTER TABLE object_compression ADD COLUMN stored_size INTEGER;
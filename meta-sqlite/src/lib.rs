@@ -5,7 +5,15 @@ use std::{
 
 
 use rusqlite::{params, Connection, Error, Result, Rows, Statement, Transaction};
-// FIXME: Build a common error thing
+
+pub use meta_store::{Object, Principal, Segment};
+
+pub mod migrations;
+mod pool;
+mod store;
+pub use migrations::SchemaError;
+pub use pool::{ConnectionPool, PoolConfig, PooledConnection};
+pub use store::SqliteMetadataStore;
 
 #[cfg(test)]
 mod tests {
@@ -25,8 +33,8 @@ mod tests {
     fn test_triggers() {
         let con = init();
         con.execute(
-            "INSERT INTO objects (bucket_id, path, file_size) VALUES(?,?,?) ",
-            params!["test_id", "/some/file/path.txt", 1024],
+            "INSERT INTO objects (bucket_id, path, file_size, created_at) VALUES(?,?,?,?) ",
+            params!["test_id", "/some/file/path.txt", 1024, "2024-01-01T00:00:00+00:00"],
         )
         .unwrap();
         let mut stmt = con
@@ -45,8 +53,8 @@ mod tests {
         assert_eq!(result.1, 1024);
 
         con.execute(
-            "INSERT INTO objects (bucket_id, path, file_size) VALUES(?,?,?) ",
-            params!["test_id", "/some/file/path2.txt", 1024],
+            "INSERT INTO objects (bucket_id, path, file_size, created_at) VALUES(?,?,?,?) ",
+            params!["test_id", "/some/file/path2.txt", 1024, "2024-01-01T00:00:00+00:00"],
         )
         .unwrap();
 
@@ -69,8 +77,8 @@ mod tests {
     #[test]
     fn test_insert_and_delete() {
         let mut con = init();
-        let tx = start_transaction(&mut con);
-        insert_metadata(&tx, "testid", "/path", "1024").unwrap();
+        let tx = start_transaction(&mut con).unwrap();
+        insert_metadata(&tx, "testid", "/path", "/path", "1024", "2024-01-01T00:00:00+00:00").unwrap();
         tx.commit().unwrap();
         let result: (String, String) = con
             .prepare("SELECT bucket_id, path FROM objects WHERE bucket_id='testid'")
@@ -89,7 +97,7 @@ mod tests {
 
         assert_eq!(bucket_total_size, 1024);
 
-        let tx = start_transaction(&mut con);
+        let tx = start_transaction(&mut con).unwrap();
         delete_metadata(&tx, "testid", "/path").unwrap();
         tx.commit().unwrap();
 
@@ -105,11 +113,11 @@ mod tests {
     #[test]
     fn test_get_by_bucket() {
         let mut con = init();
-        let tx = start_transaction(&mut con);
-        insert_metadata(&tx, "testid", "/path", "1024").unwrap();
-        insert_metadata(&tx, "testid", "/path/one", "1024").unwrap();
-        insert_metadata(&tx, "testid", "/path/two", "1024").unwrap();
-        insert_metadata(&tx, "testid", "/path/one/two", "1024").unwrap();
+        let tx = start_transaction(&mut con).unwrap();
+        insert_metadata(&tx, "testid", "/path", "/path", "1024", "2024-01-01T00:00:00+00:00").unwrap();
+        insert_metadata(&tx, "testid", "/path/one", "/path/one", "1024", "2024-01-01T00:00:00+00:00").unwrap();
+        insert_metadata(&tx, "testid", "/path/two", "/path/two", "1024", "2024-01-01T00:00:00+00:00").unwrap();
+        insert_metadata(&tx, "testid", "/path/one/two", "/path/one/two", "1024", "2024-01-01T00:00:00+00:00").unwrap();
         tx.commit().unwrap();
 
         // test root dir
@@ -125,12 +133,12 @@ mod tests {
         let objects = get_objects_in_path(&con, "testid", "/path/two/").unwrap();
         assert_eq!(objects.len(), 0);
 
-        let tx = start_transaction(&mut con);
-        insert_metadata(&tx, "testid", "/path/two/0", "1024").unwrap();
-        insert_metadata(&tx, "testid", "/path/two/1", "1024").unwrap();
-        insert_metadata(&tx, "testid", "/path/two/2", "1024").unwrap();
-        insert_metadata(&tx, "testid", "/path/two/3", "1024").unwrap();
-        insert_metadata(&tx, "testid", "/path/two/4", "1024").unwrap();
+        let tx = start_transaction(&mut con).unwrap();
+        insert_metadata(&tx, "testid", "/path/two/0", "/path/two/0", "1024", "2024-01-01T00:00:00+00:00").unwrap();
+        insert_metadata(&tx, "testid", "/path/two/1", "/path/two/1", "1024", "2024-01-01T00:00:00+00:00").unwrap();
+        insert_metadata(&tx, "testid", "/path/two/2", "/path/two/2", "1024", "2024-01-01T00:00:00+00:00").unwrap();
+        insert_metadata(&tx, "testid", "/path/two/3", "/path/two/3", "1024", "2024-01-01T00:00:00+00:00").unwrap();
+        insert_metadata(&tx, "testid", "/path/two/4", "/path/two/4", "1024", "2024-01-01T00:00:00+00:00").unwrap();
         tx.commit().unwrap();
 
 
@@ -138,8 +146,8 @@ mod tests {
         assert_eq!(objects.len(), 5);
 
 
-        let tx = start_transaction(&mut con);
-        insert_metadata(&tx, "testid", "/path/two/0/0", "1024").unwrap();
+        let tx = start_transaction(&mut con).unwrap();
+        insert_metadata(&tx, "testid", "/path/two/0/0", "/path/two/0/0", "1024", "2024-01-01T00:00:00+00:00").unwrap();
         tx.commit().unwrap();
 
         let objects = get_objects_in_path(&con, "testid", "/path/two/").unwrap();
@@ -147,9 +155,138 @@ mod tests {
         
 
   }
+
+    #[test]
+    fn test_list_matches_wildcards_literally() {
+        let mut con = init();
+        let tx = start_transaction(&mut con).unwrap();
+        insert_metadata(&tx, "testid", "a_b/1", "/store/1", "1024", "2024-01-01T00:00:00+00:00").unwrap();
+        insert_metadata(&tx, "testid", "axb/2", "/store/2", "1024", "2024-01-01T00:00:00+00:00").unwrap();
+        tx.commit().unwrap();
+
+        let objects = get_objects_in_path(&con, "testid", "a_b/").unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key, "a_b/1");
+        assert_eq!(get_objects_in_path(&con, "testid", "%/").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_object_keys_follow_objects() {
+        let mut con = init();
+        assert!(!is_bucket_encrypted(&con, "testid").unwrap());
+        set_bucket_encrypted(&con, "testid", true).unwrap();
+        assert!(is_bucket_encrypted(&con, "testid").unwrap());
+
+        let tx = start_transaction(&mut con).unwrap();
+        insert_metadata(&tx, "testid", "a", "/store/a", "1024", "2024-01-01T00:00:00+00:00").unwrap();
+        let id = tx.last_insert_rowid();
+        insert_object_key(&tx, id, &[1, 2, 3]).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(get_object_key(&con, id).unwrap(), Some(vec![1, 2, 3]));
+
+        let tx = start_transaction(&mut con).unwrap();
+        delete_metadata(&tx, "testid", "/store/a").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(get_object_key(&con, id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_blob_refcounts() {
+        let mut con = init();
+        let tx = start_transaction(&mut con).unwrap();
+        for (bucket, key) in [("one", "a"), ("one", "b"), ("two", "a")] {
+            let placeholder = format!("/store/{}/{}", bucket, key);
+            insert_metadata(&tx, bucket, key, &placeholder, "100", "2024-01-01T00:00:00+00:00").unwrap();
+            let (path, is_new) = acquire_blob(&tx, "abc", "/blobs/abc", 100).unwrap();
+            assert_eq!(path, "/blobs/abc");
+            assert_eq!(is_new, bucket == "one" && key == "a");
+            link_object_blob(&tx, tx.last_insert_rowid(), "abc").unwrap();
+        }
+        insert_metadata(&tx, "one", "plain", "/store/plain", "10", "2024-01-01T00:00:00+00:00").unwrap();
+        tx.commit().unwrap();
+
+        assert_eq!(get_usage(&con, Some("one")).unwrap(), (210, 110, 0, 0));
+        assert_eq!(get_usage(&con, Some("two")).unwrap(), (100, 100, 0, 0));
+        assert_eq!(get_usage(&con, None).unwrap(), (310, 110, 0, 0));
+
+        let tx = start_transaction(&mut con).unwrap();
+        let id = get_object_by_key(&tx, "two", "a").unwrap().id;
+        assert_eq!(get_object_blob_path(&tx, id.into()).unwrap().unwrap(), "/blobs/abc");
+        delete_metadata(&tx, "one", "/store/one/a").unwrap();
+        delete_metadata(&tx, "one", "/store/one/b").unwrap();
+        assert!(take_unreferenced_blobs(&tx).unwrap().is_empty());
+        delete_metadata(&tx, "two", "/store/two/a").unwrap();
+        assert_eq!(take_unreferenced_blobs(&tx).unwrap(), vec!["/blobs/abc".to_string()]);
+        tx.commit().unwrap();
+        assert_eq!(get_usage(&con, None).unwrap(), (10, 10, 0, 0));
+    }
+
+    #[test]
+    fn test_segment_accounting() {
+        let mut con = init();
+        assert!(get_active_segment(&con).unwrap().is_none());
+        let old = create_segment(&con, "/segments/1.seg").unwrap();
+
+        let tx = start_transaction(&mut con).unwrap();
+        for (i, key) in ["a", "b"].iter().enumerate() {
+            let placeholder = format!("/store/{}", key);
+            insert_metadata(&tx, "testid", key, &placeholder, "10", "2024-01-01T00:00:00+00:00").unwrap();
+            add_segment_entry(&tx, old.id, tx.last_insert_rowid(), i as i64 * 10, 10).unwrap();
+        }
+        tx.commit().unwrap();
+        let active = get_active_segment(&con).unwrap().unwrap();
+        assert_eq!((active.size, active.live_bytes), (20, 20));
+
+        let tx = start_transaction(&mut con).unwrap();
+        delete_metadata(&tx, "testid", "/store/a").unwrap();
+        tx.commit().unwrap();
+        assert!(get_compactable_segments(&con, 0.5).unwrap().is_empty());
+        seal_segment(&con, old.id).unwrap();
+        assert_eq!(get_compactable_segments(&con, 0.5).unwrap().len(), 1);
+
+        let new = create_segment(&con, "/segments/2.seg").unwrap();
+        let entries = get_segment_entries(&con, old.id).unwrap();
+        assert_eq!(entries.len(), 1);
+        let (object_id, _, _) = entries[0];
+        let tx = start_transaction(&mut con).unwrap();
+        assert!(move_segment_entry(&tx, object_id, old.id, new.id, 0).unwrap());
+        delete_segment(&tx, old.id).unwrap();
+        tx.commit().unwrap();
+
+        assert_eq!(get_object_segment(&con, object_id).unwrap(), Some(("/segments/2.seg".to_string(), 0, 10)));
+        let active = get_active_segment(&con).unwrap().unwrap();
+        assert_eq!((active.id, active.size, active.live_bytes), (new.id, 10, 10));
+    }
+
+    #[test]
+    fn test_acl_grant_and_revoke() {
+        let con = init();
+        upsert_principal(&con, "ci", "deadbeef", false).unwrap();
+
+        assert!(!bucket_has_acl(&con, "testid").unwrap());
+        assert_eq!(get_permissions(&con, "testid", "ci").unwrap(), 0);
+
+        grant_permissions(&con, "testid", "ci", 0b0001).unwrap();
+        grant_permissions(&con, "testid", "ci", 0b0010).unwrap();
+        assert!(bucket_has_acl(&con, "testid").unwrap());
+        assert_eq!(get_permissions(&con, "testid", "ci").unwrap(), 0b0011);
+
+        revoke_permissions(&con, "testid", "ci", 0b0001).unwrap();
+        assert_eq!(get_permissions(&con, "testid", "ci").unwrap(), 0b0010);
+
+        revoke_permissions(&con, "testid", "ci", 0b0010).unwrap();
+        assert!(!bucket_has_acl(&con, "testid").unwrap());
+
+        assert_eq!(grant_permissions(&con, "testid", "nobody", 0b0001).unwrap(), 0);
+
+        let p = get_principal_by_key_hash(&con, "deadbeef").unwrap().unwrap();
+        assert_eq!(p.name, "ci");
+        assert!(!p.is_admin);
+        assert!(get_principal_by_key_hash(&con, "nope").unwrap().is_none());
+    }
 }
 
-pub fn get_connection(db_path: Option<String>) -> Result<Connection> {
+pub fn get_connection(db_path: Option<String>) -> std::result::Result<Connection, SchemaError> {
     let con = match db_path {
         Some(x) => Connection::open(x)?,
         None => Connection::open_in_memory()?,
@@ -160,95 +297,21 @@ pub fn get_connection(db_path: Option<String>) -> Result<Connection> {
 }
 
 
-pub fn init_db(conn: &Connection) -> Result<()> {
+/// Bring the schema up to date, see `migrations`
+pub fn init_db(conn: &Connection) -> std::result::Result<(), SchemaError> {
     // Enable WAL mode
     conn.pragma_update(None, "journal_mode", &"WAL")?;
-    // create object table
-    conn.execute(
-        " -- reference table for buckets (root dirs, and their metadata)
-    CREATE TABLE IF NOT EXISTS buckets (
-    bucket_id TEXT PRIMARY KEY,   -- UUID of the top-level folder
-    total_size INTEGER DEFAULT 0
-    );
-    ",
-        params![],
-    )
-    .unwrap();
-
-    conn.execute(
-        " -- reference table for objects on disk
-    CREATE TABLE IF NOT EXISTS objects (
-      id INTEGER PRIMARY KEY AUTOINCREMENT,
-      bucket_id TEXT NOT NULL,
-      key TEXT,
-      path TEXT,
-      file_size INTEGER,
-      created_at TEXT NOT NULL,
-      deleted BOOL DEFAULT FALSE,
-    UNIQUE(bucket_id, path),
-    FOREIGN KEY (bucket_id) REFERENCES buckets(bucket_id)
-    ON DELETE CASCADE);
-    ",
-        params![],
-    )
-    .unwrap();
-
-    conn.execute("-- Trigger to update the total size when a file reference is inserted, handle case if bucket does not exists (no other files there)
-    CREATE TRIGGER IF NOT EXISTS update_total_size_after_insert
-    AFTER INSERT ON objects 
-    FOR EACH ROW
-    BEGIN
-        -- Insert bucket_id into objects if it does not exist, then update the total size
-        INSERT INTO buckets (bucket_id, total_size)
-        VALUES (NEW.bucket_id, NEW.file_size)
-        ON CONFLICT(bucket_id)
-        DO UPDATE SET total_size = total_size + NEW.file_size;
-    END; 
-    ", params![]).unwrap();
-
-    conn.execute(
-        "-- Trigger to update the total size when a file reference is updated
-    CREATE TRIGGER IF NOT EXISTS update_total_size_after_update
-    AFTER UPDATE OF file_size ON objects 
-    FOR EACH ROW
-    BEGIN
-        UPDATE buckets 
-        SET total_size = total_size - OLD.file_size + NEW.file_size
-        WHERE bucket_id = NEW.bucket_id;
-    END;
-    ",
-        params![],
-    )
-    .unwrap();
-
-    conn.execute(
-        "-- Trigger to update the total size when a file reference is deleted
-    CREATE TRIGGER IF NOT EXISTS update_total_size_after_delete
-    AFTER DELETE ON objects 
-    FOR EACH ROW
-    BEGIN
-        UPDATE buckets 
-        SET total_size = total_size - OLD.file_size
-        WHERE bucket_id = OLD.bucket_id;
-    END;
-    ",
-        params![],
-    )
-    .unwrap();
+    migrations::migrate(conn)?;
     Ok(())
 }
 
-pub fn start_transaction(conn: &mut Connection) -> Transaction {
-    return conn.transaction().unwrap();
+pub fn start_transaction(conn: &mut Connection) -> Result<Transaction<'_>> {
+    conn.transaction()
 }
 
 pub fn delete_metadata(tx: &Transaction, bucket_id: &str, path: &str) -> Result<usize, Error> {
-    Ok(tx
-        .execute(
-            "DELETE FROM objects WHERE bucket_id = ? AND path = ?",
-            &[bucket_id, path],
-        )
-        .unwrap())
+    tx.prepare_cached("DELETE FROM objects WHERE bucket_id = ? AND path = ?")?
+        .execute([bucket_id, path])
 }
 
 pub fn insert_metadata(
@@ -259,12 +322,8 @@ pub fn insert_metadata(
     size: &str,
     created_at: &str
 ) -> Result<usize, Error> {
-    Ok(tx
-        .execute(
-            "INSERT INTO objects (bucket_id, key, path, file_size, created_at) VALUES(?,?,?,?,?)",
-            &[bucket_id, key, path, size, created_at]
-        )
-        .unwrap())
+    tx.prepare_cached("INSERT INTO objects (bucket_id, key, path, file_size, created_at) VALUES(?,?,?,?,?)")?
+        .execute([bucket_id, key, path, size, created_at])
 }
 
 
@@ -282,54 +341,43 @@ pub fn get_metadata_by_key(
 
 }
 
-
-#[derive(Debug)]
-pub struct Object {
-    pub id: i32,
-    pub bucket_id: String,
-    pub path: String,
-    pub file_size: i64,
-    //pub is_dir: bool
-}
-
-
-impl Object
-{
-    pub fn serialize(&self) -> Vec<u8> 
-    {
-        let mut result = Vec::new();
-
-        // Path length (32-bit, big-endian)
-        let path_len = self.path.len() as u32;
-        result.extend_from_slice(&path_len.to_be_bytes());
-
-        // 128-bit bucket ID
-        result.extend_from_slice(&self.bucket_id.as_bytes());
-
-        // Path bytes
-        result.extend_from_slice(self.path.as_bytes());
-
-        // File/dir size (32-bit, big-endian)
-        result.extend_from_slice(&self.file_size.to_be_bytes());
-
-        // Separator "\r\n"
-        result.extend_from_slice(b"\r\n");
-
-        result
-    }
+/// the oldest object with the key
+pub fn get_object_by_key(
+    con: &Connection,
+    bucket_id: &str,
+    key: &str,
+) -> Result<Object, Error> {
+    con.prepare_cached(
+        "SELECT id, bucket_id, key, path, file_size FROM objects WHERE bucket_id=? AND key=? AND deleted=false ORDER BY id LIMIT 1",
+    )?
+    .query_row(
+        [bucket_id, key],
+        |row| {
+            Ok(Object {
+                id: row.get(0)?,
+                bucket_id: row.get(1)?,
+                key: row.get(2)?,
+                path: row.get(3)?,
+                file_size: row.get(4)?,
+            })
+        },
+    )
 }
 
-
-
 pub fn get_objects_in_path(con: &Connection, bucket_id: &str ,root_path: &str) -> Result<Vec<Object>>
 {
-  let mut stmt = con.prepare(r#"
-  SELECT id, bucket_id, path, file_size
+  let mut stmt = con.prepare_cached(r#"
+  SELECT id, bucket_id, key, path, file_size
   FROM objects 
     WHERE bucket_id = ?
-    AND path LIKE ?
-    AND path NOT LIKE ?;
+    AND key LIKE ? ESCAPE '\'
+    AND key NOT LIKE ? ESCAPE '\';
   "#)?;
+  // keys may contain LIKE wildcards, match those literally
+  let root_path = root_path
+      .replace('\\', "\\\\")
+      .replace('%', "\\%")
+      .replace('_', "\\_");
   let root_like = format!("{}%", root_path);        // To match the root path
   let exclude_subdirs = format!("{}%/%", root_path); // To exclude subdirectories
   let object_iter = stmt.query_map(&[bucket_id, root_like.as_str(), exclude_subdirs.as_str()], |row|
@@ -340,8 +388,9 @@ pub fn get_objects_in_path(con: &Connection, bucket_id: &str ,root_path: &str) -
     {
          id: row.get(0)?,
          bucket_id: row.get(1)?,
-         path: row.get(2)?,
-         file_size: row.get(3)?,
+         key: row.get(2)?,
+         path: row.get(3)?,
+         file_size: row.get(4)?,
 
     })
   })?;
@@ -349,5 +398,387 @@ pub fn get_objects_in_path(con: &Connection, bucket_id: &str ,root_path: &str) -
   objects
 }
 
+/// every object in every bucket, used by maintenance tools
+pub fn get_all_objects(con: &Connection) -> Result<Vec<Object>> {
+    let mut stmt = con.prepare("SELECT id, bucket_id, key, path, file_size FROM objects")?;
+    let objects = stmt.query_map([], |row| {
+        Ok(Object {
+            id: row.get(0)?,
+            bucket_id: row.get(1)?,
+            key: row.get(2)?,
+            path: row.get(3)?,
+            file_size: row.get(4)?,
+        })
+    })?;
+    objects.collect()
+}
+
+pub fn update_object_path(tx: &Transaction, object_id: i64, path: &str) -> Result<usize> {
+    tx.execute("UPDATE objects SET path = ? WHERE id = ?", params![path, object_id])
+}
+
+/// false if the object isn't `size` bytes, update_total_size_after_update keeps the bucket total
+pub fn resize_object(tx: &Transaction, object_id: i64, size: i64, new_size: i64) -> Result<bool> {
+    let updated = tx.execute(
+        "UPDATE objects SET file_size = ?3 WHERE id = ?1 AND file_size = ?2",
+        params![object_id, size, new_size],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Let go of the object's shared blob or segment entry like deleting it would, it reads from objects.path after
+pub fn detach_object(tx: &Transaction, object_id: i64) -> Result<()> {
+    tx.execute(
+        "UPDATE blobs SET refcount = refcount - 1 WHERE hash = (SELECT hash FROM object_blobs WHERE object_id = ?1)",
+        [object_id],
+    )?;
+    tx.execute("DELETE FROM object_blobs WHERE object_id = ?1", [object_id])?;
+    tx.execute(
+        "UPDATE segments
+        SET live_bytes = live_bytes - (SELECT length FROM object_segments WHERE object_id = ?1)
+        WHERE id = (SELECT segment_id FROM object_segments WHERE object_id = ?1)",
+        [object_id],
+    )?;
+    tx.execute("DELETE FROM object_segments WHERE object_id = ?1", [object_id])?;
+    Ok(())
+}
+
+
+
+
+pub fn set_bucket_encrypted(con: &Connection, bucket_id: &str, encrypted: bool) -> Result<usize> {
+    con.execute(
+        "INSERT INTO bucket_settings (bucket_id, encrypted) VALUES(?,?)
+        ON CONFLICT(bucket_id) DO UPDATE SET encrypted = excluded.encrypted",
+        params![bucket_id, encrypted],
+    )
+}
+
+pub fn is_bucket_encrypted(con: &Connection, bucket_id: &str) -> Result<bool> {
+    con.prepare_cached("SELECT EXISTS(SELECT 1 FROM bucket_settings WHERE bucket_id = ? AND encrypted)")?
+        .query_row([bucket_id], |row| row.get(0))
+}
+
+pub fn set_bucket_compression(con: &Connection, bucket_id: &str, compression: u8) -> Result<usize> {
+    con.execute(
+        "INSERT INTO bucket_settings (bucket_id, compression) VALUES(?,?)
+        ON CONFLICT(bucket_id) DO UPDATE SET compression = excluded.compression",
+        params![bucket_id, compression],
+    )
+}
+
+/// 0 (uncompressed) for buckets without settings
+pub fn get_bucket_compression(con: &Connection, bucket_id: &str) -> Result<u8> {
+    con.prepare_cached("SELECT COALESCE((SELECT compression FROM bucket_settings WHERE bucket_id = ?), 0)")?
+        .query_row([bucket_id], |row| row.get(0))
+}
+
+pub fn insert_object_compression(tx: &Transaction, object_id: i64, compression: u8, stored_size: i64) -> Result<usize> {
+    tx.prepare_cached("INSERT INTO object_compression (object_id, compression, stored_size) VALUES(?,?,?)")?
+        .execute(params![object_id, compression, stored_size])
+}
+
+/// 0 for objects stored as they were uploaded
+pub fn get_object_compression(con: &Connection, object_id: i64) -> Result<u8> {
+    con.prepare_cached("SELECT COALESCE((SELECT compression FROM object_compression WHERE object_id = ?), 0)")?
+        .query_row([object_id], |row| row.get(0))
+}
+
+pub fn insert_object_key(tx: &Transaction, object_id: i64, wrapped_key: &[u8]) -> Result<usize> {
+    tx.prepare_cached("INSERT INTO object_keys (object_id, wrapped_key) VALUES(?,?)")?
+        .execute(params![object_id, wrapped_key])
+}
+
+/// None for objects stored in plaintext
+pub fn get_object_key(con: &Connection, object_id: i64) -> Result<Option<Vec<u8>>> {
+    let result = con
+        .prepare_cached("SELECT wrapped_key FROM object_keys WHERE object_id = ?")?
+        .query_row([object_id], |row| row.get(0));
+    match result {
+        Ok(key) => Ok(Some(key)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
 
+/// Take a reference on the blob with `hash`, registering it at `path` if it's new.
+/// Returns the blob's path and whether the caller has to write it there.
+pub fn acquire_blob(tx: &Transaction, hash: &str, path: &str, size: i64) -> Result<(String, bool)> {
+    tx.execute(
+        "INSERT INTO blobs (hash, path, size, refcount) VALUES(?,?,?,1)
+        ON CONFLICT(hash) DO UPDATE SET refcount = refcount + 1",
+        params![hash, path, size],
+    )?;
+    tx.query_row(
+        "SELECT path, refcount FROM blobs WHERE hash = ?",
+        [hash],
+        |row| Ok((row.get(0)?, row.get::<_, i64>(1)? == 1)),
+    )
+}
 
+pub fn link_object_blob(tx: &Transaction, object_id: i64, hash: &str) -> Result<usize> {
+    tx.execute(
+        "INSERT INTO object_blobs (object_id, hash) VALUES(?,?)",
+        params![object_id, hash],
+    )
+}
+
+/// None unless the object is deduplicated
+pub fn get_object_blob_path(con: &Connection, object_id: i64) -> Result<Option<String>> {
+    let result = con
+        .prepare_cached("SELECT b.path FROM object_blobs ob JOIN blobs b ON b.hash = ob.hash WHERE ob.object_id = ?")?
+        .query_row([object_id], |row| row.get(0));
+    match result {
+        Ok(path) => Ok(Some(path)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Forget blobs nothing refers to anymore, returns their paths so the files can be removed after commit
+pub fn take_unreferenced_blobs(tx: &Transaction) -> Result<Vec<String>> {
+    let paths = tx
+        .prepare("SELECT path FROM blobs WHERE refcount <= 0")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    tx.execute("DELETE FROM blobs WHERE refcount <= 0", [])?;
+    Ok(paths)
+}
+
+/// (logical, physical, compressed logical, compressed physical) bytes. logical is what clients uploaded,
+/// physical what is on disk, a blob shared between buckets counts towards each of them.
+/// `None` sums up the whole store, where shared blobs count once.
+pub fn get_usage(con: &Connection, bucket_id: Option<&str>) -> Result<(i64, i64, i64, i64)> {
+    con.query_row(
+        "SELECT
+            COALESCE((SELECT SUM(file_size) FROM objects WHERE ?1 IS NULL OR bucket_id = ?1), 0),
+            COALESCE((SELECT SUM(COALESCE(oc.stored_size, o.file_size)) FROM objects o
+                LEFT JOIN object_compression oc ON oc.object_id = o.id
+                WHERE (?1 IS NULL OR o.bucket_id = ?1)
+                AND NOT EXISTS (SELECT 1 FROM object_blobs WHERE object_id = o.id)), 0)
+            + COALESCE((SELECT SUM(size) FROM blobs WHERE hash IN (
+                SELECT ob.hash FROM object_blobs ob JOIN objects o ON o.id = ob.object_id
+                WHERE ?1 IS NULL OR o.bucket_id = ?1)), 0),
+            COALESCE((SELECT SUM(o.file_size) FROM objects o JOIN object_compression oc ON oc.object_id = o.id
+                WHERE ?1 IS NULL OR o.bucket_id = ?1), 0),
+            COALESCE((SELECT SUM(COALESCE(oc.stored_size, o.file_size)) FROM objects o
+                JOIN object_compression oc ON oc.object_id = o.id
+                WHERE ?1 IS NULL OR o.bucket_id = ?1), 0)",
+        [bucket_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )
+}
+
+/// the total update_total_size_after_* keep in buckets, 0 for a bucket that never had objects
+pub fn get_bucket_size(con: &Connection, bucket_id: &str) -> Result<i64> {
+    let result = con
+        .prepare_cached("SELECT total_size FROM buckets WHERE bucket_id = ?")?
+        .query_row([bucket_id], |row| row.get(0));
+    match result {
+        Ok(size) => Ok(size),
+        Err(Error::QueryReturnedNoRows) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+fn segment_from_row(row: &rusqlite::Row) -> Result<Segment> {
+    Ok(Segment {
+        id: row.get(0)?,
+        path: row.get(1)?,
+        size: row.get(2)?,
+        live_bytes: row.get(3)?,
+    })
+}
+
+pub fn get_active_segment(con: &Connection) -> Result<Option<Segment>> {
+    let result = con.query_row(
+        "SELECT id, path, size, live_bytes FROM segments WHERE NOT sealed ORDER BY id DESC LIMIT 1",
+        [],
+        segment_from_row,
+    );
+    match result {
+        Ok(segment) => Ok(Some(segment)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn create_segment(con: &Connection, path: &str) -> Result<Segment> {
+    con.execute("INSERT INTO segments (path) VALUES(?)", [path])?;
+    Ok(Segment { id: con.last_insert_rowid(), path: path.to_string(), size: 0, live_bytes: 0 })
+}
+
+pub fn seal_segment(con: &Connection, segment_id: i64) -> Result<usize> {
+    con.execute("UPDATE segments SET sealed = TRUE WHERE id = ?", [segment_id])
+}
+
+pub fn add_segment_entry(tx: &Transaction, segment_id: i64, object_id: i64, offset: i64, length: i64) -> Result<usize> {
+    tx.execute(
+        "INSERT INTO object_segments (object_id, segment_id, offset, length) VALUES(?,?,?,?)",
+        params![object_id, segment_id, offset, length],
+    )?;
+    tx.execute(
+        "UPDATE segments SET size = MAX(size, ?2), live_bytes = live_bytes + ?3 WHERE id = ?1",
+        params![segment_id, offset + length, length],
+    )
+}
+
+/// (segment path, offset, length), None unless the object is packed
+pub fn get_object_segment(con: &Connection, object_id: i64) -> Result<Option<(String, i64, i64)>> {
+    let result = con
+        .prepare_cached(
+            "SELECT s.path, os.offset, os.length FROM object_segments os
+            JOIN segments s ON s.id = os.segment_id WHERE os.object_id = ?",
+        )?
+        .query_row([object_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)));
+    match result {
+        Ok(location) => Ok(Some(location)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// sealed segments where at most `max_live_ratio` of the bytes are still referenced
+pub fn get_compactable_segments(con: &Connection, max_live_ratio: f64) -> Result<Vec<Segment>> {
+    con.prepare("SELECT id, path, size, live_bytes FROM segments WHERE sealed AND live_bytes <= size * ?")?
+        .query_map([max_live_ratio], segment_from_row)?
+        .collect()
+}
+
+/// (object id, offset, length) of everything still in the segment
+pub fn get_segment_entries(con: &Connection, segment_id: i64) -> Result<Vec<(i64, i64, i64)>> {
+    con.prepare("SELECT object_id, offset, length FROM object_segments WHERE segment_id = ? ORDER BY offset")?
+        .query_map([segment_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect()
+}
+
+/// Point an entry at its copy in another segment, false if the object was deleted meanwhile
+pub fn move_segment_entry(tx: &Transaction, object_id: i64, from: i64, to: i64, offset: i64) -> Result<bool> {
+    let moved = tx.execute(
+        "UPDATE object_segments SET segment_id = ?3, offset = ?4 WHERE object_id = ?1 AND segment_id = ?2",
+        params![object_id, from, to, offset],
+    )?;
+    if moved == 1 {
+        tx.execute(
+            "UPDATE segments
+            SET size = MAX(size, ?2 + (SELECT length FROM object_segments WHERE object_id = ?3)),
+            live_bytes = live_bytes + (SELECT length FROM object_segments WHERE object_id = ?3)
+            WHERE id = ?1",
+            params![to, offset, object_id],
+        )?;
+    }
+    Ok(moved == 1)
+}
+
+pub fn delete_segment(tx: &Transaction, segment_id: i64) -> Result<usize> {
+    tx.execute("DELETE FROM segments WHERE id = ?", [segment_id])
+}
+
+pub fn upsert_principal(
+    con: &Connection,
+    principal: &str,
+    api_key_hash: &str,
+    is_admin: bool,
+) -> Result<usize> {
+    con.execute(
+        "INSERT INTO principals (principal, api_key_hash, is_admin) VALUES(?,?,?)
+        ON CONFLICT(principal) DO UPDATE SET api_key_hash = excluded.api_key_hash, is_admin = excluded.is_admin",
+        params![principal, api_key_hash, is_admin],
+    )
+}
+
+pub fn get_principal_by_key_hash(con: &Connection, api_key_hash: &str) -> Result<Option<Principal>> {
+    let result = con.prepare_cached("SELECT principal, is_admin FROM principals WHERE api_key_hash = ?")?.query_row(
+        [api_key_hash],
+        |row| {
+            Ok(Principal {
+                name: row.get(0)?,
+                is_admin: row.get(1)?,
+            })
+        },
+    );
+    match result {
+        Ok(p) => Ok(Some(p)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// returns 0 when the principal does not exist
+pub fn add_principal_cert(con: &Connection, fingerprint: &str, principal: &str) -> Result<usize> {
+    con.execute(
+        "INSERT OR REPLACE INTO principal_certs (fingerprint, principal)
+        SELECT ?, principal FROM principals WHERE principal = ?",
+        params![fingerprint, principal],
+    )
+}
+
+pub fn get_principal_by_cert(con: &Connection, fingerprint: &str) -> Result<Option<Principal>> {
+    let result = con
+        .prepare_cached(
+            "SELECT p.principal, p.is_admin FROM principal_certs c
+            JOIN principals p ON p.principal = c.principal
+            WHERE c.fingerprint = ?",
+        )?
+        .query_row([fingerprint], |row| {
+            Ok(Principal {
+                name: row.get(0)?,
+                is_admin: row.get(1)?,
+            })
+        });
+    match result {
+        Ok(p) => Ok(Some(p)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// OR `permissions` into whatever the principal already holds on the bucket,
+/// returns 0 when the principal does not exist
+pub fn grant_permissions(
+    con: &Connection,
+    bucket_id: &str,
+    principal: &str,
+    permissions: u8,
+) -> Result<usize> {
+    con.execute(
+        "INSERT INTO acl (bucket_id, principal, permissions)
+        SELECT ?, principal, ? FROM principals WHERE principal = ?
+        ON CONFLICT(bucket_id, principal) DO UPDATE SET permissions = permissions | excluded.permissions",
+        params![bucket_id, permissions, principal],
+    )
+}
+
+/// clear `permissions` from the principal, the row is dropped once nothing is left
+pub fn revoke_permissions(
+    con: &Connection,
+    bucket_id: &str,
+    principal: &str,
+    permissions: u8,
+) -> Result<usize> {
+    let changed = con.execute(
+        "UPDATE acl SET permissions = permissions & ~? WHERE bucket_id = ? AND principal = ?",
+        params![permissions, bucket_id, principal],
+    )?;
+    con.execute(
+        "DELETE FROM acl WHERE bucket_id = ? AND principal = ? AND permissions = 0",
+        params![bucket_id, principal],
+    )?;
+    Ok(changed)
+}
+
+pub fn get_permissions(con: &Connection, bucket_id: &str, principal: &str) -> Result<u8> {
+    let result = con
+        .prepare_cached("SELECT permissions FROM acl WHERE bucket_id = ? AND principal = ?")?
+        .query_row([bucket_id, principal], |row| row.get(0));
+    match result {
+        Ok(p) => Ok(p),
+        Err(Error::QueryReturnedNoRows) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn bucket_has_acl(con: &Connection, bucket_id: &str) -> Result<bool> {
+    con.prepare_cached("SELECT EXISTS(SELECT 1 FROM acl WHERE bucket_id = ?)")?
+        .query_row([bucket_id], |row| row.get(0))
+}
@@ -0,0 +1,198 @@
+use std::{error::Error, fmt};
+
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+
+/*
+SCHEMA VERSIONS:
+the version lives in sqlite's user_version, 0 is a database from before versions existed.
+MIGRATIONS[n] takes a database from version n to n + 1, whatever is pending runs on startup in a
+single transaction so a failed migration leaves the database as it was.
+never edit a migration that was released, add a new one (ALTER TABLE, backfill, ...) instead
+*/
+
+pub const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_initial.sql"),
+    include_str!("../migrations/0002_objects_by_key.sql"),
+    include_str!("../migrations/0003_compression.sql"),
+    include_str!("../migrations/0004_stored_size.sql"),
+];
+
+/// the version this build migrates to
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+#[derive(Debug)]
+pub enum SchemaError {
+    Sqlite(rusqlite::Error),
+    /// written by a newer tcpfs, running against it could break what that version relies on
+    TooNew { found: i64, supported: i64 },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Sqlite(e) => write!(f, "{}", e),
+            SchemaError::TooNew { found, supported } => write!(
+                f,
+                "metadata schema version {} is newer than this build supports ({}), upgrade tcpfs",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl Error for SchemaError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SchemaError::Sqlite(e) => Some(e),
+            SchemaError::TooNew { .. } => None,
+        }
+    }
+}
+
+impl From<SchemaError> for meta_store::TcpfsError {
+    fn from(e: SchemaError) -> Self {
+        meta_store::TcpfsError::Db(Box::new(e))
+    }
+}
+
+impl From<rusqlite::Error> for SchemaError {
+    fn from(e: rusqlite::Error) -> Self {
+        SchemaError::Sqlite(e)
+    }
+}
+
+pub fn schema_version(con: &Connection) -> rusqlite::Result<i64> {
+    con.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Bring the database up to SCHEMA_VERSION, returns the version it was at
+pub fn migrate(con: &Connection) -> Result<i64, SchemaError> {
+    apply(con, MIGRATIONS)
+}
+
+fn apply(con: &Connection, migrations: &[&str]) -> Result<i64, SchemaError> {
+    let latest = migrations.len() as i64;
+    // the common case, don't take the write lock for nothing
+    if schema_version(con)? == latest {
+        return Ok(latest);
+    }
+
+    // immediate so two processes opening the database at once don't both migrate it
+    let tx = Transaction::new_unchecked(con, TransactionBehavior::Immediate)?;
+    let found = schema_version(&tx)?;
+    if found > latest {
+        return Err(SchemaError::TooNew { found, supported: latest });
+    }
+    for (version, sql) in migrations.iter().enumerate().skip(found as usize) {
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", version as i64 + 1)?;
+    }
+    tx.commit()?;
+    if found < latest {
+        println!("Migrated metadata schema from version {} to {}", found, latest);
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use meta_store::MetadataStore;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{get_connection, SqliteMetadataStore};
+
+    /// a database file as an older release left it
+    fn fixture(dir: &TempDir, sql: &str) -> String {
+        let path = dir.path().join("metadata.db").to_string_lossy().to_string();
+        Connection::open(&path).unwrap().execute_batch(sql).unwrap();
+        path
+    }
+
+    fn in_schema(con: &Connection, name: &str) -> bool {
+        con.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = ?)",
+            [name],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_fresh_database() {
+        let con = get_connection(None).unwrap();
+        assert_eq!(schema_version(&con).unwrap(), SCHEMA_VERSION);
+        assert!(in_schema(&con, "objects_by_key"));
+        // running again is a no-op
+        assert_eq!(migrate(&con).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_baseline_fixture() {
+        let dir = TempDir::new("tcpfs-migrations").unwrap();
+        let path = fixture(&dir, include_str!("../fixtures/v0-baseline.sql"));
+        let con = Connection::open(&path).unwrap();
+        assert_eq!(schema_version(&con).unwrap(), 0);
+        assert!(!in_schema(&con, "principals"));
+        drop(con);
+
+        let con = get_connection(Some(path.clone())).unwrap();
+        assert_eq!(schema_version(&con).unwrap(), SCHEMA_VERSION);
+        assert!(in_schema(&con, "principals"));
+        assert!(in_schema(&con, "object_segments"));
+        drop(con);
+
+        // the old rows and triggers keep working
+        let store = SqliteMetadataStore::open(Some(path)).unwrap();
+        let bucket = "11111111-2222-3333-4444-555555555555";
+        let found = store.get_object(bucket, "docs/a.txt").unwrap().unwrap();
+        assert!(found.location.ends_with("/file.data"));
+        assert_eq!(store.get_usage(Some(bucket)).unwrap().logical, 150);
+        store.delete_object(bucket, "docs/b.txt").unwrap().unwrap();
+        assert_eq!(store.get_usage(Some(bucket)).unwrap().logical, 100);
+        store.upsert_principal("ci", "deadbeef", false).unwrap();
+    }
+
+    #[test]
+    fn test_full_fixture() {
+        let dir = TempDir::new("tcpfs-migrations").unwrap();
+        let path = fixture(&dir, include_str!("../fixtures/v0-full.sql"));
+        let store = SqliteMetadataStore::open(Some(path)).unwrap();
+
+        assert_eq!(store.get_principal_by_cert("cc").unwrap().unwrap().name, "ci");
+        assert_eq!(store.get_permissions("bucket", "ci").unwrap(), 9);
+        assert!(store.is_bucket_encrypted("bucket").unwrap());
+        let secret = store.get_object("bucket", "secret").unwrap().unwrap();
+        assert_eq!(secret.wrapped_key, Some(vec![1, 2, 3]));
+        assert_eq!(store.get_object("bucket", "shared").unwrap().unwrap().location, ".blobs/ab/cd/abcd");
+        assert_eq!(store.get_object("bucket", "packed").unwrap().unwrap().range, Some((0, 5)));
+        assert_eq!(store.delete_object("bucket", "shared").unwrap().unwrap().garbage, vec![".blobs/ab/cd/abcd"]);
+    }
+
+    #[test]
+    fn test_newer_database_is_refused() {
+        let dir = TempDir::new("tcpfs-migrations").unwrap();
+        let path = dir.path().join("metadata.db").to_string_lossy().to_string();
+        let con = get_connection(Some(path.clone())).unwrap();
+        con.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        drop(con);
+
+        match get_connection(Some(path)) {
+            Err(SchemaError::TooNew { found, supported }) => {
+                assert_eq!((found, supported), (SCHEMA_VERSION + 1, SCHEMA_VERSION))
+            }
+            other => panic!("expected TooNew, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_failed_migration_rolls_back() {
+        let dir = TempDir::new("tcpfs-migrations").unwrap();
+        let path = fixture(&dir, include_str!("../fixtures/v0-baseline.sql"));
+        let con = Connection::open(&path).unwrap();
+
+        assert!(apply(&con, &[MIGRATIONS[0], "CREATE TABLE broken ("]).is_err());
+        assert_eq!(schema_version(&con).unwrap(), 0);
+        assert!(!in_schema(&con, "principals"));
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+use rusqlite::Connection;
+
+use crate::{get_connection, SchemaError};
+
+/// statements kept prepared per connection, see `prepare_cached`
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// most connections open at once, callers wait for one to come back beyond that
+    pub max_size: usize,
+    /// how long a statement waits on another connection's write lock before SQLITE_BUSY
+    pub busy_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 16,
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+struct PoolState {
+    idle: Vec<Connection>,
+    open: usize,
+}
+
+/// Connections to one database, opened on demand and reused. The schema is migrated once when
+/// the pool is created, not per connection
+pub struct ConnectionPool {
+    db_path: Option<String>,
+    config: PoolConfig,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+impl ConnectionPool {
+    /// `None` is an in memory database, which only exists on its connection so the pool holds just the one
+    pub fn open(db_path: Option<String>, mut config: PoolConfig) -> Result<ConnectionPool, SchemaError> {
+        if db_path.is_none() {
+            config.max_size = 1;
+        }
+        let first = get_connection(db_path.clone())?;
+        Self::configure(&first, &config)?;
+        Ok(ConnectionPool {
+            db_path,
+            config,
+            state: Mutex::new(PoolState { idle: vec![first], open: 1 }),
+            returned: Condvar::new(),
+        })
+    }
+
+    fn configure(con: &Connection, config: &PoolConfig) -> rusqlite::Result<()> {
+        con.busy_timeout(config.busy_timeout)?;
+        con.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Take a connection, it goes back to the pool when dropped
+    pub fn get(&self) -> rusqlite::Result<PooledConnection<'_>> {
+        let mut state = self.lock();
+        loop {
+            if let Some(con) = state.idle.pop() {
+                return Ok(PooledConnection { pool: self, con: Some(con) });
+            }
+            if state.open < self.config.max_size {
+                state.open += 1;
+                drop(state);
+                return match self.connect() {
+                    Ok(con) => Ok(PooledConnection { pool: self, con: Some(con) }),
+                    Err(e) => {
+                        self.lock().open -= 1;
+                        self.returned.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+            state = self.returned.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn connect(&self) -> rusqlite::Result<Connection> {
+        // only file databases get here, see `open`
+        let con = Connection::open(self.db_path.as_deref().unwrap_or(":memory:"))?;
+        Self::configure(&con, &self.config)?;
+        Ok(con)
+    }
+}
+
+pub struct PooledConnection<'a> {
+    pool: &'a ConnectionPool,
+    con: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.con.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.con.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(con) = self.con.take() {
+            self.pool.lock().idle.push(con);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_connections_are_reused_and_bounded() {
+        let dir = TempDir::new("tcpfs-pool").unwrap();
+        let db_path = dir.path().join("metadata.db").to_string_lossy().to_string();
+        let config = PoolConfig { max_size: 2, ..PoolConfig::default() };
+        let pool = Arc::new(ConnectionPool::open(Some(db_path), config).unwrap());
+
+        let a = pool.get().unwrap();
+        let b = pool.get().unwrap();
+        assert_eq!(pool.lock().open, 2);
+
+        // a third caller waits until one comes back
+        let waiter = {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                let con = pool.get().unwrap();
+                con.query_row("SELECT COUNT(*) FROM objects", [], |row| row.get::<_, i64>(0)).unwrap()
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        drop(a);
+        assert_eq!(waiter.join().unwrap(), 0);
+        drop(b);
+
+        let state = pool.lock();
+        assert_eq!((state.open, state.idle.len()), (2, 2));
+    }
+}
@@ -0,0 +1,340 @@
+use meta_store::{
+    Compression, DeletedObject, MetadataStore, NewObject, Object, Placement, Principal, Segment, SegmentMove,
+    StoredObject, TcpfsError, Usage,
+};
+use rusqlite::{Connection, Error, ErrorCode, TransactionBehavior};
+
+use crate::{
+    acquire_blob, add_principal_cert, add_segment_entry, bucket_has_acl, create_segment,
+    delete_metadata, delete_segment, detach_object, get_active_segment, get_all_objects, get_compactable_segments,
+    get_bucket_compression, get_bucket_size, get_object_blob_path, get_object_by_key, get_object_compression, get_object_key,
+    get_object_segment,
+    get_objects_in_path, get_permissions, get_principal_by_cert, get_principal_by_key_hash,
+    get_segment_entries, get_usage, grant_permissions, insert_metadata, insert_object_compression, insert_object_key,
+    is_bucket_encrypted, link_object_blob, move_segment_entry, resize_object, revoke_permissions, seal_segment,
+    set_bucket_compression, set_bucket_encrypted, take_unreferenced_blobs, update_object_path, upsert_principal, ConnectionPool,
+    PoolConfig,
+};
+
+/// The MetadataStore on top of the functions in this crate, calls borrow a connection from the pool
+pub struct SqliteMetadataStore {
+    pool: ConnectionPool,
+}
+
+impl SqliteMetadataStore {
+    /// Creates the tables if needed, `None` keeps everything in memory
+    pub fn open(db_path: Option<String>) -> meta_store::Result<Self> {
+        Self::open_with(db_path, PoolConfig::default())
+    }
+
+    pub fn open_with(db_path: Option<String>, config: PoolConfig) -> meta_store::Result<Self> {
+        Ok(SqliteMetadataStore { pool: ConnectionPool::open(db_path, config)? })
+    }
+
+    fn with_connection<T>(&self, f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>) -> meta_store::Result<T> {
+        let mut con = self.pool.get().map_err(db_error)?;
+        f(&mut con).map_err(db_error)
+    }
+}
+
+/// stored compression numbers, one this build doesn't know is an error rather than garbage
+fn compression(value: u8) -> rusqlite::Result<Compression> {
+    Compression::from_u8(value).ok_or(Error::IntegralValueOutOfRange(0, value.into()))
+}
+
+fn db_error(e: Error) -> TcpfsError {
+    match e {
+        Error::QueryReturnedNoRows => TcpfsError::NotFound(e.to_string()),
+        Error::SqliteFailure(failure, _) if failure.code == ErrorCode::ConstraintViolation => {
+            TcpfsError::Conflict(e.to_string())
+        }
+        Error::SqliteFailure(failure, _) if failure.code == ErrorCode::DiskFull => TcpfsError::Quota(e.to_string()),
+        e => TcpfsError::Db(Box::new(e)),
+    }
+}
+
+/// Writes take the lock up front. A deferred transaction that reads first can't wait out
+/// another writer when it upgrades, sqlite fails it with SQLITE_BUSY straight away
+fn write_transaction(con: &mut Connection) -> rusqlite::Result<rusqlite::Transaction<'_>> {
+    con.transaction_with_behavior(TransactionBehavior::Immediate)
+}
+
+/// None instead of QueryReturnedNoRows
+fn optional<T>(result: rusqlite::Result<T>) -> rusqlite::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl MetadataStore for SqliteMetadataStore {
+    fn upsert_principal(&self, name: &str, api_key_hash: &str, is_admin: bool) -> meta_store::Result<()> {
+        self.with_connection(|con| upsert_principal(con, name, api_key_hash, is_admin).map(|_| ()))
+    }
+
+    fn get_principal_by_key_hash(&self, api_key_hash: &str) -> meta_store::Result<Option<Principal>> {
+        self.with_connection(|con| get_principal_by_key_hash(con, api_key_hash))
+    }
+
+    fn add_principal_cert(&self, fingerprint: &str, principal: &str) -> meta_store::Result<bool> {
+        self.with_connection(|con| Ok(add_principal_cert(con, fingerprint, principal)? > 0))
+    }
+
+    fn get_principal_by_cert(&self, fingerprint: &str) -> meta_store::Result<Option<Principal>> {
+        self.with_connection(|con| get_principal_by_cert(con, fingerprint))
+    }
+
+    fn grant_permissions(&self, bucket_id: &str, principal: &str, permissions: u8) -> meta_store::Result<bool> {
+        self.with_connection(|con| Ok(grant_permissions(con, bucket_id, principal, permissions)? > 0))
+    }
+
+    fn revoke_permissions(&self, bucket_id: &str, principal: &str, permissions: u8) -> meta_store::Result<()> {
+        self.with_connection(|con| revoke_permissions(con, bucket_id, principal, permissions).map(|_| ()))
+    }
+
+    fn get_permissions(&self, bucket_id: &str, principal: &str) -> meta_store::Result<u8> {
+        self.with_connection(|con| get_permissions(con, bucket_id, principal))
+    }
+
+    fn bucket_has_acl(&self, bucket_id: &str) -> meta_store::Result<bool> {
+        self.with_connection(|con| bucket_has_acl(con, bucket_id))
+    }
+
+    fn set_bucket_encrypted(&self, bucket_id: &str, encrypted: bool) -> meta_store::Result<()> {
+        self.with_connection(|con| set_bucket_encrypted(con, bucket_id, encrypted).map(|_| ()))
+    }
+
+    fn is_bucket_encrypted(&self, bucket_id: &str) -> meta_store::Result<bool> {
+        self.with_connection(|con| is_bucket_encrypted(con, bucket_id))
+    }
+
+    fn set_bucket_compression(&self, bucket_id: &str, compression: Compression) -> meta_store::Result<()> {
+        self.with_connection(|con| set_bucket_compression(con, bucket_id, compression as u8).map(|_| ()))
+    }
+
+    fn get_bucket_compression(&self, bucket_id: &str) -> meta_store::Result<Compression> {
+        self.with_connection(|con| compression(get_bucket_compression(con, bucket_id)?))
+    }
+
+    fn get_usage(&self, bucket_id: Option<&str>) -> meta_store::Result<Usage> {
+        let (logical, physical, compressed_logical, compressed_physical) =
+            self.with_connection(|con| get_usage(con, bucket_id))?;
+        Ok(Usage {
+            logical: logical as u64,
+            physical: physical as u64,
+            compressed_logical: compressed_logical as u64,
+            compressed_physical: compressed_physical as u64,
+        })
+    }
+
+    fn get_bucket_size(&self, bucket_id: &str) -> meta_store::Result<u64> {
+        self.with_connection(|con| get_bucket_size(con, bucket_id)).map(|size| size as u64)
+    }
+
+    fn insert_object(&self, object: &NewObject) -> meta_store::Result<i64> {
+        self.with_connection(|con| {
+            let tx = write_transaction(con)?;
+            insert_metadata(
+                &tx,
+                object.bucket_id,
+                object.key,
+                object.path,
+                &object.size.to_string(),
+                object.created_at,
+            )?;
+            let object_id = tx.last_insert_rowid();
+            if let Some(wrapped_key) = object.wrapped_key {
+                insert_object_key(&tx, object_id, wrapped_key)?;
+            }
+            if object.compression != Compression::None {
+                insert_object_compression(&tx, object_id, object.compression as u8, object.stored_size)?;
+            }
+            match &object.placement {
+                Placement::Own => {}
+                Placement::Shared { hash, path } => {
+                    acquire_blob(&tx, hash, path, object.size)?;
+                    link_object_blob(&tx, object_id, hash)?;
+                }
+                Placement::Packed { segment_id, offset, length } => {
+                    add_segment_entry(&tx, *segment_id, object_id, *offset, *length)?;
+                }
+            }
+            tx.commit()?;
+            Ok(object_id)
+        })
+    }
+
+    fn get_object(&self, bucket_id: &str, key: &str) -> meta_store::Result<Option<StoredObject>> {
+        self.with_connection(|con| {
+            let tx = con.transaction()?;
+            let object = match optional(get_object_by_key(&tx, bucket_id, key))? {
+                Some(object) => object,
+                None => return Ok(None),
+            };
+            let object_id = object.id.into();
+            let wrapped_key = get_object_key(&tx, object_id)?;
+            let compression = compression(get_object_compression(&tx, object_id)?)?;
+            // packed objects are read straight out of their segment
+            let (location, range) = match get_object_segment(&tx, object_id)? {
+                Some((path, offset, length)) => (path, Some((offset as u64, length as u64))),
+                None => (get_object_blob_path(&tx, object_id)?.unwrap_or_else(|| object.path.clone()), None),
+            };
+            Ok(Some(StoredObject { object, wrapped_key, compression, location, range }))
+        })
+    }
+
+    fn extend_object(&self, object_id: i64, size: i64, new_size: i64, detach: bool) -> meta_store::Result<Option<Vec<String>>> {
+        self.with_connection(|con| {
+            let tx = write_transaction(con)?;
+            if !resize_object(&tx, object_id, size, new_size)? {
+                return Ok(None);
+            }
+            let garbage = match detach {
+                true => {
+                    detach_object(&tx, object_id)?;
+                    take_unreferenced_blobs(&tx)?
+                }
+                false => Vec::new(),
+            };
+            tx.commit()?;
+            Ok(Some(garbage))
+        })
+    }
+
+    fn delete_object(&self, bucket_id: &str, key: &str) -> meta_store::Result<Option<DeletedObject>> {
+        self.with_connection(|con| {
+            let tx = write_transaction(con)?;
+            let object = match optional(get_object_by_key(&tx, bucket_id, key))? {
+                Some(object) => object,
+                None => return Ok(None),
+            };
+            let deduplicated = get_object_blob_path(&tx, object.id.into())?.is_some();
+            let packed = get_object_segment(&tx, object.id.into())?.is_some();
+            delete_metadata(&tx, bucket_id, &object.path)?;
+            // shared blobs only go once the last object referring to them does,
+            // packed objects leave dead space behind for compaction
+            let garbage = match (deduplicated, packed) {
+                (true, _) => take_unreferenced_blobs(&tx)?,
+                (false, true) => Vec::new(),
+                (false, false) => vec![object.path.clone()],
+            };
+            tx.commit()?;
+            Ok(Some(DeletedObject { object, garbage }))
+        })
+    }
+
+    fn get_objects_in_path(&self, bucket_id: &str, prefix: &str) -> meta_store::Result<Vec<Object>> {
+        self.with_connection(|con| get_objects_in_path(con, bucket_id, prefix))
+    }
+
+    fn get_all_objects(&self) -> meta_store::Result<Vec<Object>> {
+        self.with_connection(|con| get_all_objects(con))
+    }
+
+    fn update_object_path(&self, object_id: i64, path: &str) -> meta_store::Result<()> {
+        self.with_connection(|con| {
+            let tx = write_transaction(con)?;
+            update_object_path(&tx, object_id, path)?;
+            tx.commit()
+        })
+    }
+
+    fn get_active_segment(&self) -> meta_store::Result<Option<Segment>> {
+        self.with_connection(|con| get_active_segment(con))
+    }
+
+    fn create_segment(&self, path: &str) -> meta_store::Result<Segment> {
+        self.with_connection(|con| create_segment(con, path))
+    }
+
+    fn seal_segment(&self, segment_id: i64) -> meta_store::Result<()> {
+        self.with_connection(|con| seal_segment(con, segment_id).map(|_| ()))
+    }
+
+    fn get_compactable_segments(&self, max_live_ratio: f64) -> meta_store::Result<Vec<Segment>> {
+        self.with_connection(|con| get_compactable_segments(con, max_live_ratio))
+    }
+
+    fn get_segment_entries(&self, segment_id: i64) -> meta_store::Result<Vec<(i64, i64, i64)>> {
+        self.with_connection(|con| get_segment_entries(con, segment_id))
+    }
+
+    fn relocate_segment(&self, segment_id: i64, moves: &[SegmentMove]) -> meta_store::Result<()> {
+        self.with_connection(|con| {
+            let tx = write_transaction(con)?;
+            for m in moves {
+                move_segment_entry(&tx, m.object_id, segment_id, m.segment_id, m.offset)?;
+            }
+            delete_segment(&tx, segment_id)?;
+            tx.commit()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, sync::Arc, thread, time::Duration};
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_conformance_in_memory() {
+        meta_store::conformance::run(|| Box::new(SqliteMetadataStore::open(None).unwrap()));
+    }
+
+    #[test]
+    fn test_conformance_on_disk() {
+        let dir = TempDir::new("tcpfs-meta").unwrap();
+        let n = Cell::new(0);
+        meta_store::conformance::run(|| {
+            n.set(n.get() + 1);
+            let db_path = dir.path().join(format!("{}.db", n.get()));
+            Box::new(SqliteMetadataStore::open(Some(db_path.to_string_lossy().to_string())).unwrap())
+        });
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let dir = TempDir::new("tcpfs-meta").unwrap();
+        let db_path = dir.path().join("metadata.db").to_string_lossy().to_string();
+        let config = PoolConfig { max_size: 4, busy_timeout: Duration::from_secs(10) };
+        let store = Arc::new(SqliteMetadataStore::open_with(Some(db_path), config).unwrap());
+
+        let writers: Vec<_> = (0..8)
+            .map(|n| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        let key = format!("{}/{}", n, i);
+                        let path = format!("b/{}.data", key);
+                        store
+                            .insert_object(&NewObject {
+                                bucket_id: "b",
+                                key: &key,
+                                path: &path,
+                                size: 1,
+                                created_at: "2024-01-01T00:00:00+00:00",
+                                wrapped_key: None,
+                                compression: Compression::None,
+                                stored_size: 1,
+                                placement: Placement::Own,
+                            })
+                            .unwrap();
+                        // read then write, the case a deferred transaction gets SQLITE_BUSY on
+                        if i % 2 == 0 {
+                            store.delete_object("b", &key).unwrap().unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(store.get_all_objects().unwrap().len(), 8 * 12);
+        assert_eq!(store.get_usage(Some("b")).unwrap().logical, 8 * 12);
+    }
+}
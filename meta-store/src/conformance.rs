@@ -0,0 +1,294 @@
+//! The behaviour every MetadataStore has to share, implementations call `run` from their tests
+
+use crate::*;
+
+const CREATED_AT: &str = "2024-01-01T00:00:00+00:00";
+
+/// `new_store` has to hand out an empty store every time
+pub fn run(new_store: impl Fn() -> Box<dyn MetadataStore>) {
+    principals_and_acls(new_store().as_ref());
+    objects(new_store().as_ref());
+    encryption(new_store().as_ref());
+    compression(new_store().as_ref());
+    shared_blobs(new_store().as_ref());
+    segments(new_store().as_ref());
+    appends(new_store().as_ref());
+}
+
+fn insert(store: &dyn MetadataStore, bucket_id: &str, key: &str, size: i64, placement: Placement) -> i64 {
+    let path = format!("{}/{}.data", bucket_id, key);
+    store
+        .insert_object(&NewObject {
+            bucket_id,
+            key,
+            path: &path,
+            size,
+            created_at: CREATED_AT,
+            wrapped_key: None,
+            compression: Compression::None,
+            stored_size: size,
+            placement,
+        })
+        .unwrap()
+}
+
+fn keys(objects: Vec<Object>) -> Vec<String> {
+    let mut keys: Vec<String> = objects.into_iter().map(|o| o.key).collect();
+    keys.sort();
+    keys
+}
+
+fn usage(logical: u64, physical: u64) -> Usage {
+    Usage { logical, physical, ..Usage::default() }
+}
+
+fn principals_and_acls(store: &dyn MetadataStore) {
+    store.upsert_principal("ci", "deadbeef", false).unwrap();
+    let ci = store.get_principal_by_key_hash("deadbeef").unwrap().unwrap();
+    assert_eq!(ci, Principal { name: "ci".to_string(), is_admin: false });
+    assert!(store.get_principal_by_key_hash("nope").unwrap().is_none());
+
+    // upserting replaces the key
+    store.upsert_principal("ci", "cafe", true).unwrap();
+    assert!(store.get_principal_by_key_hash("deadbeef").unwrap().is_none());
+    assert!(store.get_principal_by_key_hash("cafe").unwrap().unwrap().is_admin);
+    // a key belongs to one principal
+    assert!(matches!(store.upsert_principal("other", "cafe", false), Err(TcpfsError::Conflict(_))));
+    assert_eq!(store.get_principal_by_key_hash("cafe").unwrap().unwrap().name, "ci");
+
+    assert!(store.add_principal_cert("fp", "ci").unwrap());
+    assert!(!store.add_principal_cert("fp2", "nobody").unwrap());
+    assert_eq!(store.get_principal_by_cert("fp").unwrap().unwrap().name, "ci");
+    assert!(store.get_principal_by_cert("fp2").unwrap().is_none());
+
+    assert!(!store.bucket_has_acl("b").unwrap());
+    assert_eq!(store.get_permissions("b", "ci").unwrap(), 0);
+    assert!(store.grant_permissions("b", "ci", 0b0001).unwrap());
+    assert!(store.grant_permissions("b", "ci", 0b0010).unwrap());
+    assert!(!store.grant_permissions("b", "nobody", 0b0001).unwrap());
+    assert!(store.bucket_has_acl("b").unwrap());
+    assert!(!store.bucket_has_acl("other").unwrap());
+    assert_eq!(store.get_permissions("b", "ci").unwrap(), 0b0011);
+
+    store.revoke_permissions("b", "ci", 0b0001).unwrap();
+    assert_eq!(store.get_permissions("b", "ci").unwrap(), 0b0010);
+    store.revoke_permissions("b", "ci", 0b0010).unwrap();
+    assert!(!store.bucket_has_acl("b").unwrap());
+    store.revoke_permissions("b", "nobody", 0b0010).unwrap();
+}
+
+fn objects(store: &dyn MetadataStore) {
+    assert_eq!(store.get_usage(None).unwrap(), Usage::default());
+    for key in ["a", "dir/b", "dir/c", "dir/sub/d", "a_b/1", "axb/2"] {
+        insert(store, "one", key, 10, Placement::Own);
+    }
+    insert(store, "two", "a", 5, Placement::Own);
+    let taken = NewObject {
+        bucket_id: "one",
+        key: "elsewhere",
+        path: "one/a.data",
+        size: 1,
+        created_at: CREATED_AT,
+        wrapped_key: None,
+        compression: Compression::None,
+        stored_size: 1,
+        placement: Placement::Own,
+    };
+    assert!(matches!(store.insert_object(&taken), Err(TcpfsError::Conflict(_))));
+
+    let found = store.get_object("one", "dir/b").unwrap().unwrap();
+    assert_eq!(found.object.key, "dir/b");
+    assert_eq!(found.object.bucket_id, "one");
+    assert_eq!(found.object.file_size, 10);
+    assert_eq!(found.location, found.object.path);
+    assert_eq!((found.wrapped_key, found.range), (None, None));
+    assert!(store.get_object("two", "dir/b").unwrap().is_none());
+
+    assert_eq!(keys(store.get_objects_in_path("one", "").unwrap()), ["a"]);
+    assert_eq!(keys(store.get_objects_in_path("one", "dir/").unwrap()), ["dir/b", "dir/c"]);
+    assert_eq!(keys(store.get_objects_in_path("one", "dir/sub/").unwrap()), ["dir/sub/d"]);
+    // keys are matched literally
+    assert_eq!(keys(store.get_objects_in_path("one", "a_b/").unwrap()), ["a_b/1"]);
+    assert!(store.get_objects_in_path("one", "%/").unwrap().is_empty());
+    assert_eq!(store.get_all_objects().unwrap().len(), 7);
+
+    assert_eq!(store.get_usage(Some("one")).unwrap(), usage(60, 60));
+    assert_eq!(store.get_usage(None).unwrap(), usage(65, 65));
+    assert_eq!((store.get_bucket_size("one").unwrap(), store.get_bucket_size("none").unwrap()), (60, 0));
+
+    let deleted = store.delete_object("one", "dir/b").unwrap().unwrap();
+    assert_eq!(deleted.object, found.object);
+    assert_eq!(deleted.garbage, vec![found.object.path.clone()]);
+    assert!(store.get_object("one", "dir/b").unwrap().is_none());
+    assert!(store.delete_object("one", "dir/b").unwrap().is_none());
+    assert_eq!(store.get_usage(Some("one")).unwrap(), usage(50, 50));
+    assert_eq!(store.get_bucket_size("one").unwrap(), 50);
+
+    let a = store.get_object("two", "a").unwrap().unwrap().object;
+    store.update_object_path(a.id.into(), "moved/a.data").unwrap();
+    assert_eq!(store.get_object("two", "a").unwrap().unwrap().location, "moved/a.data");
+}
+
+fn encryption(store: &dyn MetadataStore) {
+    assert!(!store.is_bucket_encrypted("b").unwrap());
+    store.set_bucket_encrypted("b", true).unwrap();
+    assert!(store.is_bucket_encrypted("b").unwrap());
+    assert!(!store.is_bucket_encrypted("other").unwrap());
+
+    store
+        .insert_object(&NewObject {
+            bucket_id: "b",
+            key: "secret",
+            path: "b/secret.data",
+            size: 3,
+            created_at: CREATED_AT,
+            wrapped_key: Some(&[1, 2, 3]),
+            compression: Compression::None,
+            stored_size: 3,
+            placement: Placement::Own,
+        })
+        .unwrap();
+    let found = store.get_object("b", "secret").unwrap().unwrap();
+    assert_eq!(found.wrapped_key, Some(vec![1, 2, 3]));
+
+    store.set_bucket_encrypted("b", false).unwrap();
+    assert!(!store.is_bucket_encrypted("b").unwrap());
+}
+
+fn compression(store: &dyn MetadataStore) {
+    assert_eq!(store.get_bucket_compression("b").unwrap(), Compression::None);
+    store.set_bucket_compression("b", Compression::Zstd).unwrap();
+    assert_eq!(store.get_bucket_compression("b").unwrap(), Compression::Zstd);
+    assert_eq!(store.get_bucket_compression("other").unwrap(), Compression::None);
+    // independent of encryption
+    store.set_bucket_encrypted("b", true).unwrap();
+    assert_eq!(store.get_bucket_compression("b").unwrap(), Compression::Zstd);
+
+    store
+        .insert_object(&NewObject {
+            bucket_id: "b",
+            key: "logs",
+            path: "b/logs.data",
+            size: 1000,
+            created_at: CREATED_AT,
+            wrapped_key: None,
+            compression: Compression::Lz4,
+            stored_size: 200,
+            placement: Placement::Own,
+        })
+        .unwrap();
+    let found = store.get_object("b", "logs").unwrap().unwrap();
+    assert_eq!((found.compression, found.object.file_size), (Compression::Lz4, 1000));
+    insert(store, "b", "plain", 10, Placement::Own);
+    assert_eq!(store.get_object("b", "plain").unwrap().unwrap().compression, Compression::None);
+
+    // compressed objects take their stored size on disk
+    let expected = Usage { logical: 1010, physical: 210, compressed_logical: 1000, compressed_physical: 200 };
+    assert_eq!(store.get_usage(Some("b")).unwrap(), expected);
+    assert_eq!(store.get_bucket_size("b").unwrap(), 1010);
+    assert_eq!(store.get_usage(None).unwrap(), expected);
+    store.delete_object("b", "logs").unwrap();
+    assert_eq!(store.get_usage(Some("b")).unwrap(), usage(10, 10));
+}
+
+fn shared_blobs(store: &dyn MetadataStore) {
+    let shared = || Placement::Shared { hash: "abc".to_string(), path: ".blobs/abc".to_string() };
+    for (bucket, key) in [("one", "a"), ("one", "b"), ("two", "a")] {
+        insert(store, bucket, key, 100, shared());
+    }
+    insert(store, "one", "plain", 10, Placement::Own);
+
+    assert_eq!(store.get_usage(Some("one")).unwrap(), usage(210, 110));
+    assert_eq!(store.get_usage(Some("two")).unwrap(), usage(100, 100));
+    assert_eq!(store.get_usage(None).unwrap(), usage(310, 110));
+
+    let found = store.get_object("two", "a").unwrap().unwrap();
+    assert_eq!(found.location, ".blobs/abc");
+    assert_eq!(found.range, None);
+
+    // the blob only goes with the last object referring to it
+    assert!(store.delete_object("one", "a").unwrap().unwrap().garbage.is_empty());
+    assert!(store.delete_object("one", "b").unwrap().unwrap().garbage.is_empty());
+    assert_eq!(store.delete_object("two", "a").unwrap().unwrap().garbage, vec![".blobs/abc".to_string()]);
+    assert_eq!(store.get_usage(None).unwrap(), usage(10, 10));
+
+    // and can be registered again afterwards
+    insert(store, "one", "again", 100, shared());
+    assert_eq!(store.get_object("one", "again").unwrap().unwrap().location, ".blobs/abc");
+}
+
+fn segments(store: &dyn MetadataStore) {
+    assert!(store.get_active_segment().unwrap().is_none());
+    let old = store.create_segment(".segments/1.seg").unwrap();
+    assert_eq!((old.size, old.live_bytes), (0, 0));
+
+    for (i, key) in ["a", "b"].iter().enumerate() {
+        let placement = Placement::Packed { segment_id: old.id, offset: i as i64 * 10, length: 10 };
+        insert(store, "b", key, 10, placement);
+    }
+    let active = store.get_active_segment().unwrap().unwrap();
+    assert_eq!((active.id, active.size, active.live_bytes), (old.id, 20, 20));
+    let found = store.get_object("b", "b").unwrap().unwrap();
+    assert_eq!((found.location.as_str(), found.range), (".segments/1.seg", Some((10, 10))));
+
+    // packed objects leave dead space behind instead of garbage
+    assert!(store.delete_object("b", "a").unwrap().unwrap().garbage.is_empty());
+    assert!(store.get_compactable_segments(0.5).unwrap().is_empty());
+    store.seal_segment(old.id).unwrap();
+    assert!(store.get_active_segment().unwrap().is_none());
+    let compactable = store.get_compactable_segments(0.5).unwrap();
+    assert_eq!(compactable.len(), 1);
+    assert_eq!((compactable[0].size, compactable[0].live_bytes), (20, 10));
+
+    let new = store.create_segment(".segments/2.seg").unwrap();
+    assert_ne!(new.id, old.id);
+    let entries = store.get_segment_entries(old.id).unwrap();
+    assert_eq!(entries, vec![(found.object.id.into(), 10, 10)]);
+
+    // "c" is deleted after its entry was read, the move is skipped
+    let c = insert(store, "b", "c", 5, Placement::Packed { segment_id: new.id, offset: 0, length: 5 });
+    store.delete_object("b", "c").unwrap();
+    let moves = [
+        SegmentMove { object_id: found.object.id.into(), segment_id: new.id, offset: 5 },
+        SegmentMove { object_id: c, segment_id: new.id, offset: 15 },
+    ];
+    store.relocate_segment(old.id, &moves).unwrap();
+
+    let moved = store.get_object("b", "b").unwrap().unwrap();
+    assert_eq!((moved.location.as_str(), moved.range), (".segments/2.seg", Some((5, 10))));
+    assert!(store.get_segment_entries(old.id).unwrap().is_empty());
+    assert!(store.get_compactable_segments(1.0).unwrap().is_empty());
+    let active = store.get_active_segment().unwrap().unwrap();
+    assert_eq!((active.id, active.size, active.live_bytes), (new.id, 15, 10));
+}
+
+fn appends(store: &dyn MetadataStore) {
+    let log = insert(store, "b", "log", 10, Placement::Own);
+    assert_eq!(store.extend_object(log, 10, 15, false).unwrap(), Some(Vec::new()));
+    // someone else got there first
+    assert_eq!(store.extend_object(log, 10, 20, false).unwrap(), None);
+    assert_eq!(store.get_object("b", "log").unwrap().unwrap().object.file_size, 15);
+    assert_eq!(store.get_usage(Some("b")).unwrap(), usage(15, 15));
+
+    // packed and shared objects move to their own blob
+    let segment = store.create_segment(".segments/1.seg").unwrap();
+    let packed = insert(store, "b", "packed", 5, Placement::Packed { segment_id: segment.id, offset: 0, length: 5 });
+    assert_eq!(store.extend_object(packed, 5, 8, true).unwrap(), Some(Vec::new()));
+    let found = store.get_object("b", "packed").unwrap().unwrap();
+    assert_eq!((found.location, found.range, found.object.file_size), ("b/packed.data".to_string(), None, 8));
+    assert!(store.get_segment_entries(segment.id).unwrap().is_empty());
+    assert_eq!(store.get_active_segment().unwrap().unwrap().live_bytes, 0);
+
+    let shared = || Placement::Shared { hash: "abc".to_string(), path: ".blobs/abc".to_string() };
+    let first = insert(store, "b", "first", 100, shared());
+    let second = insert(store, "b", "second", 100, shared());
+    assert_eq!(store.extend_object(first, 100, 110, true).unwrap(), Some(Vec::new()));
+    assert_eq!(store.get_object("b", "first").unwrap().unwrap().location, "b/first.data");
+    assert_eq!(store.extend_object(second, 100, 101, true).unwrap(), Some(vec![".blobs/abc".to_string()]));
+    assert_eq!(store.get_usage(Some("b")).unwrap(), usage(234, 234));
+    assert_eq!(store.get_bucket_size("b").unwrap(), 234);
+
+    // gone objects can't grow
+    store.delete_object("b", "log").unwrap();
+    assert_eq!(store.extend_object(log, 15, 20, false).unwrap(), None);
+}
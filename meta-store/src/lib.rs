@@ -0,0 +1,285 @@
+use std::{error::Error, fmt, io};
+
+#[cfg(feature = "conformance")]
+pub mod conformance;
+
+/*
+METADATA STORES:
+everything the server knows about buckets, objects, principals and where blob bytes live goes
+through a MetadataStore, the protocol crate never talks to a database directly.
+meta-sqlite is the default, meta-redb an embedded pure rust alternative.
+every method is its own transaction, an object and everything hanging off it (data key,
+shared blob reference, segment entry) is written and removed together.
+there are no object versions or audit log in this tree yet, when they come they belong here too.
+*/
+
+pub type Result<T> = std::result::Result<T, TcpfsError>;
+
+/// What went wrong, shared by the stores and the protocol which maps each kind to a wire status
+#[derive(Debug)]
+pub enum TcpfsError {
+    NotFound(String),
+    /// clashes with something already stored, like a path or api key that is taken
+    Conflict(String),
+    /// out of space
+    Quota(String),
+    Io(io::Error),
+    /// the metadata database failed
+    Db(Box<dyn Error + Send + Sync>),
+    /// the client didn't stick to the wire format
+    Protocol(String),
+}
+
+impl fmt::Display for TcpfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpfsError::NotFound(message)
+            | TcpfsError::Conflict(message)
+            | TcpfsError::Quota(message)
+            | TcpfsError::Protocol(message) => write!(f, "{}", message),
+            TcpfsError::Io(e) => write!(f, "{}", e),
+            TcpfsError::Db(e) => write!(f, "metadata error: {}", e),
+        }
+    }
+}
+
+impl Error for TcpfsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TcpfsError::Io(e) => Some(e),
+            TcpfsError::Db(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TcpfsError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => TcpfsError::Quota(e.to_string()),
+            _ => TcpfsError::Io(e),
+        }
+    }
+}
+
+/// Database errors, a TcpfsError boxed on the way keeps its kind
+impl From<Box<dyn Error + Send + Sync>> for TcpfsError {
+    fn from(e: Box<dyn Error + Send + Sync>) -> Self {
+        match e.downcast::<TcpfsError>() {
+            Ok(e) => *e,
+            Err(e) => TcpfsError::Db(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub id: i32,
+    pub bucket_id: String,
+    pub key: String,
+    pub path: String,
+    pub file_size: i64,
+    //pub is_dir: bool
+}
+
+impl Object
+{
+    pub fn serialize(&self) -> Vec<u8>
+    {
+        let mut result = Vec::new();
+
+        // Path length (32-bit, big-endian), the key is the path clients see
+        let path_len = self.key.len() as u32;
+        result.extend_from_slice(&path_len.to_be_bytes());
+
+        // 128-bit bucket ID
+        result.extend_from_slice(self.bucket_id.as_bytes());
+
+        // Path bytes
+        result.extend_from_slice(self.key.as_bytes());
+
+        // File/dir size (32-bit, big-endian)
+        result.extend_from_slice(&self.file_size.to_be_bytes());
+
+        // Separator "\r\n"
+        result.extend_from_slice(b"\r\n");
+
+        result
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+    pub is_admin: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub id: i64,
+    pub path: String,
+    /// bytes committed to the segment, the file can be longer after a crash
+    pub size: i64,
+    pub live_bytes: i64,
+}
+
+/// Where a new object's bytes went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Placement {
+    /// a blob of its own at the object's path
+    Own,
+    /// a content addressed blob shared by identical objects, registered at `path` if it's new
+    Shared { hash: String, path: String },
+    /// a range of a segment
+    Packed { segment_id: i64, offset: i64, length: i64 },
+}
+
+/// How an object's bytes are compressed at rest, the numbers are what gets stored and sent on the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+impl Compression {
+    pub fn from_u8(value: u8) -> Option<Compression> {
+        match value {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+pub struct NewObject<'a> {
+    pub bucket_id: &'a str,
+    pub key: &'a str,
+    /// unique within the bucket, also where an `Own` blob lives
+    pub path: &'a str,
+    pub size: i64,
+    pub created_at: &'a str,
+    /// set for objects encrypted at rest
+    pub wrapped_key: Option<&'a [u8]>,
+    /// `size` is what the client sent, the blob holds it compressed like this
+    pub compression: Compression,
+    /// bytes in the blob, recorded for compressed objects (the others take `size`)
+    pub stored_size: i64,
+    pub placement: Placement,
+}
+
+/// An object and where to read its bytes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub object: Object,
+    pub wrapped_key: Option<Vec<u8>>,
+    pub compression: Compression,
+    pub location: String,
+    /// (offset, length) within `location`, set for packed objects
+    pub range: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeletedObject {
+    pub object: Object,
+    /// blobs nothing refers to anymore, the caller removes them
+    pub garbage: Vec<String>,
+}
+
+/// logical is what clients uploaded, physical what is stored after deduplication and compression.
+/// a blob shared between buckets counts towards each of them, once for the whole store
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub logical: u64,
+    pub physical: u64,
+    /// the objects stored compressed, their logical and physical bytes give the compression ratio
+    pub compressed_logical: u64,
+    pub compressed_physical: u64,
+}
+
+/// A packed object copied into another segment by compaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentMove {
+    pub object_id: i64,
+    pub segment_id: i64,
+    pub offset: i64,
+}
+
+pub trait MetadataStore: Send + Sync {
+    /// replaces the key (and admin flag) if the principal exists
+    fn upsert_principal(&self, name: &str, api_key_hash: &str, is_admin: bool) -> Result<()>;
+
+    fn get_principal_by_key_hash(&self, api_key_hash: &str) -> Result<Option<Principal>>;
+
+    /// false when the principal does not exist
+    fn add_principal_cert(&self, fingerprint: &str, principal: &str) -> Result<bool>;
+
+    fn get_principal_by_cert(&self, fingerprint: &str) -> Result<Option<Principal>>;
+
+    /// OR `permissions` into what the principal holds, false when the principal does not exist
+    fn grant_permissions(&self, bucket_id: &str, principal: &str, permissions: u8) -> Result<bool>;
+
+    /// the entry is dropped once nothing is left
+    fn revoke_permissions(&self, bucket_id: &str, principal: &str, permissions: u8) -> Result<()>;
+
+    fn get_permissions(&self, bucket_id: &str, principal: &str) -> Result<u8>;
+
+    fn bucket_has_acl(&self, bucket_id: &str) -> Result<bool>;
+
+    fn set_bucket_encrypted(&self, bucket_id: &str, encrypted: bool) -> Result<()>;
+
+    fn is_bucket_encrypted(&self, bucket_id: &str) -> Result<bool>;
+
+    /// how new objects in the bucket are stored, whatever clients send
+    fn set_bucket_compression(&self, bucket_id: &str, compression: Compression) -> Result<()>;
+
+    fn get_bucket_compression(&self, bucket_id: &str) -> Result<Compression>;
+
+    /// `None` sums up the whole store
+    fn get_usage(&self, bucket_id: Option<&str>) -> Result<Usage>;
+
+    /// the bucket's logical bytes from the running total kept as objects come, grow and go,
+    /// cheap enough to check on every upload unlike `get_usage`
+    fn get_bucket_size(&self, bucket_id: &str) -> Result<u64>;
+
+    /// returns the new object's id
+    fn insert_object(&self, object: &NewObject) -> Result<i64>;
+
+    /// the oldest object with the key, None if there is none
+    fn get_object(&self, bucket_id: &str, key: &str) -> Result<Option<StoredObject>>;
+
+    /// Grow a plain object from `size` to `new_size` bytes, None if it isn't `size` bytes long (anymore)
+    /// or is gone. `detach` moves a packed or deduplicated object to its own blob at its path, the
+    /// blobs that leaves unreferenced are returned for the caller to remove
+    fn extend_object(&self, object_id: i64, size: i64, new_size: i64, detach: bool) -> Result<Option<Vec<String>>>;
+
+    /// None if there was nothing to delete
+    fn delete_object(&self, bucket_id: &str, key: &str) -> Result<Option<DeletedObject>>;
+
+    /// objects directly under `prefix`, not the ones further down
+    fn get_objects_in_path(&self, bucket_id: &str, prefix: &str) -> Result<Vec<Object>>;
+
+    /// every object in every bucket, used by maintenance tools
+    fn get_all_objects(&self) -> Result<Vec<Object>>;
+
+    fn update_object_path(&self, object_id: i64, path: &str) -> Result<()>;
+
+    /// the newest unsealed segment
+    fn get_active_segment(&self) -> Result<Option<Segment>>;
+
+    fn create_segment(&self, path: &str) -> Result<Segment>;
+
+    fn seal_segment(&self, segment_id: i64) -> Result<()>;
+
+    /// sealed segments where at most `max_live_ratio` of the bytes are still referenced
+    fn get_compactable_segments(&self, max_live_ratio: f64) -> Result<Vec<Segment>>;
+
+    /// (object id, offset, length) of everything still in the segment, by offset
+    fn get_segment_entries(&self, segment_id: i64) -> Result<Vec<(i64, i64, i64)>>;
+
+    /// Point the moved entries at their copies and forget the segment. Objects deleted
+    /// since the entries were read are skipped
+    fn relocate_segment(&self, segment_id: i64, moves: &[SegmentMove]) -> Result<()>;
+}
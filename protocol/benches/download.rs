@@ -0,0 +1,76 @@
+//! sendfile against the buffered copy downloads used before, over a loopback connection.
+//! cargo bench -p protocol --bench download [--features io-uring]
+use std::{fs::File, io::Write};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use protocol::zerocopy;
+use tempdir::TempDir;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+};
+
+const BLOB_SIZE: u64 = 64 * 1024 * 1024;
+
+/// a connected pair, the client end is drained in the background
+async fn sink() -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 1024 * 1024];
+        while client.read(&mut buf).await.unwrap() > 0 {}
+    });
+    sock
+}
+
+fn download(c: &mut Criterion) {
+    let dir = TempDir::new("tcpfs-bench").unwrap();
+    let path = dir.path().join("blob");
+    let chunk: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+    let mut file = File::create(&path).unwrap();
+    for _ in 0..BLOB_SIZE / chunk.len() as u64 {
+        file.write_all(&chunk).unwrap();
+    }
+    drop(file);
+
+    let runtime = Runtime::new().unwrap();
+    let mut sock = runtime.block_on(sink());
+    let mut group = c.benchmark_group("download");
+    group.throughput(Throughput::Bytes(BLOB_SIZE));
+    group.sample_size(20);
+
+    group.bench_function("sendfile", |b| {
+        b.iter(|| {
+            let file = File::open(&path).unwrap();
+            runtime.block_on(zerocopy::send_file(&file, 0, BLOB_SIZE, &sock, None)).unwrap()
+        })
+    });
+    #[cfg(feature = "io-uring")]
+    group.bench_function("io_uring", |b| {
+        b.iter(|| {
+            let file = File::open(&path).unwrap();
+            runtime.block_on(protocol::uring::send_file(file, 0, BLOB_SIZE, &sock, None)).unwrap()
+        })
+    });
+    group.bench_function("buffered", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut file = tokio::fs::File::open(&path).await.unwrap();
+                let mut buf = vec![0; 64 * 1024];
+                loop {
+                    let n = file.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    sock.write_all(&buf[..n]).await.unwrap();
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, download);
+criterion_main!(benches);
@@ -0,0 +1,97 @@
+use meta_store::{MetadataStore, Principal, Result};
+use sha2::{Digest, Sha256};
+
+// permission bits stored in the acl table
+pub const READ: u8 = 0b0001;
+pub const WRITE: u8 = 0b0010;
+pub const DELETE: u8 = 0b0100;
+pub const LIST: u8 = 0b1000;
+pub const ALL: u8 = READ | WRITE | DELETE | LIST;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclMode {
+    /// buckets without any acl entries are open to everyone, this is how the server behaved before acls.
+    /// the first grant on a bucket closes it to anonymous clients and every principal not granted anything
+    Open,
+    /// nothing is allowed unless it was granted (admins always pass)
+    DefaultDeny,
+}
+
+impl AclMode {
+    pub fn parse(mode: &str) -> Option<AclMode> {
+        match mode {
+            "open" => Some(AclMode::Open),
+            "deny" | "default-deny" => Some(AclMode::DefaultDeny),
+            _ => None,
+        }
+    }
+}
+
+pub fn hash_api_key(api_key: &[u8]) -> String {
+    Sha256::digest(api_key)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn authenticate(metadata: &dyn MetadataStore, api_key: &[u8]) -> Result<Option<Principal>> {
+    metadata.get_principal_by_key_hash(&hash_api_key(api_key))
+}
+
+pub fn is_allowed(
+    metadata: &dyn MetadataStore,
+    mode: AclMode,
+    principal: Option<&Principal>,
+    bucket_id: &str,
+    permission: u8,
+) -> Result<bool> {
+    if let Some(p) = principal {
+        if p.is_admin {
+            return Ok(true);
+        }
+        let granted = metadata.get_permissions(bucket_id, &p.name)?;
+        if granted & permission == permission {
+            return Ok(true);
+        }
+    }
+    match mode {
+        AclMode::Open => Ok(!metadata.bucket_has_acl(bucket_id)?),
+        AclMode::DefaultDeny => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(name: &str, is_admin: bool) -> Principal {
+        Principal {
+            name: name.to_string(),
+            is_admin,
+        }
+    }
+
+    #[test]
+    fn test_modes() {
+        let metadata = meta_sqlite::SqliteMetadataStore::open(None).unwrap();
+        metadata.upsert_principal("ci", &hash_api_key(b"ci-key"), false).unwrap();
+        let ci = principal("ci", false);
+        let admin = principal("root", true);
+
+        // nothing granted yet
+        assert!(is_allowed(&metadata, AclMode::Open, None, "b", READ).unwrap());
+        assert!(!is_allowed(&metadata, AclMode::DefaultDeny, None, "b", READ).unwrap());
+        assert!(!is_allowed(&metadata, AclMode::DefaultDeny, Some(&ci), "b", READ).unwrap());
+        assert!(is_allowed(&metadata, AclMode::DefaultDeny, Some(&admin), "b", READ).unwrap());
+
+        // once a bucket has entries it is closed to everyone else even in open mode
+        metadata.grant_permissions("b", "ci", READ | LIST).unwrap();
+        assert!(!is_allowed(&metadata, AclMode::Open, None, "b", READ).unwrap());
+        assert!(is_allowed(&metadata, AclMode::DefaultDeny, Some(&ci), "b", READ).unwrap());
+        assert!(!is_allowed(&metadata, AclMode::DefaultDeny, Some(&ci), "b", WRITE).unwrap());
+
+        let found = authenticate(&metadata, b"ci-key").unwrap().unwrap();
+        assert_eq!(found.name, "ci");
+        assert!(authenticate(&metadata, b"wrong").unwrap().is_none());
+    }
+}
@@ -0,0 +1,279 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+pub use meta_store::Compression;
+
+/*
+COMPRESSION:
+the codec a body travels in (the compressed flag on UPLOAD/DOWNLOAD) and the one a bucket stores
+objects in (SET BUCKET OPTION 0x02) are independent, the server converts on the way in and out:
+    upload:   wire codec -> original bytes (hashed and counted, that is the object) -> bucket codec -> blob
+    download: blob codec -> original bytes (ranges are cut here) -> requested codec -> wire
+a whole object stored in the codec the client asked for goes out as it is.
+zstd and lz4 use their standard frame formats, what the command line tools write can be uploaded.
+objects in encrypted buckets are stored uncompressed
+
+SEEKABLE ZSTD:
+zstd at rest is written in zstd's seekable format, independent frames of SEEK_FRAME original bytes
+followed by a seek table in a skippable frame (plain zstd decoders read it like any other zstd):
++------------------------------+------------------------------+
+| 0x184D2A5E (32 bits, LE)     | Table Size (32 bits, LE)     |
++------------------------------+------------------------------+
++------------------------------+------------------------------+
+| Compressed Size (32 bits, LE)| Original Size (32 bits, LE)  |   one per frame
++------------------------------+------------------------------+
++------------------------------+------------------------------+------------------------------+
+| Frame Count (32 bits, LE)    | Descriptor 0x00 (8 bits)     | 0x8F92EAB1 (32 bits, LE)     |
++------------------------------+------------------------------+------------------------------+
+a range read starts decompressing at the frame holding its offset. lz4 blobs and zstd ones without
+a table (uploaded before it was written) are decompressed from the start
+*/
+
+/// zstd's default, a good trade for logs and json
+const ZSTD_LEVEL: i32 = 3;
+
+/// original bytes per seekable frame, a range read decompresses at most this much it doesn't need
+const SEEK_FRAME: usize = 1024 * 1024;
+
+const SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+const FOOTER_SIZE: u64 = 9;
+
+/// Reads `codec` compressed bytes from `inner` as the original ones,
+/// anything that fails on the way is reported as `InvalidData`
+pub fn decoder<'a>(codec: Compression, inner: impl Read + Send + 'a) -> io::Result<Box<dyn Read + Send + 'a>> {
+    Ok(match codec {
+        Compression::None => Box::new(inner),
+        Compression::Zstd => Box::new(Corrupt(zstd::stream::read::Decoder::new(inner)?)),
+        Compression::Lz4 => Box::new(Corrupt(lz4_flex::frame::FrameDecoder::new(inner))),
+    })
+}
+
+/// Like `decoder` over a whole stored blob, positioned at `offset` of the original bytes
+pub fn decoder_at<'a>(
+    codec: Compression,
+    mut blob: impl Read + Seek + Send + 'a,
+    offset: u64,
+) -> io::Result<Box<dyn Read + Send + 'a>> {
+    let (start, skip) = match codec {
+        Compression::Zstd => match seek_table(&mut blob)? {
+            Some(frames) => find_frame(&frames, offset),
+            None => (0, offset),
+        },
+        _ => (0, offset),
+    };
+    blob.seek(SeekFrom::Start(start))?;
+    let mut reader = decoder(codec, blob)?;
+    io::copy(&mut (&mut reader).take(skip), &mut io::sink())?;
+    Ok(reader)
+}
+
+/// (compressed, original) size of each frame, None if the blob doesn't end in a seek table
+fn seek_table(blob: &mut (impl Read + Seek)) -> io::Result<Option<Vec<(u32, u32)>>> {
+    let length = blob.seek(SeekFrom::End(0))?;
+    if length < 8 + FOOTER_SIZE {
+        return Ok(None);
+    }
+    let mut footer = [0; FOOTER_SIZE as usize];
+    blob.seek(SeekFrom::Start(length - FOOTER_SIZE))?;
+    blob.read_exact(&mut footer)?;
+    let le = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    let count = le(&footer[..4]) as u64;
+    if le(&footer[5..]) != SEEKABLE_MAGIC || footer[4] != 0 || length < 8 + count * 8 + FOOTER_SIZE {
+        return Ok(None);
+    }
+
+    let mut table = vec![0; (8 + count * 8) as usize];
+    blob.seek(SeekFrom::Start(length - FOOTER_SIZE - count * 8 - 8))?;
+    blob.read_exact(&mut table)?;
+    if le(&table[..4]) != SKIPPABLE_MAGIC {
+        return Ok(None);
+    }
+    Ok(Some(table[8..].chunks(8).map(|entry| (le(&entry[..4]), le(&entry[4..]))).collect()))
+}
+
+/// where in the blob the frame holding `offset` starts, and how far into it `offset` is
+fn find_frame(frames: &[(u32, u32)], offset: u64) -> (u64, u64) {
+    let (mut start, mut skip) = (0, offset);
+    for &(compressed, original) in frames {
+        if skip < original as u64 {
+            break;
+        }
+        start += compressed as u64;
+        skip -= original as u64;
+    }
+    (start, skip)
+}
+
+/// the decoders have their own idea of error kinds, a bad frame is bad data whatever they say
+struct Corrupt<R>(R);
+
+impl<R: Read> Read for Corrupt<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => e,
+            _ => io::Error::new(io::ErrorKind::InvalidData, format!("corrupt compressed data: {}", e)),
+        })
+    }
+}
+
+/// Compresses what is written to it into `inner`, `finish` ends the frame
+pub enum Encoder<W: Write> {
+    None(W),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+    Seekable(Seekable<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// for the wire, one stream
+    pub fn new(codec: Compression, inner: W) -> io::Result<Encoder<W>> {
+        Ok(match codec {
+            Compression::None => Encoder::None(inner),
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(inner, ZSTD_LEVEL)?),
+            Compression::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(inner)),
+        })
+    }
+
+    /// for blobs at rest, zstd goes seekable
+    pub fn stored(codec: Compression, inner: W) -> io::Result<Encoder<W>> {
+        match codec {
+            Compression::Zstd => Ok(Encoder::Seekable(Seekable { inner, buf: Vec::new(), frames: Vec::new() })),
+            _ => Self::new(codec, inner),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(inner) => Ok(inner),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Lz4(encoder) => Ok(encoder.finish()?),
+            Encoder::Seekable(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(inner) => inner.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Lz4(encoder) => encoder.write(buf),
+            Encoder::Seekable(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(inner) => inner.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Lz4(encoder) => encoder.flush(),
+            Encoder::Seekable(encoder) => encoder.inner.flush(),
+        }
+    }
+}
+
+/// zstd in SEEK_FRAME sized frames with a seek table at the end, see SEEKABLE ZSTD
+pub struct Seekable<W: Write> {
+    inner: W,
+    /// original bytes of the frame being filled
+    buf: Vec<u8>,
+    frames: Vec<(u32, u32)>,
+}
+
+impl<W: Write> Seekable<W> {
+    fn end_frame(&mut self) -> io::Result<()> {
+        let compressed = zstd::bulk::compress(&self.buf, ZSTD_LEVEL)?;
+        self.inner.write_all(&compressed)?;
+        self.frames.push((compressed.len() as u32, self.buf.len() as u32));
+        self.buf.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            self.end_frame()?;
+        }
+        let mut table = Vec::with_capacity(8 + self.frames.len() * 8 + FOOTER_SIZE as usize);
+        table.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+        table.extend_from_slice(&((self.frames.len() * 8) as u32 + FOOTER_SIZE as u32).to_le_bytes());
+        for (compressed, original) in &self.frames {
+            table.extend_from_slice(&compressed.to_le_bytes());
+            table.extend_from_slice(&original.to_le_bytes());
+        }
+        table.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        table.push(0);
+        table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        self.inner.write_all(&table)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Seekable<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(SEEK_FRAME - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == SEEK_FRAME {
+            self.end_frame()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = b"{\"level\":\"info\",\"msg\":\"hello\"}\n".repeat(1000);
+        for codec in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            let mut encoder = Encoder::new(codec, Vec::new()).unwrap();
+            encoder.write_all(&data).unwrap();
+            let compressed = encoder.finish().unwrap();
+            if codec != Compression::None {
+                assert!(compressed.len() < data.len() / 10, "{:?} {}", codec, compressed.len());
+            }
+
+            let mut decoded = Vec::new();
+            decoder(codec, &compressed[..]).unwrap().read_to_end(&mut decoded).unwrap();
+            assert!(decoded == data);
+        }
+        // not a zstd frame
+        assert!(decoder(Compression::Zstd, &b"plain text"[..]).unwrap().read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_seekable() {
+        let data: Vec<u8> = (0..SEEK_FRAME * 2 + 1000).map(|i| (i % 251) as u8).collect();
+        let mut encoder = Encoder::stored(Compression::Zstd, Vec::new()).unwrap();
+        encoder.write_all(&data).unwrap();
+        let blob = encoder.finish().unwrap();
+
+        // a plain zstd decoder skips the seek table
+        let mut decoded = Vec::new();
+        decoder(Compression::Zstd, &blob[..]).unwrap().read_to_end(&mut decoded).unwrap();
+        assert!(decoded == data);
+
+        let frames = seek_table(&mut io::Cursor::new(&blob)).unwrap().unwrap();
+        assert_eq!(frames.iter().map(|f| f.1).collect::<Vec<_>>(), [SEEK_FRAME as u32, SEEK_FRAME as u32, 1000]);
+        assert_eq!(find_frame(&frames, SEEK_FRAME as u64 + 5), (frames[0].0 as u64, 5));
+        for offset in [0, 17, SEEK_FRAME, SEEK_FRAME * 2 + 999] {
+            let mut read = vec![0; data.len() - offset];
+            decoder_at(Compression::Zstd, io::Cursor::new(&blob), offset as u64).unwrap().read_exact(&mut read).unwrap();
+            assert!(read == data[offset..], "{}", offset);
+        }
+
+        // a zstd stream without a table still reads, from the start
+        let mut encoder = Encoder::new(Compression::Zstd, Vec::new()).unwrap();
+        encoder.write_all(&data).unwrap();
+        let stream = encoder.finish().unwrap();
+        assert!(seek_table(&mut io::Cursor::new(&stream)).unwrap().is_none());
+        let mut read = Vec::new();
+        decoder_at(Compression::Zstd, io::Cursor::new(&stream), 100).unwrap().read_to_end(&mut read).unwrap();
+        assert!(read == data[100..]);
+    }
+}
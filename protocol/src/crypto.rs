@@ -0,0 +1,288 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+/*
+ENCRYPTED BLOB LAYOUT:
+every object gets its own random data key, wrapped by the master key and kept in the metadata store.
+the object's path is sealed into the wrapped key as aad, it only opens for the row it was made for
+so keys (and their blobs) can't be swapped between objects.
+the plaintext is cut into CHUNK_SIZE chunks that are sealed independently so ranges can be
+read without decrypting the whole file:
++---------------------------------------------+-----------------+------ ... ------+
+| chunk 0 ciphertext (CHUNK_SIZE) | tag (128) | chunk 1 ...     |  last chunk      |
++---------------------------------------------+-----------------+------ ... ------+
+nonce = chunk index (64 bits, big endian) + 4 zero bytes, aad = 1 for the last chunk else 0
+so chunks can't be reordered and a truncated file fails to decrypt
+*/
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+fn chunk_nonce(index: u64) -> Nonce {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    Nonce::from(nonce)
+}
+
+fn crypto_error<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// The key an object's blob is encrypted with
+pub type DataKey = [u8; KEY_SIZE];
+
+pub fn new_data_key() -> DataKey {
+    rand::random()
+}
+
+/// Key encryption key loaded from a local keyfile, either 32 raw bytes or 64 hex characters
+pub struct MasterKey(ChaCha20Poly1305);
+
+impl MasterKey {
+    pub fn from_bytes(key: &[u8]) -> Result<MasterKey, Box<dyn Error + Send + Sync>> {
+        if key.len() != KEY_SIZE {
+            Err(format!("master key has to be {} bytes", KEY_SIZE))?;
+        }
+        Ok(MasterKey(ChaCha20Poly1305::new(Key::from_slice(key))))
+    }
+
+    pub fn from_file(path: &Path) -> Result<MasterKey, Box<dyn Error + Send + Sync>> {
+        let raw = fs::read(path)?;
+        if raw.len() == KEY_SIZE {
+            return Self::from_bytes(&raw);
+        }
+        let hex = String::from_utf8(raw)?;
+        let hex = hex.trim();
+        if hex.len() != KEY_SIZE * 2 {
+            Err(format!("{} is neither 32 raw bytes nor 64 hex characters", path.display()))?;
+        }
+        let key = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()?;
+        Self::from_bytes(&key)
+    }
+
+    /// the data key of the object at `path` in its wrapped form (nonce + ciphertext)
+    pub fn wrap_data_key(&self, data_key: &DataKey, path: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let payload = Payload { msg: data_key, aad: path.as_bytes() };
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend(self.0.encrypt(&Nonce::from(nonce), payload).map_err(crypto_error)?);
+        Ok(wrapped)
+    }
+
+    /// fails for a key wrapped for another path
+    pub fn unwrap_data_key(&self, wrapped: &[u8], path: &str) -> Result<DataKey, Box<dyn Error + Send + Sync>> {
+        if wrapped.len() != NONCE_SIZE + KEY_SIZE + TAG_SIZE {
+            Err("wrapped data key has the wrong length")?;
+        }
+        let (nonce, ciphertext) = wrapped.split_at(NONCE_SIZE);
+        let payload = Payload { msg: ciphertext, aad: path.as_bytes() };
+        let key = self.0.decrypt(Nonce::from_slice(nonce), payload).map_err(crypto_error)?;
+        Ok(key.try_into().unwrap())
+    }
+}
+
+/// Seals everything written to it, `finish` has to be called to write the last chunk
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    buf: Vec<u8>,
+    index: u64,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(inner: W, data_key: &[u8; KEY_SIZE]) -> Self {
+        EncryptingWriter {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(data_key)),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            index: 0,
+        }
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let payload = Payload {
+            msg: &self.buf,
+            aad: &[last as u8],
+        };
+        let sealed = self.cipher.encrypt(&chunk_nonce(self.index), payload).map_err(crypto_error)?;
+        self.inner.write_all(&sealed)?;
+        self.buf.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // a full chunk is only sealed once more data shows up, it might be the last one
+        if self.buf.len() == CHUNK_SIZE && !data.is_empty() {
+            self.seal(false)?;
+        }
+        let n = data.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Random access plaintext view of an encrypted blob, `plain_len` comes from the metadata
+pub struct DecryptingReader<R: Read + Seek> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    plain_len: u64,
+    pos: u64,
+    chunk: Option<u64>,
+    buf: Vec<u8>,
+}
+
+impl<R: Read + Seek> DecryptingReader<R> {
+    pub fn new(inner: R, data_key: &[u8; KEY_SIZE], plain_len: u64) -> Self {
+        DecryptingReader {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(data_key)),
+            plain_len,
+            pos: 0,
+            chunk: None,
+            buf: Vec::new(),
+        }
+    }
+
+    fn load_chunk(&mut self, index: u64) -> io::Result<()> {
+        let start = index * CHUNK_SIZE as u64;
+        let plain = (self.plain_len - start).min(CHUNK_SIZE as u64) as usize;
+        let last = index == self.plain_len.saturating_sub(1) / CHUNK_SIZE as u64;
+
+        let mut sealed = vec![0; plain + TAG_SIZE];
+        self.inner.seek(SeekFrom::Start(index * (CHUNK_SIZE + TAG_SIZE) as u64))?;
+        self.inner.read_exact(&mut sealed)?;
+        let payload = Payload {
+            msg: &sealed,
+            aad: &[last as u8],
+        };
+        self.buf = self.cipher.decrypt(&chunk_nonce(index), payload).map_err(crypto_error)?;
+        self.chunk = Some(index);
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for DecryptingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.plain_len || out.is_empty() {
+            return Ok(0);
+        }
+        let index = self.pos / CHUNK_SIZE as u64;
+        if self.chunk != Some(index) {
+            self.load_chunk(index)?;
+        }
+        let offset = (self.pos % CHUNK_SIZE as u64) as usize;
+        let n = out.len().min(self.buf.len() - offset);
+        out[..n].copy_from_slice(&self.buf[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for DecryptingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => p as i128,
+            SeekFrom::End(p) => self.plain_len as i128 + p as i128,
+            SeekFrom::Current(p) => self.pos as i128 + p as i128,
+        };
+        if target < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start"));
+        }
+        self.pos = target as u64;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn seal(data: &[u8], key: &[u8; KEY_SIZE]) -> Vec<u8> {
+        let mut writer = EncryptingWriter::new(Vec::new(), key);
+        io::copy(&mut Cursor::new(data), &mut writer).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_and_ranges() {
+        let master = MasterKey::from_bytes(&[7; KEY_SIZE]).unwrap();
+        let key = new_data_key();
+        let wrapped = master.wrap_data_key(&key, "b/ab/cd/1.data").unwrap();
+        assert_eq!(master.unwrap_data_key(&wrapped, "b/ab/cd/1.data").unwrap(), key);
+
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE - 5] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let sealed = seal(&data, &key);
+            assert_eq!(sealed.len(), len + len.div_ceil(CHUNK_SIZE).max(1) * TAG_SIZE);
+            if len > 0 {
+                assert_ne!(sealed[..len.min(16)], data[..len.min(16)]);
+            }
+
+            let mut reader = DecryptingReader::new(Cursor::new(&sealed), &key, len as u64);
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).unwrap();
+            assert_eq!(out, data);
+
+            // a range straddling a chunk boundary
+            if len >= CHUNK_SIZE + 10 {
+                let start = CHUNK_SIZE as u64 - 10;
+                reader.seek(SeekFrom::Start(start)).unwrap();
+                let mut out = vec![0; 20];
+                reader.read_exact(&mut out).unwrap();
+                assert_eq!(out, data[start as usize..start as usize + 20]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let key = [1; KEY_SIZE];
+        let data = vec![42; CHUNK_SIZE * 2];
+        let mut sealed = seal(&data, &key);
+
+        // dropping the last chunk makes the (now) final chunk fail its aad check
+        let truncated = sealed[..CHUNK_SIZE + TAG_SIZE].to_vec();
+        let mut reader = DecryptingReader::new(Cursor::new(truncated), &key, CHUNK_SIZE as u64);
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+
+        sealed[3] ^= 1;
+        let mut reader = DecryptingReader::new(Cursor::new(sealed), &key, data.len() as u64);
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+
+        let other = MasterKey::from_bytes(&[9; KEY_SIZE]).unwrap();
+        let master = MasterKey::from_bytes(&[7; KEY_SIZE]).unwrap();
+        let wrapped = master.wrap_data_key(&new_data_key(), "b/ab/cd/1.data").unwrap();
+        assert!(other.unwrap_data_key(&wrapped, "b/ab/cd/1.data").is_err());
+        // moved over to another object's row
+        assert!(master.unwrap_data_key(&wrapped, "b/ab/cd/2.data").is_err());
+    }
+}
@@ -1,15 +1,47 @@
 use std::{
-    env,
+    collections::HashSet,
     error::Error,
-    fs,
-    io::{Read, Write},
-    net::{TcpStream},
-    path::PathBuf,
-    time::SystemTime
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, SystemTime},
 };
-use chrono::Datelike;
-use chrono::Timelike;
 use chrono::prelude::{DateTime, Utc};
+use meta_store::{Compression, MetadataStore, NewObject, Placement, Principal, StoredObject, TcpfsError};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio_util::io::SyncIoBridge;
+
+pub mod acl;
+pub mod compression;
+pub mod crypto;
+pub mod limits;
+pub mod multipart;
+pub mod placement;
+pub mod quota;
+pub mod ratelimit;
+pub mod segments;
+pub mod staging;
+pub mod store;
+pub mod stream;
+pub mod timeouts;
+pub mod tls;
+pub mod token;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+pub mod validation;
+pub mod zerocopy;
+use acl::AclMode;
+use crypto::{DecryptingReader, EncryptingWriter, MasterKey};
+use multipart::{Concat, Part, Uploads};
+use quota::{Quota, Reservation, Reserving};
+use ratelimit::{Rate, RateConfig, RateLimits, Scope, Throttle};
+use segments::SegmentStore;
+use store::{BlobStore, StagedBlob};
+use stream::ClientStream;
+use token::Grant;
+use validation::{ValidationError, MAX_FIELD_LENGTH};
+
+/// buffer between the socket and the blocking side of an upload or download
+const PIPE_SIZE: usize = 64 * 1024;
 
 
 /*
@@ -19,44 +51,275 @@ COMMAND TYPES:
 0x03 -> DELETE -> u64 (bytes freed)
 0x04 -> LIST -> ARRAY[BUCKET_ID: UUID]
 0x06 -> DELETE BUCKET -> u64 (bytes freed)
+0x07 -> AUTH | optional preamble, the command that follows runs as the key's principal |
+0x08 -> GRANT (admin) -> status
+0x09 -> REVOKE (admin) -> status
+0x0A -> ADD PRINCIPAL (admin) -> status
+0x0B -> ADD CLIENT CERT (admin) -> status
+0x0C -> PRESIGN -> token
+0x0D -> TOKEN | optional preamble, replaces AUTH for a single UPLOAD or DOWNLOAD |
+0x0E -> DOWNLOAD RANGE -> bytes
+0x0F -> SET BUCKET OPTION (admin) -> status
+0x10 -> STAT -> u64 (logical bytes), u64 (physical bytes), u64, u64 (the same for compressed objects)
+0x11 -> COMPACT SEGMENTS (admin) -> u64 (bytes reclaimed)
+0x12 -> SET RATE LIMIT (admin) -> status
+0x13 -> RATE LIMITS (admin) -> ARRAY[LIMIT]
+0x14 -> INITIATE MULTIPART -> upload id
+0x15 -> UPLOAD PART -> status
+0x16 -> COMPLETE MULTIPART -> u64 (object size)
+0x17 -> ABORT MULTIPART -> status
+0x18 -> UPLOAD CHUNKED | UPLOAD of a body whose length isn't known up front |
+0x19 -> APPEND -> u64 (object size)
+
+COMPRESSED TRANSFERS:
+the high bit on UPLOAD, DOWNLOAD, DOWNLOAD RANGE or UPLOAD CHUNKED (0x81, 0x82, 0x8E, 0x98) says a codec byte follows the
+command: 0x00 none, 0x01 zstd, 0x02 lz4. an upload body (and its length) is then compressed with it,
+the checksum is still over the original bytes. a download body comes back compressed with it, ranges
+are of the original bytes. what is stored is up to the bucket, see `compression`
+
+RESPONSE STATUS:
+every response starts with a status byte, anything other than OK is followed by a message
+and the connection is closed
++----------------------+----------------------------+-----------------------------------+
+|   Status (8 bits)    | Message Length (32 bits)   |    Message (variable length)      |
++----------------------+----------------------------+-----------------------------------+
+errors coming out of a handler are reported with the status of their kind:
+not found -> NOT FOUND, conflict -> CONFLICT, quota -> QUOTA EXCEEDED,
+protocol violation -> BAD REQUEST, database and anything unexpected -> INTERNAL.
+I/O errors on the connection close it without a status
+
+COMPATIBILITY:
+this is not the protocol the first server spoke and there is no version negotiation. UPLOAD now
+has to send the SHA-256 of its data after it and DOWNLOAD answers with a status byte before
+the data, clients of the original protocol have to be updated (the python client is)
+
+BUSY RESPONSE:
+sent instead of running the request when the server is saturated, the connection is closed after it
++----------------------+----------------------------+
+|     0x07 (8 bits)    | Retry After ms (32 bits)   |
++----------------------+----------------------------+
 */
 
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0x00,
+    Denied = 0x01,
+    NotFound = 0x02,
+    BadRequest = 0x03,
+    Internal = 0x04,
+    Conflict = 0x05,
+    QuotaExceeded = 0x06,
+    Busy = 0x07,
+}
+
+impl Status {
+    /// The status a failed request is answered with, None when the connection itself failed
+    /// and the response might be half written already
+    pub fn of(error: &(dyn Error + 'static)) -> Option<Status> {
+        if let Some(error) = error.downcast_ref::<TcpfsError>() {
+            return match error {
+                TcpfsError::NotFound(_) => Some(Status::NotFound),
+                TcpfsError::Conflict(_) => Some(Status::Conflict),
+                TcpfsError::Quota(_) => Some(Status::QuotaExceeded),
+                TcpfsError::Protocol(_) => Some(Status::BadRequest),
+                TcpfsError::Db(_) => Some(Status::Internal),
+                TcpfsError::Io(_) => None,
+            };
+        }
+        match error.downcast_ref::<std::io::Error>() {
+            Some(e) if matches!(e.kind(), ErrorKind::StorageFull | ErrorKind::QuotaExceeded) => {
+                Some(Status::QuotaExceeded)
+            }
+            Some(_) => None,
+            None => Some(Status::Internal),
+        }
+    }
+}
+
+/// Who is making the request, resolved from the preamble before the command runs
+#[derive(Default)]
+pub struct Session {
+    pub principal: Option<Principal>,
+    /// set when the request came in with a presigned token instead of a principal
+    pub grant: Option<Grant>,
+}
+
+impl Session {
+    fn is_admin(&self) -> bool {
+        self.principal.as_ref().map(|p| p.is_admin).unwrap_or(false)
+    }
+
+    fn name(&self) -> &str {
+        match (&self.principal, &self.grant) {
+            (_, Some(_)) => "token",
+            (Some(p), None) => &p.name,
+            (None, None) => "anonymous",
+        }
+    }
+}
+
+/// Everything the server decides at startup
+pub struct HandlerConfig {
+    /// sqlite or redb, picked at startup
+    pub metadata: Arc<dyn MetadataStore>,
+    /// where blobs go, picked at startup
+    pub store: Arc<dyn BlobStore>,
+    pub acl_mode: AclMode,
+    pub token_secret: Vec<u8>,
+    /// wraps the data keys of buckets with encryption turned on
+    pub master_key: Option<MasterKey>,
+    /// store identical uploads once, see `placement`
+    pub dedup: bool,
+    /// objects smaller than this are packed into segments, 0 turns packing off
+    pub pack_threshold: u32,
+    /// starting limits, admins can change them while the server runs, see `ratelimit`
+    pub rates: RateConfig,
+    /// logical bytes a bucket may hold, 0 is unlimited
+    pub bucket_quota: u64,
+}
 
-pub struct RequestHandler;
+pub struct RequestHandler {
+    metadata: Arc<dyn MetadataStore>,
+    store: Arc<dyn BlobStore>,
+    acl_mode: AclMode,
+    token_secret: Vec<u8>,
+    master_key: Option<MasterKey>,
+    dedup: bool,
+    pack_threshold: u32,
+    segments: SegmentStore,
+    rates: RateLimits,
+    uploads: Uploads,
+    quota: Arc<Quota>,
+    /// an object is held from checking its length until its append is committed
+    appends: ObjectLocks,
+}
 
 impl RequestHandler {
-    pub fn handle_client(
-        mut stream: TcpStream,
-        db_path: Option<&String>,
-        working_dir: &PathBuf,
-    ) -> Result<(), Box<dyn Error>> {
+    pub fn new(config: HandlerConfig) -> Self {
+        RequestHandler {
+            segments: SegmentStore::new(config.store.clone(), config.metadata.clone()),
+            quota: Quota::new(config.bucket_quota, config.metadata.clone()),
+            metadata: config.metadata,
+            store: config.store,
+            acl_mode: config.acl_mode,
+            token_secret: config.token_secret,
+            master_key: config.master_key,
+            dedup: config.dedup,
+            pack_threshold: config.pack_threshold,
+            rates: RateLimits::new(config.rates),
+            uploads: Uploads::default(),
+            appends: ObjectLocks::default(),
+        }
+    }
+
+    /// Metadata, blob and crypto calls are synchronous, they run on the blocking pool
+    /// so a slow disk or a busy database doesn't stall the other connections
+    async fn blocking<T, E>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&RequestHandler) -> Result<T, E> + Send + 'static,
+    ) -> Result<T, Box<dyn Error + Send + Sync>>
+    where
+        T: Send + 'static,
+        E: Into<Box<dyn Error + Send + Sync>> + Send + 'static,
+    {
+        let handler = self.clone();
+        tokio::task::spawn_blocking(move || f(&handler)).await?.map_err(Into::into)
+    }
+
+    pub async fn handle_client(self: &Arc<Self>, mut stream: ClientStream) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let result = self.serve(&mut stream).await;
+        if let Err(e) = &result {
+            if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == ErrorKind::TimedOut) {
+                println!("closing slow connection from {:?}: {}", stream.peer_addr(), e);
+                return Ok(());
+            }
+        }
+        // tell the client what went wrong while we still can, the error goes to the log either way
+        if let Err(e) = &result {
+            if let Some(status) = Status::of(e.as_ref()) {
+                let _ = Self::respond_error(&mut stream, status, &e.to_string()).await;
+            }
+        }
+        // for tls this sends close_notify, so the client sees a clean close instead of a truncated stream
+        let _ = stream.shutdown().await;
+        result
+    }
+
+    /// Answer a connection that didn't get a slot, see `limits`
+    pub async fn turn_away(mut stream: ClientStream, retry_after: Duration) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let retry_after_ms = retry_after.as_millis().min(u32::MAX as u128) as u32;
+        stream.write_all(&[Status::Busy as u8]).await?;
+        stream.write_all(&retry_after_ms.to_be_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    async fn serve(self: &Arc<Self>, stream: &mut ClientStream) -> Result<(), Box<dyn Error + Send + Sync>> {
         // Buffer to hold the command type
         let mut command_type = [0; 1];
 
         // Read the first byte to determine the command type
-        stream.read_exact(&mut command_type)?;
+        stream.read_exact(&mut command_type).await?;
+        stream.header();
+
+        // a verified client certificate maps to a principal, an AUTH preamble takes precedence
+        let mut session = Session::default();
+        if let Some(fingerprint) = stream.peer_cert_fingerprint() {
+            session.principal = self.blocking(move |h| h.metadata.get_principal_by_cert(&fingerprint)).await?;
+        }
+        if command_type[0] == 0x07 {
+            println!("AUTH command received");
+            match self.handle_auth(stream).await? {
+                Some(p) => session.principal = Some(p),
+                None => return Self::respond_error(stream, Status::Denied, "invalid api key").await,
+            }
+            stream.idle();
+            stream.read_exact(&mut command_type).await?;
+            stream.header();
+        } else if command_type[0] == 0x0D {
+            println!("TOKEN command received");
+            match self.handle_token(stream).await {
+                Ok(grant) => session = Session { principal: None, grant: Some(grant) },
+                Err(e) => return Self::respond_error(stream, Status::Denied, &e.to_string()).await,
+            }
+            stream.idle();
+            stream.read_exact(&mut command_type).await?;
+            stream.header();
+        }
+
+        let mut codec = Compression::None;
+        if command_type[0] & 0x80 != 0 && [0x01, 0x02, 0x0E, 0x18].contains(&(command_type[0] & 0x7F)) {
+            let mut codec_buf = [0; 1];
+            stream.read_exact(&mut codec_buf).await?;
+            codec = Compression::from_u8(codec_buf[0])
+                .ok_or_else(|| TcpfsError::Protocol(format!("unknown compression {:#04x}", codec_buf[0])))?;
+            command_type[0] &= 0x7F;
+        }
+        if session.grant.is_some() && ![0x01, 0x02, 0x0E, 0x18].contains(&command_type[0]) {
+            return Self::respond_error(stream, Status::Denied, "tokens are only valid for upload and download").await;
+        }
 
         // Match the command type and handle accordingly
         match command_type[0] {
             0x01 => {
                 println!("UPLOAD command received");
                 // Call your upload handling function here
-                Self::handle_upload(stream, db_path, working_dir)?;
+                self.handle_upload(stream, &session, false, codec).await?;
             }
             0x02 => {
                 println!("DOWNLOAD command received");
                 // Call your download handling function here
-                Self::handle_download(stream, db_path, working_dir)?;
+                self.handle_download(stream, &session, false, codec).await?;
             }
             0x03 => {
                 println!("DELETE command received");
-                // Call your delete handling function here
-                //handle_delete(&mut stream).await?;
+                self.handle_delete(stream, &session).await?;
             }
             0x04 => {
                 println!("LIST command received");
                 // Call your list handling function here
-                Self::handle_list(stream, db_path).unwrap_err();
+                self.handle_list(stream, &session).await?;
             }
 
             0x05 => {
@@ -64,207 +327,2259 @@ impl RequestHandler {
                 // Call your list handling function here
                 //handle_bucket_delete(&mut stream).await?;
             }
-            _ => {
+            0x08 | 0x09 => {
+                println!("GRANT/REVOKE command received");
+                self.handle_acl_change(stream, &session, command_type[0] == 0x08).await?;
+            }
+            0x0A => {
+                println!("ADD PRINCIPAL command received");
+                self.handle_add_principal(stream, &session).await?;
+            }
+            0x0B => {
+                println!("ADD CLIENT CERT command received");
+                self.handle_add_client_cert(stream, &session).await?;
+            }
+            0x0C => {
+                println!("PRESIGN command received");
+                self.handle_presign(stream, &session).await?;
+            }
+            0x0E => {
+                println!("DOWNLOAD RANGE command received");
+                self.handle_download(stream, &session, true, codec).await?;
+            }
+            0x0F => {
+                println!("SET BUCKET OPTION command received");
+                self.handle_bucket_option(stream, &session).await?;
+            }
+            0x10 => {
+                println!("STAT command received");
+                self.handle_stat(stream, &session).await?;
+            }
+            0x11 => {
+                println!("COMPACT SEGMENTS command received");
+                self.handle_compact(stream, &session).await?;
+            }
+            0x12 => {
+                println!("SET RATE LIMIT command received");
+                self.handle_set_rate_limit(stream, &session).await?;
+            }
+            0x13 => {
+                println!("RATE LIMITS command received");
+                self.handle_rate_limits(stream, &session).await?;
+            }
+            0x14 => {
+                println!("INITIATE MULTIPART command received");
+                self.handle_multipart_start(stream, &session).await?;
+            }
+            0x15 => {
+                println!("UPLOAD PART command received");
+                self.handle_upload_part(stream, &session).await?;
+            }
+            0x16 => {
+                println!("COMPLETE MULTIPART command received");
+                self.handle_multipart_complete(stream, &session).await?;
+            }
+            0x17 => {
+                println!("ABORT MULTIPART command received");
+                self.handle_multipart_abort(stream, &session).await?;
+            }
+            0x18 => {
+                println!("UPLOAD CHUNKED command received");
+                self.handle_upload(stream, &session, true, codec).await?;
+            }
+            0x19 => {
+                println!("APPEND command received");
+                self.handle_append(stream, &session).await?;
+            }
+            other => {
                 println!("Unknown command received");
-                // Handle unknown commands here, possibly returning an error or ignoring
+                Err(TcpfsError::Protocol(format!("unknown command {:#04x}", other)))?;
             }
         }
         Ok(())
     }
 
 
-    fn iso8601_now() -> (String, (i32, u32, u32, u32, u32, u32, u32)) 
+    async fn respond_error(stream: &mut ClientStream, status: Status, message: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        stream.write_all(&[status as u8]).await?;
+        stream.write_all(&(message.len() as u32).to_be_bytes()).await?;
+        stream.write_all(message.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn respond_bad_request(stream: &mut ClientStream, error: ValidationError) -> Result<(), Box<dyn Error + Send + Sync>> {
+        println!("rejected request: {}", error);
+        Self::respond_error(stream, Status::BadRequest, &error.to_string()).await
+    }
+
+    /// writes the denial for us, callers should just return when this is false.
+    /// `key` is only needed by commands a presigned token can stand in for
+    async fn authorize(
+        self: &Arc<Self>,
+        stream: &mut ClientStream,
+        session: &Session,
+        bucket_id: &str,
+        key: Option<&str>,
+        permission: u8,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let allowed = match &session.grant {
+            Some(grant) => {
+                grant.operation == permission
+                    && grant.bucket_id.to_string() == bucket_id
+                    && Some(grant.key.as_str()) == key
+                    && grant.expires_at > token::now_secs()
+            }
+            None => {
+                let (principal, bucket_id) = (session.principal.clone(), bucket_id.to_string());
+                self.blocking(move |h| {
+                    acl::is_allowed(h.metadata.as_ref(), h.acl_mode, principal.as_ref(), &bucket_id, permission)
+                })
+                .await?
+            }
+        };
+        if allowed {
+            return Ok(true);
+        }
+        println!("denied {} on bucket {}", session.name(), bucket_id);
+        Self::respond_error(stream, Status::Denied, "permission denied").await?;
+        Ok(false)
+    }
+
+
+    /// waits out the request rate limits, the throttle holds the transfer to the byte limits
+    async fn throttle(&self, session: &Session, bucket_id: &str) -> Throttle {
+        let key = session.principal.as_ref().map(|p| p.name.as_str());
+        self.rates.admit(key, bucket_id).await
+    }
+
+    fn iso8601_now() -> String
     {
         let dt: DateTime<Utc> = SystemTime::now().into();
 
         // ISO8601 formatted timestamp
-        let iso8601 = format!("{}", dt.format("%+"));
+        format!("{}", dt.format("%+"))
+    }
+
+/*
+AUTH REQUEST:
++----------------------+----------------------------+-----------------------------------+
+|          0x07        | Api Key Length (32 bits)   |      Api Key (variable length)    |
++----------------------+----------------------------+-----------------------------------+
+no response on success, the next command is read straight after
+*/
+    async fn handle_auth(self: &Arc<Self>, stream: &mut ClientStream) -> Result<Option<Principal>, Box<dyn Error + Send + Sync>> {
+        let mut key_length_buf = [0; 4];
+        stream.read_exact(&mut key_length_buf).await?;
+        let key_length = u32::from_be_bytes(key_length_buf);
 
-        // Deconstructed parts: year, month, day, hour, minute, second
-        let deconstructed = (
-            dt.year(),       // Year (i32)
-            dt.month(),      // Month (u32)
-            dt.day(),        // Day (u32)
-            dt.hour(),       // Hour (u32)
-            dt.minute(),     // Minute (u32)
-            dt.second(),     // Second (u32)
-            dt.nanosecond()  // 
-        );
+        let api_key = match validation::read_field(stream, key_length, MAX_FIELD_LENGTH).await? {
+            Ok(api_key) => api_key,
+            Err(_) => return Ok(None),
+        };
 
-        (iso8601, deconstructed)
+        self.blocking(move |h| acl::authenticate(h.metadata.as_ref(), &api_key)).await
     }
 
 /*
-LIST REQUEST:
-header:
-+----------------------+--------------------+----------------------+
-|          0x02        | Path Length (32 bits)| bucket_id (128 bits)|
-+----------------------+--------------------+----------------------+
+TOKEN REQUEST:
++----------------------+----------------------------+-----------------------------------+
+|          0x0D        | Token Length (32 bits)     |      Token (variable length)      |
++----------------------+----------------------------+-----------------------------------+
+followed by the UPLOAD or DOWNLOAD the token was minted for, no response on success
+*/
+    async fn handle_token(self: &Arc<Self>, stream: &mut ClientStream) -> Result<Grant, Box<dyn Error + Send + Sync>> {
+        let mut token_length_buf = [0; 4];
+        stream.read_exact(&mut token_length_buf).await?;
+        let token_length = u32::from_be_bytes(token_length_buf);
+
+        let token = validation::read_field(stream, token_length, MAX_FIELD_LENGTH).await??;
+        Ok(token::verify(&self.token_secret, &token, token::now_secs())?)
+    }
+
+/*
+PRESIGN REQUEST, the caller has to hold the permission it is handing out:
++----------------------+----------------------+----------------------+----------------------+----------------------+
+|          0x0C        | Operation (8 bits)   | TTL secs (32 bits)   | Key Length (32 bits) | bucket_id (128 bits) |
++----------------------+----------------------+----------------------+----------------------+----------------------+
 +-----------------------------------------------------------------------------------------+
-|        Relative Path (variable length)                                                  |
+|                              Key (variable length)                                      |
 +-----------------------------------------------------------------------------------------+
-LIST RESPONSE (REPEATING):
-
-+------------------------------+--------------------------+--------------------+------------------------------------------+
-|    1 byte (0==file, 1==dir)    | Path Length (32 bits) |  bucket_id (128 bits)     |    File/Dir size length ( 32 bits ) |
-+------------------------------+--------------------------+----------------------+-----------------------------------------+
-+----------------------+--------------------+----------------------+----------------------+
-|                                Path
-+----------------------+--------------------+----------------------+----------------------+
-\r\n
+operation: 0x01 download, 0x02 upload
+PRESIGN RESPONSE:
++----------------------+----------------------------+-----------------------------------+
+|   Status (8 bits)    | Token Length (32 bits)     |      Token (variable length)      |
++----------------------+----------------------------+-----------------------------------+
 */
-    fn handle_list(mut stream: TcpStream, db_path: Option<&String>) -> Result<(), Box<dyn Error>> 
-    {
-        let mut key_length_buf: [u8; 4] = [0; 4];
-        stream.read_exact(&mut key_length_buf)?;
+    async fn handle_presign(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut operation = [0; 1];
+        stream.read_exact(&mut operation).await?;
+
+        let mut ttl_buf = [0; 4];
+        stream.read_exact(&mut ttl_buf).await?;
+        let ttl = u32::from_be_bytes(ttl_buf) as u64;
+
+        let mut key_length_buf = [0; 4];
+        stream.read_exact(&mut key_length_buf).await?;
         let key_length = u32::from_be_bytes(key_length_buf);
 
         let mut bucket_id_buf = [0; 16];
-        stream.read_exact(&mut bucket_id_buf)?;
-        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf)).to_string();
+        stream.read_exact(&mut bucket_id_buf).await?;
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf));
 
-        let mut key_buf = vec![0; key_length as usize];
-        stream.read_exact(&mut key_buf)?;
-        let key = String::from_utf8(key_buf).expect("Invalid UTF-8 in path");
+        let key = match validation::read_key(stream, key_length).await? {
+            Ok(key) => key,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
 
-        let con = meta_sqlite::get_connection(db_path.cloned()).unwrap();
-        for obj in meta_sqlite::get_objects_in_path(&con, &bucket_id, &key)
-            .unwrap()
-            .into_iter()
-        {
-            stream.write_all(&obj.serialize().clone()).unwrap();
+        if operation[0] != acl::READ && operation[0] != acl::WRITE {
+            return Self::respond_error(stream, Status::Denied, "only download and upload can be presigned").await;
+        }
+        if !self.authorize(stream, session, &bucket_id.to_string(), None, operation[0]).await? {
+            return Ok(());
         }
 
+        let grant = Grant {
+            operation: operation[0],
+            expires_at: token::now_secs() + ttl.min(token::MAX_TTL_SECS),
+            bucket_id,
+            key,
+        };
+        let token = token::mint(&self.token_secret, &grant);
+        stream.write_all(&[Status::Ok as u8]).await?;
+        stream.write_all(&(token.len() as u32).to_be_bytes()).await?;
+        stream.write_all(token.as_bytes()).await?;
         Ok(())
     }
 
-
 /*
-DOWNLOAD REQUEST:
-header:
-+----------------------+----------------------+----------------------+
-|          0x02        | Key Length (32 bits) | bucket_id (128 bits) |
-+----------------------+----------------------+----------------------+
+GRANT / REVOKE REQUEST (admin only):
++----------------------+----------------------+-------------------------+----------------------------+
+|     0x08 | 0x09      | bucket_id (128 bits) | Permissions (8 bits)    | Principal Length (32 bits) |
++----------------------+----------------------+-------------------------+----------------------------+
 +-----------------------------------------------------------------------------------------+
-|                              Key (variable length)                                      |
+|                              Principal (variable length)                                |
 +-----------------------------------------------------------------------------------------+
-DOWNLOAD RESPONSE:
+permissions: 0x01 read, 0x02 write, 0x04 delete, 0x08 list
+RESPONSE: status
+in open mode the first grant on a bucket takes it out of the open, from then on only admins and
+the principals granted something get in, anonymous clients included
+*/
+    async fn handle_acl_change(
+        self: &Arc<Self>,
+        stream: &mut ClientStream,
+        session: &Session,
+        grant: bool,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut bucket_id_buf = [0; 16];
+        stream.read_exact(&mut bucket_id_buf).await?;
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf)).to_string();
+
+        let mut permissions = [0; 1];
+        stream.read_exact(&mut permissions).await?;
+
+        let mut name_length_buf = [0; 4];
+        stream.read_exact(&mut name_length_buf).await?;
+        let name = match validation::read_name(stream, u32::from_be_bytes(name_length_buf)).await? {
+            Ok(name) => name,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
+
+        if !session.is_admin() {
+            return Self::respond_error(stream, Status::Denied, "admin only").await;
+        }
+
+        let known = self
+            .blocking(move |h| match grant {
+                true => h.metadata.grant_permissions(&bucket_id, &name, permissions[0]),
+                false => h.metadata.revoke_permissions(&bucket_id, &name, permissions[0]).map(|_| true),
+            })
+            .await?;
+        // the principal has to exist before it can be granted anything
+        if !known {
+            return Self::respond_error(stream, Status::NotFound, "unknown principal").await;
+        }
+        stream.write_all(&[Status::Ok as u8]).await?;
+        Ok(())
+    }
+
+/*
+ADD PRINCIPAL REQUEST (admin only), replaces the key if the principal exists:
++----------------------+----------------------+----------------------------+----------------------------+
+|          0x0A        | Is Admin (8 bits)    | Principal Length (32 bits) | Api Key Length (32 bits)   |
++----------------------+----------------------+----------------------------+----------------------------+
 +-----------------------------------------------------------------------------------------+
-|                              File Data (variable length)                                |
+|                 Principal (variable length) | Api Key (variable length)                 |
 +-----------------------------------------------------------------------------------------+
+RESPONSE: status
 */
-    fn handle_download(
-        mut stream: TcpStream,
-        db_path: Option<&String>,
-        working_dir: &PathBuf,
-    ) -> Result<(), Box<dyn Error>> 
-    {
+    async fn handle_add_principal(
+        self: &Arc<Self>,
+        stream: &mut ClientStream,
+        session: &Session,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut is_admin = [0; 1];
+        stream.read_exact(&mut is_admin).await?;
+
+        let mut name_length_buf = [0; 4];
+        stream.read_exact(&mut name_length_buf).await?;
         let mut key_length_buf = [0; 4];
-        stream.read_exact(&mut key_length_buf)?;
-        let path_length = u32::from_be_bytes(key_length_buf);
+        stream.read_exact(&mut key_length_buf).await?;
 
-        // FIXME: Lots of allocs happening here, probably could be done better
-        let mut bucket_id_buf = [0; 16];
-        stream.read_exact(&mut bucket_id_buf)?;
-        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf)).to_string();
+        let name = match validation::read_name(stream, u32::from_be_bytes(name_length_buf)).await? {
+            Ok(name) => name,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
+        let api_key = match validation::read_field(stream, u32::from_be_bytes(key_length_buf), MAX_FIELD_LENGTH).await? {
+            Ok(api_key) => api_key,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
 
-        let mut con = meta_sqlite::get_connection(db_path.cloned()).unwrap();
-        let trans = meta_sqlite::start_transaction(&mut con); 
+        if !session.is_admin() {
+            return Self::respond_error(stream, Status::Denied, "admin only").await;
+        }
 
-        let mut key_buf = vec![0; path_length as usize];
-        stream.read_exact(&mut key_buf)?;
-        let key = String::from_utf8(key_buf).expect("Invalid UTF-8 in path");
+        self.blocking(move |h| h.metadata.upsert_principal(&name, &acl::hash_api_key(&api_key), is_admin[0] != 0))
+            .await?;
+        stream.write_all(&[Status::Ok as u8]).await?;
+        Ok(())
+    }
 
-        let path: String = meta_sqlite::get_metadata_by_key(&trans,
-                                                            bucket_id.as_str(),
-                                                            &key).unwrap();
+/*
+ADD CLIENT CERT REQUEST (admin only), maps a tls client certificate to an existing principal:
++----------------------+----------------------------+----------------------------+
+|          0x0B        | Principal Length (32 bits) | Cert Length (32 bits)      |
++----------------------+----------------------------+----------------------------+
++-----------------------------------------------------------------------------------------+
+|             Principal (variable length) | DER encoded certificate (variable length)     |
++-----------------------------------------------------------------------------------------+
+RESPONSE: status
+*/
+    async fn handle_add_client_cert(
+        self: &Arc<Self>,
+        stream: &mut ClientStream,
+        session: &Session,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut name_length_buf = [0; 4];
+        stream.read_exact(&mut name_length_buf).await?;
+        let mut cert_length_buf = [0; 4];
+        stream.read_exact(&mut cert_length_buf).await?;
 
-        let pb = PathBuf::new();
-        let target = pb
-            .join(path);
-        match target.is_file() {
-            true => std::io::copy(&mut fs::File::open(&target).unwrap(), &mut stream)?,
-            false => Err("Invalid key path")?,
+        let name = match validation::read_name(stream, u32::from_be_bytes(name_length_buf)).await? {
+            Ok(name) => name,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
+        let cert = match validation::read_field(stream, u32::from_be_bytes(cert_length_buf), MAX_FIELD_LENGTH).await? {
+            Ok(cert) => cert,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
         };
+
+        if !session.is_admin() {
+            return Self::respond_error(stream, Status::Denied, "admin only").await;
+        }
+
+        if !self.blocking(move |h| h.metadata.add_principal_cert(&acl::hash_api_key(&cert), &name)).await? {
+            return Self::respond_error(stream, Status::NotFound, "unknown principal").await;
+        }
+        stream.write_all(&[Status::Ok as u8]).await?;
         Ok(())
     }
 
 /*
-UPLOAD REQUEST:
-header:
+SET BUCKET OPTION REQUEST (admin only):
 +----------------------+----------------------+----------------------+----------------------+
-| Command Type (8 bits)| Key Length (32 bits) | File Length (32 bits)| bucket_id (128 bits) |
+|          0x0F        | bucket_id (128 bits) | Option (8 bits)      | Value (8 bits)       |
 +----------------------+----------------------+----------------------+----------------------+
-data:
+options: 0x01 encryption at rest (0 off, 1 on)
+         0x02 compression at rest (0 none, 1 zstd, 2 lz4), ignored while encryption is on
+both only affect objects uploaded afterwards
+RESPONSE: status
+*/
+    async fn handle_bucket_option(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut bucket_id_buf = [0; 16];
+        stream.read_exact(&mut bucket_id_buf).await?;
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf)).to_string();
+
+        let mut option = [0; 2];
+        stream.read_exact(&mut option).await?;
+
+        if !session.is_admin() {
+            return Self::respond_error(stream, Status::Denied, "admin only").await;
+        }
+
+        match option {
+            [0x01, value] => {
+                if value != 0 && self.master_key.is_none() {
+                    return Self::respond_error(stream, Status::BadRequest, "server has no master key configured").await;
+                }
+                self.blocking(move |h| h.metadata.set_bucket_encrypted(&bucket_id, value != 0)).await?;
+            }
+            [0x02, value] => {
+                let compression = match Compression::from_u8(value) {
+                    Some(compression) => compression,
+                    None => return Self::respond_error(stream, Status::BadRequest, "unknown compression").await,
+                };
+                self.blocking(move |h| h.metadata.set_bucket_compression(&bucket_id, compression)).await?;
+            }
+            _ => return Self::respond_error(stream, Status::BadRequest, "unknown bucket option").await,
+        }
+        stream.write_all(&[Status::Ok as u8]).await?;
+        Ok(())
+    }
+
+/*
+STAT REQUEST:
++----------------------+----------------------+
+|          0x10        | bucket_id (128 bits) |
++----------------------+----------------------+
+STAT RESPONSE:
++----------------------+----------------------------+----------------------------+
+|   Status (8 bits)    | Logical Bytes (64 bits)    | Physical Bytes (64 bits)   |
++----------------------+----------------------------+----------------------------+
++-------------------------------------+-------------------------------------+
+| Compressed Logical Bytes (64 bits)  | Compressed Physical Bytes (64 bits) |
++-------------------------------------+-------------------------------------+
+logical is what was uploaded, physical what it takes on disk after deduplication and
+compression. the compressed pair covers the objects stored compressed, logical / physical
+is their compression ratio. the nil bucket id asks for the whole store and is admin only
+*/
+    async fn handle_stat(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut bucket_id_buf = [0; 16];
+        stream.read_exact(&mut bucket_id_buf).await?;
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf));
+
+        let usage = match bucket_id.is_nil() {
+            true if !session.is_admin() => return Self::respond_error(stream, Status::Denied, "admin only").await,
+            true => self.blocking(|h| h.metadata.get_usage(None)).await?,
+            false => {
+                let bucket_id = bucket_id.to_string();
+                if !self.authorize(stream, session, &bucket_id, None, acl::LIST).await? {
+                    return Ok(());
+                }
+                self.blocking(move |h| h.metadata.get_usage(Some(&bucket_id))).await?
+            }
+        };
+
+        stream.write_all(&[Status::Ok as u8]).await?;
+        stream.write_all(&usage.logical.to_be_bytes()).await?;
+        stream.write_all(&usage.physical.to_be_bytes()).await?;
+        stream.write_all(&usage.compressed_logical.to_be_bytes()).await?;
+        stream.write_all(&usage.compressed_physical.to_be_bytes()).await?;
+        Ok(())
+    }
+
+/*
+COMPACT SEGMENTS REQUEST (admin only):
++----------------------+
+|          0x11        |
++----------------------+
+COMPACT SEGMENTS RESPONSE:
++----------------------+----------------------------+
+|   Status (8 bits)    | Bytes Reclaimed (64 bits)  |
++----------------------+----------------------------+
+the reclaimed files are only deleted by a later compaction, see `segments`
+*/
+    async fn handle_compact(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !session.is_admin() {
+            return Self::respond_error(stream, Status::Denied, "admin only").await;
+        }
+        let reclaimed = self.blocking(|h| h.segments.compact(segments::COMPACT_LIVE_RATIO)).await?;
+        println!("Compaction reclaimed {} bytes", reclaimed);
+
+        stream.write_all(&[Status::Ok as u8]).await?;
+        stream.write_all(&reclaimed.to_be_bytes()).await?;
+        Ok(())
+    }
+
+/*
+SET RATE LIMIT REQUEST (admin only):
++----------------------+----------------------+----------------------------+----------------------------+
+|          0x12        | Scope (8 bits)       | bucket_id (128 bits)       | Bytes/sec (64 bits)        |
++----------------------+----------------------+----------------------------+----------------------------+
+| Requests/sec (64 bits)                      | Name Length (32 bits)      | Name (variable length)     |
++---------------------------------------------+----------------------------+----------------------------+
+scope: 0x00 global, 0x01 one principal by name, 0x02 one bucket by id. the nil bucket id or an
+empty name changes the default for every bucket or principal. 0 is unlimited, see `ratelimit`
+RESPONSE: status
+*/
+    async fn handle_set_rate_limit(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut header = [0; 1 + 16 + 8 + 8 + 4];
+        stream.read_exact(&mut header).await?;
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(header[1..17].try_into().unwrap()));
+        let rate = Rate {
+            bytes_per_sec: u64::from_be_bytes(header[17..25].try_into().unwrap()),
+            requests_per_sec: u64::from_be_bytes(header[25..33].try_into().unwrap()),
+        };
+        let name = match validation::read_name(stream, u32::from_be_bytes(header[33..].try_into().unwrap())).await? {
+            Ok(name) => name,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
+
+        if !session.is_admin() {
+            return Self::respond_error(stream, Status::Denied, "admin only").await;
+        }
+        let (scope, name) = match Scope::from_u8(header[0]) {
+            Some(Scope::Bucket) if bucket_id.is_nil() => (Scope::Bucket, String::new()),
+            Some(Scope::Bucket) => (Scope::Bucket, bucket_id.to_string()),
+            Some(scope) => (scope, name),
+            None => return Self::respond_error(stream, Status::BadRequest, "unknown rate limit scope").await,
+        };
+        println!("Rate limit {:?} {:?} set to {:?}", scope, name, rate);
+        self.rates.set(scope, &name, rate);
+        stream.write_all(&[Status::Ok as u8]).await?;
+        Ok(())
+    }
+
+/*
+RATE LIMITS REQUEST (admin only):
++----------------------+
+|          0x13        |
++----------------------+
+RATE LIMITS RESPONSE: status, Count (32 bits), then (REPEATING) in the SET RATE LIMIT layout:
++----------------------+----------------------------+----------------------------+----------------------------+
+| Scope (8 bits)       | bucket_id (128 bits)       | Bytes/sec (64 bits)        | Requests/sec (64 bits)     |
++----------------------+----------------------------+----------------------------+----------------------------+
+| Name Length (32 bits)| Name (variable length)     |
++----------------------+----------------------------+
+the defaults for global, principals and buckets come first, then the overrides
+*/
+    async fn handle_rate_limits(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !session.is_admin() {
+            return Self::respond_error(stream, Status::Denied, "admin only").await;
+        }
+        let limits = self.rates.list();
+        let mut response = vec![Status::Ok as u8];
+        response.extend_from_slice(&(limits.len() as u32).to_be_bytes());
+        for (scope, name, rate) in limits {
+            let (bucket_id, name) = match scope {
+                Scope::Bucket => (uuid::Uuid::parse_str(&name).unwrap_or_default(), String::new()),
+                _ => (uuid::Uuid::nil(), name),
+            };
+            response.push(scope as u8);
+            response.extend_from_slice(&bucket_id.as_u128().to_be_bytes());
+            response.extend_from_slice(&rate.bytes_per_sec.to_be_bytes());
+            response.extend_from_slice(&rate.requests_per_sec.to_be_bytes());
+            response.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            response.extend_from_slice(name.as_bytes());
+        }
+        stream.write_all(&response).await?;
+        Ok(())
+    }
+
+/*
+INITIATE MULTIPART REQUEST:
++----------------------+----------------------+----------------------+
+|          0x14        | Key Length (32 bits) | bucket_id (128 bits) |
++----------------------+----------------------+----------------------+
 +-----------------------------------------------------------------------------------------+
-|                                 Key (variable length)                                   |
+|                              Key (variable length)                                      |
 +-----------------------------------------------------------------------------------------+
-|                              File Data (variable length)                                |
+INITIATE MULTIPART RESPONSE:
++----------------------+----------------------------+
+|   Status (8 bits)    | Upload Id (128 bits)       |
++----------------------+----------------------------+
+see `multipart`, every step needs write access to the bucket
+*/
+    async fn handle_multipart_start(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut header = [0; 4 + 16];
+        stream.read_exact(&mut header).await?;
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(header[4..].try_into().unwrap())).to_string();
+        let key = match validation::read_key(stream, u32::from_be_bytes(header[..4].try_into().unwrap())).await? {
+            Ok(key) => key,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
+
+        if !self.authorize(stream, session, &bucket_id, Some(&key), acl::WRITE).await? {
+            return Ok(());
+        }
+        self.throttle(session, &bucket_id).await;
+        let id = self.uploads.start(&bucket_id, &key);
+        stream.write_all(&[Status::Ok as u8]).await?;
+        stream.write_all(&id.to_be_bytes()).await?;
+        Ok(())
+    }
+
+    /// the (bucket, key) of a multipart upload the session may write to, None once an error went out
+    async fn multipart_target(
+        self: &Arc<Self>,
+        stream: &mut ClientStream,
+        session: &Session,
+        id: u128,
+    ) -> Result<Option<(String, String)>, Box<dyn Error + Send + Sync>> {
+        let (bucket_id, key) = match self.uploads.target(id) {
+            Some(target) => target,
+            None => {
+                Self::respond_error(stream, Status::NotFound, "no such upload").await?;
+                return Ok(None);
+            }
+        };
+        if !self.authorize(stream, session, &bucket_id, Some(&key), acl::WRITE).await? {
+            return Ok(None);
+        }
+        Ok(Some((bucket_id, key)))
+    }
+
+/*
+UPLOAD PART REQUEST:
++----------------------+----------------------------+----------------------------+----------------------------+
+|          0x15        | Upload Id (128 bits)       | Part Number (32 bits)      | Part Length (32 bits)      |
++----------------------+----------------------------+----------------------------+----------------------------+
++-----------------------------------------------------------------------------------------+
+|                              Part Data (variable length)                                |
++-----------------------------------------------------------------------------------------+
+|                              SHA-256 of Part Data (256 bits)                            |
 +-----------------------------------------------------------------------------------------+
+UPLOAD PART RESPONSE: status
+part numbers run from 1 to 10000, parts of one upload can be sent at the same time over
+different connections
 */
-    fn handle_upload(
-        mut stream: TcpStream,
-        db_path: Option<&String>,
-        working_dir: &PathBuf,
-    ) -> Result<(), Box<dyn Error>> 
-    {
-        let mut key_length_buf = [0; 4];
-        stream.read_exact(&mut key_length_buf)?;
-        let key_length = u32::from_be_bytes(key_length_buf);
+    async fn handle_upload_part(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut header = [0; 16 + 4 + 4];
+        stream.read_exact(&mut header).await?;
+        let id = u128::from_be_bytes(header[..16].try_into().unwrap());
+        let number = u32::from_be_bytes(header[16..20].try_into().unwrap());
+        let length = u32::from_be_bytes(header[20..].try_into().unwrap());
 
-        let mut file_length_buf = [0; 4];
-        stream.read_exact(&mut file_length_buf)?;
-        let file_length = u32::from_be_bytes(file_length_buf);
+        if !(1..=multipart::MAX_PARTS).contains(&number) {
+            return Self::respond_error(stream, Status::BadRequest, "part number out of range").await;
+        }
+        let (bucket_id, _) = match self.multipart_target(stream, session, id).await? {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        let throttle = self.throttle(session, &bucket_id).await;
+        // the part's length comes off the bucket's room before its body is read, that is the quota's
+        // share here. compression and encryption apply once the parts are stitched
+        let reservation = self.blocking(move |h| h.quota.reserve(&bucket_id, length.into())).await?;
+        stream.transfer();
+        let received = self.receive_body(stream, Some(length), Compression::None, Storage::default(), reservation, &throttle).await?;
+        if received.count != u64::from(length) {
+            Err(TcpfsError::Protocol(format!("part ended after {} of {} bytes", received.count, length)))?;
+        }
+        let mut expected = [0; 32];
+        stream.read_exact(&mut expected).await?;
+        if received.checksum != expected {
+            return Self::respond_error(stream, Status::BadRequest, "checksum mismatch").await;
+        }
 
-        // FIXME: Lots of allocs happening here, probably could be done better
-        let mut bucket_id_buf = [0; 16];
-        stream.read_exact(&mut bucket_id_buf)?;
-        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf)).to_string();
+        let part = Part { staged: received.staged, checksum: received.checksum, size: received.count, reservation: received.reservation };
+        self.uploads.add_part(id, number, part)?;
+        stream.write_all(&[Status::Ok as u8]).await?;
+        Ok(())
+    }
 
-        let mut con = meta_sqlite::get_connection(db_path.cloned()).unwrap();
-        let trans = meta_sqlite::start_transaction(&mut con);
+/*
+COMPLETE MULTIPART REQUEST:
++----------------------+----------------------------+----------------------------+
+|          0x16        | Upload Id (128 bits)       | Part Count (32 bits)       |
++----------------------+----------------------------+----------------------------+
+then (REPEATING) in ascending order:
++----------------------------+----------------------------------------+
+| Part Number (32 bits)      | SHA-256 of Part Data (256 bits)        |
++----------------------------+----------------------------------------+
+COMPLETE MULTIPART RESPONSE:
++----------------------+----------------------------+
+|   Status (8 bits)    | Object Size (64 bits)      |
++----------------------+----------------------------+
+the listed parts become the object in that order, the rest are dropped. a failure, a mismatch
+or anything after it like the disk, leaves the upload as it was to try again. the parts already
+hold the object's room in the bucket, a COMPLETE is never refused over the quota
+*/
+    async fn handle_multipart_complete(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut header = [0; 16 + 4];
+        stream.read_exact(&mut header).await?;
+        let id = u128::from_be_bytes(header[..16].try_into().unwrap());
+        let count = u32::from_be_bytes(header[16..].try_into().unwrap());
+        if count > multipart::MAX_PARTS {
+            return Self::respond_error(stream, Status::BadRequest, "too many parts").await;
+        }
+        let mut listing = vec![0; count as usize * 36];
+        stream.read_exact(&mut listing).await?;
+        let listed: Vec<(u32, [u8; 32])> = listing
+            .chunks(36)
+            .map(|entry| (u32::from_be_bytes(entry[..4].try_into().unwrap()), entry[4..].try_into().unwrap()))
+            .collect();
 
-        let mut key_buf = vec![0; key_length as usize];
-        stream.read_exact(&mut key_buf)?;
-        let key = String::from_utf8(key_buf).expect("Invalid UTF-8 in path");
+        let (bucket_id, key) = match self.multipart_target(stream, session, id).await? {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        self.throttle(session, &bucket_id).await;
+        let storage = {
+            let bucket_id = bucket_id.clone();
+            self.blocking(move |h| h.bucket_storage(&bucket_id)).await?
+        };
+        if storage.encrypted && self.master_key.is_none() {
+            return Self::respond_error(stream, Status::Internal, "bucket is encrypted and no master key is configured").await;
+        }
 
-        let (iso, parts) = Self::iso8601_now();
-        println!("ISO8601: {}", iso);
+        let mut parts = self.uploads.complete(id, &listed)?;
+        let (parts, completed) = self
+            .blocking(move |h| {
+                let completed = h.complete_multipart(&mut parts, &bucket_id, &key, storage);
+                Ok::<_, TcpfsError>((parts, completed))
+            })
+            .await?;
+        // a failure (a conflict, the disk) leaves the parts where they were for another try
+        let size = match completed {
+            Ok(size) => size,
+            Err(e) => {
+                self.uploads.restore(id, &listed, parts);
+                return Err(e);
+            }
+        };
+        self.uploads.completed(id);
+        stream.write_all(&[Status::Ok as u8]).await?;
+        stream.write_all(&size.to_be_bytes()).await?;
+        Ok(())
+    }
 
-        let pb = PathBuf::new();
-        let destination = pb
-            .join(working_dir)
-            .join(bucket_id.clone())
-            .join(parts.0.to_string())
-            .join(parts.1.to_string())
-            .join(parts.2.to_string())
-            .join(parts.3.to_string())
-            .join(parts.4.to_string())
-            .join(parts.5.to_string())
-            .join(parts.6.to_string())
-            .join("file.data".to_string());
-            
-        let dest_parent = destination.parent().unwrap();
-        std::fs::create_dir_all(dest_parent).unwrap();
-
-        meta_sqlite::insert_metadata(
-            &trans,
-            bucket_id.as_str(),
-            &key,
-            destination.as_os_str().to_str().unwrap(),
-            &file_length.to_string(),
-            &iso
-        )?;
-
-        // Open the destination file for writing
-        let mut file = fs::File::create(destination)?;
-
-        // Copy the bytes from the limited stream to the file
-        std::io::copy(&mut stream.take(file_length.into()), &mut file)?;
-        trans.commit()?;
-
-        // Buffer to store each received message
+/*
+ABORT MULTIPART REQUEST:
++----------------------+----------------------------+
+|          0x17        | Upload Id (128 bits)       |
++----------------------+----------------------------+
+ABORT MULTIPART RESPONSE: status
+*/
+    async fn handle_multipart_abort(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut id = [0; 16];
+        stream.read_exact(&mut id).await?;
+        let id = u128::from_be_bytes(id);
+        if self.multipart_target(stream, session, id).await?.is_none() {
+            return Ok(());
+        }
+        // the staged parts go with it
+        self.uploads.abort(id);
+        stream.write_all(&[Status::Ok as u8]).await?;
         Ok(())
     }
-    fn handle_delete() {}
+
+/*
+APPEND REQUEST:
+header:
++----------------------+----------------------+----------------------+----------------------+
+|          0x19        | Key Length (32 bits) | Data Length (32 bits)| bucket_id (128 bits) |
++----------------------+----------------------+----------------------+----------------------+
++-----------------------------------------------------------------------------------------+
+|     Expected Length (64 bits), the object's current size or 0xFFFFFFFFFFFFFFFF for any   |
++-----------------------------------------------------------------------------------------+
+data:
++-----------------------------------------------------------------------------------------+
+|                                 Key (variable length)                                   |
++-----------------------------------------------------------------------------------------+
+|                              Data (variable length)                                     |
++-----------------------------------------------------------------------------------------+
+|                              SHA-256 of Data (256 bits)                                 |
++-----------------------------------------------------------------------------------------+
+APPEND RESPONSE:
++----------------------+----------------------------+
+|   Status (8 bits)    | Object Size (64 bits)      |
++----------------------+----------------------------+
+adds the data to the end of the object, a missing key is created (expecting 0 or any length).
+an object that isn't the expected length is left alone and answered with CONFLICT, so concurrent
+writers can each append at the offset they know about and retry. the data is staged and checked
+first, then written after the existing bytes and committed with the new length, readers see the
+object at its old or its new length. objects stored encrypted or compressed can't be appended to,
+new ones are stored as they come whatever the bucket's compression
+*/
+    async fn handle_append(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut header = [0; 32];
+        stream.read_exact(&mut header).await?;
+        let key_length = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let length = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(header[8..24].try_into().unwrap())).to_string();
+        let expected = match u64::from_be_bytes(header[24..].try_into().unwrap()) {
+            u64::MAX => None,
+            expected => Some(expected),
+        };
+
+        let key = match validation::read_key(stream, key_length).await? {
+            Ok(key) => key,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
+
+        if !self.authorize(stream, session, &bucket_id, Some(&key), acl::WRITE).await? {
+            return Ok(());
+        }
+        let throttle = self.throttle(session, &bucket_id).await;
+
+        let storage = {
+            let bucket_id = bucket_id.clone();
+            self.blocking(move |h| h.bucket_storage(&bucket_id)).await?
+        };
+        if storage.encrypted {
+            return Self::respond_error(stream, Status::BadRequest, "objects in encrypted buckets can't be appended to").await;
+        }
+        // held until the new length is committed, whichever object in the bucket gets there first
+        let reservation = {
+            let bucket_id = bucket_id.clone();
+            self.blocking(move |h| h.quota.reserve(&bucket_id, length.into())).await?
+        };
+
+        stream.transfer();
+        let received = self.receive_body(stream, Some(length), Compression::None, Storage::default(), reservation, &throttle).await?;
+        if received.count != u64::from(length) {
+            Err(TcpfsError::Protocol(format!("append ended after {} of {} bytes", received.count, length)))?;
+        }
+        let mut checksum = [0; 32];
+        stream.read_exact(&mut checksum).await?;
+        if received.checksum != checksum {
+            return Self::respond_error(stream, Status::BadRequest, "checksum mismatch").await;
+        }
+
+        let size = self.blocking(move |h| h.append_object(received, &bucket_id, &key, expected)).await?;
+        stream.write_all(&[Status::Ok as u8]).await?;
+        stream.write_all(&size.to_be_bytes()).await?;
+        Ok(())
+    }
+
+/*
+LIST REQUEST:
+header:
++----------------------+--------------------+----------------------+
+|          0x02        | Path Length (32 bits)| bucket_id (128 bits)|
++----------------------+--------------------+----------------------+
++-----------------------------------------------------------------------------------------+
+|        Relative Path (variable length)                                                  |
++-----------------------------------------------------------------------------------------+
+LIST RESPONSE: status, then (REPEATING):
+
++------------------------------+--------------------------+--------------------+------------------------------------------+
+|    1 byte (0==file, 1==dir)    | Path Length (32 bits) |  bucket_id (128 bits)     |    File/Dir size length ( 32 bits ) |
++------------------------------+--------------------------+----------------------+-----------------------------------------+
++----------------------+--------------------+----------------------+----------------------+
+|                                Path
++----------------------+--------------------+----------------------+----------------------+
+\r\n
+*/
+    async fn handle_list(self: &Arc<Self>, stream: &mut ClientStream, session: &Session) -> Result<(), Box<dyn Error + Send + Sync>>
+    {
+        let mut key_length_buf: [u8; 4] = [0; 4];
+        stream.read_exact(&mut key_length_buf).await?;
+        let key_length = u32::from_be_bytes(key_length_buf);
+
+        let mut bucket_id_buf = [0; 16];
+        stream.read_exact(&mut bucket_id_buf).await?;
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf)).to_string();
+
+        let key = match validation::read_prefix(stream, key_length).await? {
+            Ok(key) => key,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
+
+        if !self.authorize(stream, session, &bucket_id, None, acl::LIST).await? {
+            return Ok(());
+        }
+        self.throttle(session, &bucket_id).await;
+
+        let objects = self.blocking(move |h| h.metadata.get_objects_in_path(&bucket_id, &key)).await?;
+        // a long listing is a body like any other
+        stream.transfer();
+        stream.write_all(&[Status::Ok as u8]).await?;
+        for obj in objects {
+            stream.write_all(&obj.serialize()).await?;
+        }
+
+        Ok(())
+    }
+
+
+/*
+DOWNLOAD REQUEST:
+header:
++----------------------+----------------------+----------------------+
+|          0x02        | Key Length (32 bits) | bucket_id (128 bits) |
++----------------------+----------------------+----------------------+
++-----------------------------------------------------------------------------------------+
+|                              Key (variable length)                                      |
++-----------------------------------------------------------------------------------------+
+DOWNLOAD RANGE REQUEST, same as DOWNLOAD with the range after the bucket:
++----------------------+----------------------+----------------------+----------------------+----------------------+
+|          0x0E        | Key Length (32 bits) | bucket_id (128 bits) | Offset (64 bits)     | Length (64 bits)     |
++----------------------+----------------------+----------------------+----------------------+----------------------+
+DOWNLOAD RESPONSE:
++----------------------+------------------------------------------------------------------+
+|   Status (8 bits)    |                File Data (variable length)                       |
++----------------------+------------------------------------------------------------------+
+encrypted objects are decrypted on the way out, ranges are clamped to the end of the object
+*/
+    async fn handle_download(
+        self: &Arc<Self>,
+        stream: &mut ClientStream,
+        session: &Session,
+        ranged: bool,
+        codec: Compression,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>
+    {
+        let mut key_length_buf = [0; 4];
+        stream.read_exact(&mut key_length_buf).await?;
+        let path_length = u32::from_be_bytes(key_length_buf);
+
+        // FIXME: Lots of allocs happening here, probably could be done better
+        let mut bucket_id_buf = [0; 16];
+        stream.read_exact(&mut bucket_id_buf).await?;
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf)).to_string();
+
+        let (offset, length) = match ranged {
+            true => {
+                let mut range_buf = [0; 16];
+                stream.read_exact(&mut range_buf).await?;
+                (
+                    u64::from_be_bytes(range_buf[..8].try_into().unwrap()),
+                    u64::from_be_bytes(range_buf[8..].try_into().unwrap()),
+                )
+            }
+            false => (0, u64::MAX),
+        };
+
+        let key = match validation::read_key(stream, path_length).await? {
+            Ok(key) => key,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
+
+        if !self.authorize(stream, session, &bucket_id, Some(&key), acl::READ).await? {
+            return Ok(());
+        }
+        let throttle = self.throttle(session, &bucket_id).await;
+
+        let found = match self.blocking(move |h| h.metadata.get_object(&bucket_id, &key)).await? {
+            Some(found) => found,
+            None => return Self::respond_error(stream, Status::NotFound, "no such key").await,
+        };
+
+        if offset > found.object.file_size as u64 {
+            return Self::respond_error(stream, Status::BadRequest, "range starts past the end of the object").await;
+        }
+
+        let length = length.min(found.object.file_size as u64 - offset);
+        // the stored bytes as they are: uncompressed ones for a plain request, or the whole
+        // object when it is stored in the codec that was asked for
+        let whole = offset == 0 && length == found.object.file_size as u64;
+        let raw = found.compression == codec && (codec == Compression::None || whole);
+
+        // plain blobs in files go from the page cache straight to a plain socket
+        stream.transfer();
+        if raw && found.wrapped_key.is_none() && !stream.is_tls() && !throttle.is_limited() {
+            let (location, range) = (found.location.clone(), found.range);
+            let file = self.blocking(move |h| h.store.open_file(&location, range).map_err(blob_error)).await?;
+            if let Some((file, start, stored)) = file {
+                let (start, length) = match codec {
+                    Compression::None => (start + offset, length),
+                    _ => (start, stored),
+                };
+                stream.write_all(&[Status::Ok as u8]).await?;
+                let pace = stream.pace();
+                let sent = match () {
+                    #[cfg(feature = "io-uring")]
+                    _ if uring::available() => uring::send_file(file, start, length, stream.tcp(), pace).await?,
+                    _ => zerocopy::send_file(&file, start, length, stream.tcp(), pace).await?,
+                };
+                if sent != length {
+                    Err(io::Error::new(ErrorKind::UnexpectedEof, "blob is shorter than the object"))?;
+                }
+                return Ok(());
+            }
+        }
+
+        let reader = self.blocking(move |h| h.open_blob(found, offset, raw)).await?;
+        // a compressed blob has its own length, it is sent whole
+        let (reader, encode): (Box<dyn Read + Send>, _) = match (raw, codec) {
+            (true, Compression::None) => (Box::new(reader.take(length)), Compression::None),
+            (true, _) => (reader, Compression::None),
+            (false, codec) => (Box::new(reader.take(length)), codec),
+        };
+        stream.write_all(&[Status::Ok as u8]).await?;
+        send_blob(reader, stream, &throttle, encode).await?;
+        Ok(())
+    }
+
+/*
+UPLOAD REQUEST:
+header:
++----------------------+----------------------+----------------------+----------------------+
+| Command Type (8 bits)| Key Length (32 bits) | File Length (32 bits)| bucket_id (128 bits) |
++----------------------+----------------------+----------------------+----------------------+
+data:
++-----------------------------------------------------------------------------------------+
+|                                 Key (variable length)                                   |
++-----------------------------------------------------------------------------------------+
+|                              File Data (variable length)                                |
++-----------------------------------------------------------------------------------------+
+|                              SHA-256 of File Data (256 bits)                            |
++-----------------------------------------------------------------------------------------+
+UPLOAD CHUNKED REQUEST, same as UPLOAD without the file length, the data comes in chunks:
++----------------------+----------------------+----------------------+
+|          0x18        | Key Length (32 bits) | bucket_id (128 bits) |
++----------------------+----------------------+----------------------+
++-----------------------------------------------------------------------------------------+
+|                                 Key (variable length)                                   |
++-----------------------------------------------------------------------------------------+
+then (REPEATING) until a chunk of length 0:
++----------------------------+------------------------------------------------------------+
+| Chunk Length (32 bits)     |                  Chunk Data (variable length)              |
++----------------------------+------------------------------------------------------------+
+then the trailer:
++----------------------------+------------------------------------------------------------+
+| Has Checksum (8 bits)      |      SHA-256 of File Data (256 bits), if Has Checksum is 1  |
++----------------------------+------------------------------------------------------------+
+UPLOAD RESPONSE: status
+the data is staged, checked against the length and checksum, fsynced and renamed into place
+before the metadata is committed, so a dropped connection never leaves a truncated object.
+with a bucket quota (TCPFS_BUCKET_QUOTA) the upload is refused with QUOTA EXCEEDED as soon as
+the bucket would go over, up front when the length is known and as the bytes arrive when it isn't
+*/
+    async fn handle_upload(
+        self: &Arc<Self>,
+        stream: &mut ClientStream,
+        session: &Session,
+        chunked: bool,
+        codec: Compression,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>
+    {
+        let mut key_length_buf = [0; 4];
+        stream.read_exact(&mut key_length_buf).await?;
+        let key_length = u32::from_be_bytes(key_length_buf);
+
+        let mut file_length = None;
+        if !chunked {
+            let mut file_length_buf = [0; 4];
+            stream.read_exact(&mut file_length_buf).await?;
+            file_length = Some(u32::from_be_bytes(file_length_buf));
+        }
+
+        // FIXME: Lots of allocs happening here, probably could be done better
+        let mut bucket_id_buf = [0; 16];
+        stream.read_exact(&mut bucket_id_buf).await?;
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf)).to_string();
+
+        let key = match validation::read_key(stream, key_length).await? {
+            Ok(key) => key,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
+
+        if !self.authorize(stream, session, &bucket_id, Some(&key), acl::WRITE).await? {
+            return Ok(());
+        }
+        let throttle = self.throttle(session, &bucket_id).await;
+
+        let storage = {
+            let bucket_id = bucket_id.clone();
+            self.blocking(move |h| h.bucket_storage(&bucket_id)).await?
+        };
+        if storage.encrypted && self.master_key.is_none() {
+            return Self::respond_error(stream, Status::Internal, "bucket is encrypted and no master key is configured").await;
+        }
+        // a plain body of known length holds its room before it is read, anything else as it decodes
+        let reserve = match (file_length, codec) {
+            (Some(length), Compression::None) => length.into(),
+            _ => 0,
+        };
+        let reservation = {
+            let bucket_id = bucket_id.clone();
+            self.blocking(move |h| h.quota.reserve(&bucket_id, reserve)).await?
+        };
+
+        stream.transfer();
+        let received = self.receive_body(stream, file_length, codec, storage, reservation, &throttle).await?;
+
+        let expected = match file_length {
+            Some(length) => {
+                // the copy stops quietly when the client goes away, so check we got everything
+                if received.count != u64::from(length) {
+                    Err(TcpfsError::Protocol(format!("upload ended after {} of {} bytes", received.count, length)))?;
+                }
+                let mut expected = [0; 32];
+                stream.read_exact(&mut expected).await?;
+                Some(expected)
+            }
+            None => {
+                let mut trailer = [0; 1];
+                stream.read_exact(&mut trailer).await?;
+                match trailer[0] {
+                    0 => None,
+                    1 => {
+                        let mut expected = [0; 32];
+                        stream.read_exact(&mut expected).await?;
+                        Some(expected)
+                    }
+                    _ => return Self::respond_error(stream, Status::BadRequest, "invalid trailer").await,
+                }
+            }
+        };
+        if expected.is_some_and(|expected| received.checksum != expected) {
+            return Self::respond_error(stream, Status::BadRequest, "checksum mismatch").await;
+        }
+
+        self.blocking(move |h| h.commit_upload(received, &bucket_id, &key)).await?;
+
+        stream.write_all(&[Status::Ok as u8]).await?;
+        Ok(())
+    }
+
+    /// Copy `length` bytes, or chunks when None, from the stream to a staged blob, nothing is visible yet.
+    /// the body has to fit into `reservation`, or the bucket's room that is left to grow it
+    async fn receive_body(
+        self: &Arc<Self>,
+        stream: &mut ClientStream,
+        length: Option<u32>,
+        wire: Compression,
+        storage: Storage,
+        reservation: Reservation,
+        throttle: &Throttle,
+    ) -> Result<Received, Box<dyn Error + Send + Sync>> {
+        #[allow(unused_mut)]
+        let mut staged = self.blocking(|h| h.store.stage()).await?;
+
+        // plain bodies staged in files can go through io_uring, the next chunk arrives while the last is written.
+        // their length was reserved up front
+        #[cfg(feature = "io-uring")]
+        if let (Some(file_length), false, (Compression::None, Compression::None), true, false, false) = (
+            length,
+            storage.encrypted,
+            (wire, storage.compression),
+            staged.file().is_some(),
+            stream.is_tls(),
+            throttle.is_limited(),
+        ) {
+            if uring::available() {
+                use sha2::{Digest, Sha256};
+                use std::os::fd::{AsFd, AsRawFd};
+                let sock = stream.tcp().as_fd().try_clone_to_owned()?;
+                let pace = stream.pace();
+                return self
+                    .blocking(move |_| {
+                        let mut hasher = Sha256::new();
+                        let file = staged.file().unwrap();
+                        let count = uring::receive(sock.as_raw_fd(), file, 0, file_length.into(), pace, |data| {
+                            hasher.update(data)
+                        })?;
+                        let checksum = hasher.finalize().into();
+                        let compression = Compression::None;
+                        let (size, stored_size) = (count, count);
+                        Ok::<_, io::Error>(Received { staged, data_key: None, compression, checksum, count, size, stored_size, reservation })
+                    })
+                    .await;
+            }
+        }
+
+        // the file side runs on the blocking pool and gets the bytes through a pipe
+        let (pipe, mut writer) = tokio::io::duplex(PIPE_SIZE);
+        let body = SyncIoBridge::new(pipe);
+        let receiving = self.blocking(move |h| h.receive_upload(staged, body, wire, storage, reservation));
+        let sending = async {
+            let sent = match length {
+                Some(length) => copy_from_client(stream, &mut writer, length.into(), throttle).await,
+                None => copy_chunks_from_client(stream, &mut writer, throttle).await,
+            };
+            // the receiving side only stops at the end of the pipe
+            drop(writer);
+            sent
+        };
+        let (received, sent) = tokio::join!(receiving, sending);
+        // a failed write on our side breaks the pipe too, its error is the one worth reporting
+        let received = received?;
+        sent?;
+        Ok(received)
+    }
+
+/*
+DELETE REQUEST:
+header:
++----------------------+----------------------+----------------------+
+|          0x03        | Key Length (32 bits) | bucket_id (128 bits) |
++----------------------+----------------------+----------------------+
++-----------------------------------------------------------------------------------------+
+|                              Key (variable length)                                      |
++-----------------------------------------------------------------------------------------+
+DELETE RESPONSE:
++----------------------+----------------------------+
+|   Status (8 bits)    | Bytes Freed (64 bits)      |
++----------------------+----------------------------+
+*/
+    async fn handle_delete(
+        self: &Arc<Self>,
+        stream: &mut ClientStream,
+        session: &Session,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut key_length_buf = [0; 4];
+        stream.read_exact(&mut key_length_buf).await?;
+        let key_length = u32::from_be_bytes(key_length_buf);
+
+        let mut bucket_id_buf = [0; 16];
+        stream.read_exact(&mut bucket_id_buf).await?;
+        let bucket_id = uuid::Uuid::from_u128(u128::from_be_bytes(bucket_id_buf)).to_string();
+
+        let key = match validation::read_key(stream, key_length).await? {
+            Ok(key) => key,
+            Err(e) => return Self::respond_bad_request(stream, e).await,
+        };
+
+        if !self.authorize(stream, session, &bucket_id, None, acl::DELETE).await? {
+            return Ok(());
+        }
+        self.throttle(session, &bucket_id).await;
+        let freed = match self.blocking(move |h| h.delete_object(&bucket_id, &key)).await? {
+            Some(freed) => freed,
+            None => return Self::respond_error(stream, Status::NotFound, "no such key").await,
+        };
+
+        stream.write_all(&[Status::Ok as u8]).await?;
+        stream.write_all(&freed.to_be_bytes()).await?;
+        Ok(())
+    }
+
+    // the synchronous halves of the handlers above, these run on the blocking pool
+
+    /// how new objects in the bucket are stored
+    fn bucket_storage(&self, bucket_id: &str) -> meta_store::Result<Storage> {
+        let encrypted = self.metadata.is_bucket_encrypted(bucket_id)?;
+        // encrypted objects are stored uncompressed
+        let compression = match encrypted {
+            true => Compression::None,
+            false => self.metadata.get_bucket_compression(bucket_id)?,
+        };
+        Ok(Storage { encrypted, compression })
+    }
+
+    /// the object's bytes from `offset` on, decrypted if the object was stored encrypted.
+    /// compressed objects are decompressed unless `raw`, then they come as stored
+    fn open_blob(&self, found: StoredObject, offset: u64, raw: bool) -> Result<Box<dyn Read + Send>, Box<dyn Error + Send + Sync>> {
+        let mut file = self.store.get(&found.location, found.range).map_err(blob_error)?;
+        match (found.compression, raw) {
+            (Compression::None, _) => {}
+            (_, true) => return Ok(Box::new(file)),
+            (codec, false) => return Ok(compression::decoder_at(codec, file, offset)?),
+        }
+        match (found.wrapped_key, &self.master_key) {
+            (Some(wrapped), Some(master_key)) => {
+                let data_key = master_key.unwrap_data_key(&wrapped, &found.object.path)?;
+                let mut reader = DecryptingReader::new(file, &data_key, found.object.file_size as u64);
+                reader.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(reader))
+            }
+            (Some(_), None) => Err("object is encrypted and no master key is configured")?,
+            (None, _) => {
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+
+    /// stage the upload body, encrypting it with a fresh data key for encrypted buckets.
+    /// a body in the `wire` codec is decompressed and stored the way `storage` says.
+    /// `reservation` grows with the decoded bytes, the rest isn't read once the bucket is full
+    fn receive_upload(
+        &self,
+        mut staged: Box<dyn StagedBlob>,
+        body: impl Read + Send,
+        wire: Compression,
+        storage: Storage,
+        mut reservation: Reservation,
+    ) -> Result<Received, Box<dyn Error + Send + Sync>> {
+        let Storage { encrypted, compression: stored } = storage;
+        let mut body = staging::CountingReader::new(body);
+        let mut reader = staging::HashingReader::new(Reserving::new(compression::decoder(wire, &mut body)?, &mut reservation));
+        // a body that doesn't decode is the client's fault
+        let corrupt = |e: io::Error| match (wire, e.kind()) {
+            (_, ErrorKind::QuotaExceeded) => TcpfsError::Quota(e.to_string()),
+            (Compression::Zstd | Compression::Lz4, ErrorKind::InvalidData) => TcpfsError::Protocol(e.to_string()),
+            _ => TcpfsError::from(e),
+        };
+        // what ends up on disk, encrypted objects are counted at their original size
+        let stored_size;
+        let data_key = match self.master_key.as_ref().filter(|_| encrypted) {
+            Some(_) => {
+                let data_key = crypto::new_data_key();
+                let mut writer = EncryptingWriter::new(staged.as_mut(), &data_key);
+                io::copy(&mut reader, &mut writer).map_err(corrupt)?;
+                stored_size = reader.count();
+                writer.finish()?;
+                Some(data_key)
+            }
+            None => {
+                let mut writer = compression::Encoder::stored(stored, staging::CountingWriter::new(&mut staged))?;
+                io::copy(&mut reader, &mut writer).map_err(corrupt)?;
+                stored_size = writer.finish()?.count();
+                None
+            }
+        };
+        let (size, checksum) = (reader.count(), reader.finish());
+        // the frame ended before the length the client announced
+        if io::copy(&mut body, &mut io::sink())? > 0 {
+            Err(TcpfsError::Protocol("data after the end of the compressed body".to_string()))?;
+        }
+        let compression = if data_key.is_some() { Compression::None } else { stored };
+        Ok(Received { count: body.count(), size, stored_size, checksum, compression, staged, data_key, reservation })
+    }
+
+    /// move a checked upload into place and record it
+    fn commit_upload(
+        &self,
+        received: Received,
+        bucket_id: &str,
+        key: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Received { mut staged, data_key, checksum, size, stored_size, compression, reservation, .. } = received;
+        let iso = Self::iso8601_now();
+        println!("ISO8601: {}", iso);
+
+        let destination = placement::new_blob_location(bucket_id);
+        // the data key is wrapped for this object's path and no other
+        let wrapped_key = match (&data_key, &self.master_key) {
+            (Some(data_key), Some(master_key)) => Some(master_key.wrap_data_key(data_key, &destination)?),
+            _ => None,
+        };
+
+        // small objects go into a segment instead of their own file, deduplicated ones are shared anyway.
+        // encrypted objects are never deduplicated, each one has its own data key, and neither are
+        // compressed ones, the same content may be stored in different codecs
+        let dedup = self.dedup && wrapped_key.is_none() && compression == Compression::None;
+        let held = (!dedup && size < self.pack_threshold as u64).then(|| self.segments.lock());
+        let (placed, blob) = match &held {
+            Some(held) => {
+                let appended = self.segments.append(held, &mut staged.reader()?)?;
+                let placed = Placement::Packed {
+                    segment_id: appended.segment_id,
+                    offset: appended.offset as i64,
+                    length: appended.length as i64,
+                };
+                (placed, None)
+            }
+            None if dedup => {
+                // rewriting an existing shared blob is harmless, it has the same content
+                let (hash, location) = placement::content_address(&checksum);
+                (Placement::Shared { hash, path: location.clone() }, Some(location))
+            }
+            None => (Placement::Own, Some(destination.clone())),
+        };
+
+        // the blob has to be in place before the metadata points at it
+        if let Some(blob) = &blob {
+            staged.persist(blob)?;
+        }
+        let inserted = self.metadata.insert_object(&NewObject {
+            bucket_id,
+            key,
+            path: &destination,
+            size: size as i64,
+            created_at: &iso,
+            wrapped_key: wrapped_key.as_deref(),
+            placement: placed,
+            compression,
+            stored_size: stored_size as i64,
+        });
+        if let Err(e) = inserted {
+            // the blob made it but the metadata didn't, don't leave it orphaned.
+            // a shared blob may be referenced by other objects already
+            if let (Some(blob), false) = (blob, dedup) {
+                let _ = self.store.delete(&blob);
+            }
+            Err(e)?;
+        }
+        drop(held);
+        // the metadata counts the object from here on
+        drop(reservation);
+        Ok(())
+    }
+
+    /// stitch the parts of a multipart upload into one staged blob and commit it like an upload, returns its size
+    fn complete_multipart(
+        &self,
+        parts: &mut [Part],
+        bucket_id: &str,
+        key: &str,
+        storage: Storage,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let staged = self.store.stage()?;
+        // the object is as big as the parts, their reservations hold its room until it is committed
+        let received = self.receive_upload(staged, Concat::new(parts)?, Compression::None, storage, Reservation::none())?;
+        let size = received.size;
+        self.commit_upload(received, bucket_id, key)?;
+        Ok(size)
+    }
+
+    /// add a staged append to the end of the object, or create it, returns the new size.
+    /// objects with a blob of their own grow in place and are cut back to their length when the
+    /// append fails. packed and deduplicated ones are copied to a blob of their own first
+    fn append_object(
+        &self,
+        received: Received,
+        bucket_id: &str,
+        key: &str,
+        expected: Option<u64>,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let _held = self.appends.lock(bucket_id, key);
+        let found = match self.metadata.get_object(bucket_id, key)? {
+            Some(found) => found,
+            None if expected.unwrap_or(0) == 0 => {
+                let size = received.size;
+                self.commit_upload(received, bucket_id, key)?;
+                return Ok(size);
+            }
+            None => Err(TcpfsError::NotFound("no such key".to_string()))?,
+        };
+        let size = found.object.file_size as u64;
+        if let Some(expected) = expected.filter(|expected| *expected != size) {
+            Err(TcpfsError::Conflict(format!("object is {} bytes, not {}", size, expected)))?;
+        }
+        if found.wrapped_key.is_some() || found.compression != Compression::None {
+            Err(TcpfsError::Protocol("encrypted or compressed objects can't be appended to".to_string()))?;
+        }
+
+        let Received { mut staged, size: appended, reservation, .. } = received;
+        let path = found.object.path.clone();
+        let detach = found.range.is_some() || found.location != path;
+        // what didn't get committed is nobody's, the copy goes and a blob grown in place is cut back
+        let undo = || {
+            let _ = match detach {
+                true => self.store.delete(&path),
+                false => self.store.write_at(&path, size, &mut io::empty()).map(|_| ()),
+            };
+        };
+        if detach {
+            let mut moved = self.store.stage()?;
+            io::copy(&mut self.store.get(&found.location, found.range).map_err(blob_error)?.take(size), &mut moved)?;
+            io::copy(&mut staged.reader()?, &mut moved)?;
+            moved.persist(&path)?;
+        } else if let Err(e) = self.store.write_at(&path, size, &mut staged.reader()?) {
+            undo();
+            Err(blob_error(e))?;
+        }
+
+        let new_size = size + appended;
+        let extended = self.metadata.extend_object(found.object.id.into(), size as i64, new_size as i64, detach);
+        // the metadata counts the appended bytes from here on
+        drop(reservation);
+        match extended {
+            Ok(Some(garbage)) => {
+                for location in garbage {
+                    if let Err(e) = self.store.delete(&location) {
+                        eprintln!("failed to remove {}: {:?}", location, e);
+                    }
+                }
+                Ok(new_size)
+            }
+            // deleted (or changed by another server) in the meantime
+            Ok(None) => {
+                undo();
+                Err(TcpfsError::Conflict("object changed during the append".to_string()))?
+            }
+            Err(e) => {
+                undo();
+                Err(e)?
+            }
+        }
+    }
+
+    /// drop the object's metadata and whatever blobs nothing refers to anymore, None if there was no such key.
+    /// shared blobs only go once the last object referring to them does,
+    /// packed objects leave dead space behind for compaction
+    fn delete_object(&self, bucket_id: &str, key: &str) -> meta_store::Result<Option<u64>> {
+        let deleted = match self.metadata.delete_object(bucket_id, key)? {
+            Some(deleted) => deleted,
+            None => return Ok(None),
+        };
+        // metadata is gone so the blob is unreachable either way, don't fail the request over it
+        for location in deleted.garbage {
+            if let Err(e) = self.store.delete(&location) {
+                eprintln!("failed to remove {}: {:?}", location, e);
+            }
+        }
+        Ok(Some(deleted.object.file_size as u64))
+    }
+}
+
+/// a blob the metadata points at but the store doesn't have is reported like a missing key
+fn blob_error(e: io::Error) -> TcpfsError {
+    match e.kind() {
+        ErrorKind::NotFound => TcpfsError::NotFound("Invalid key path".to_string()),
+        _ => e.into(),
+    }
+}
+
+/// How a bucket takes new objects
+#[derive(Debug, Clone, Copy, Default)]
+struct Storage {
+    encrypted: bool,
+    compression: Compression,
+}
+
+/// An upload body that made it to the staging area, not checked yet
+struct Received {
+    staged: Box<dyn StagedBlob>,
+    /// set when the body was encrypted, wrapped once the object's path is known
+    data_key: Option<crypto::DataKey>,
+    checksum: [u8; 32],
+    /// bytes that came over the wire
+    count: u64,
+    /// bytes of the object once decompressed
+    size: u64,
+    /// bytes in the staged blob
+    stored_size: u64,
+    compression: Compression,
+    /// the object's room in the bucket, held until it is committed or dropped
+    reservation: Reservation,
+}
+
+/// A lock for each (bucket, key) in use, appends to different objects don't wait on each other
+#[derive(Default)]
+struct ObjectLocks {
+    held: Mutex<HashSet<(String, String)>>,
+    released: Condvar,
+}
+
+impl ObjectLocks {
+    /// blocks until nobody holds the object, it is held until the returned guard is dropped
+    fn lock(&self, bucket_id: &str, key: &str) -> ObjectLock<'_> {
+        let object = (bucket_id.to_string(), key.to_string());
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        while held.contains(&object) {
+            held = self.released.wait(held).unwrap_or_else(|e| e.into_inner());
+        }
+        held.insert(object.clone());
+        ObjectLock { locks: self, object }
+    }
+}
+
+struct ObjectLock<'a> {
+    locks: &'a ObjectLocks,
+    object: (String, String),
+}
+
+impl Drop for ObjectLock<'_> {
+    fn drop(&mut self) {
+        self.locks.held.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.object);
+        self.locks.released.notify_all();
+    }
+}
+
+/// Copy a blob to the client, the blob is read on the blocking pool and comes over through a pipe
+async fn send_blob(
+    mut blob: impl Read + Send + 'static,
+    stream: &mut ClientStream,
+    throttle: &Throttle,
+    codec: Compression,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (mut pipe, writer) = tokio::io::duplex(PIPE_SIZE);
+    let writer = SyncIoBridge::new(writer);
+    let reading = tokio::task::spawn_blocking(move || {
+        let mut writer = compression::Encoder::new(codec, writer)?;
+        io::copy(&mut blob, &mut writer)?;
+        writer.finish().map(drop)
+    });
+    let sent = copy_to_client(&mut pipe, stream, throttle).await;
+    // unblocks the reading side if the client went away
+    drop(pipe);
+    // a failed read closes the pipe early, which looks like a short blob from here
+    reading.await??;
+    sent?;
+    Ok(())
+}
+
+/// `length` bytes of upload body from the client into `pipe`, paid for with `throttle` as they arrive
+async fn copy_from_client(stream: &mut ClientStream, pipe: &mut DuplexStream, length: u64, throttle: &Throttle) -> io::Result<u64> {
+    let mut buf = vec![0; PIPE_SIZE];
+    let mut copied = 0;
+    while copied < length {
+        let want = (length - copied).min(PIPE_SIZE as u64) as usize;
+        let n = stream.read(&mut buf[..want]).await?;
+        if n == 0 {
+            break;
+        }
+        // held back by us, the client isn't slow
+        let waited = throttle.consume(n).await;
+        stream.excuse(waited);
+        pipe.write_all(&buf[..n]).await?;
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+/// a chunked upload body from the client into `pipe`, up to the zero length chunk that ends it
+async fn copy_chunks_from_client(stream: &mut ClientStream, pipe: &mut DuplexStream, throttle: &Throttle) -> io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        let mut chunk_length = [0; 4];
+        stream.read_exact(&mut chunk_length).await?;
+        let chunk_length = u64::from(u32::from_be_bytes(chunk_length));
+        if chunk_length == 0 {
+            return Ok(copied);
+        }
+        // without a total there is nothing to check afterwards, a short chunk is an error right here
+        if copy_from_client(stream, pipe, chunk_length, throttle).await? != chunk_length {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "upload ended inside a chunk"));
+        }
+        copied += chunk_length;
+    }
+}
+
+/// everything in `pipe` to the client, paid for with `throttle` before it goes out
+async fn copy_to_client(pipe: &mut DuplexStream, stream: &mut ClientStream, throttle: &Throttle) -> io::Result<u64> {
+    let mut buf = vec![0; PIPE_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = pipe.read(&mut buf).await?;
+        if n == 0 {
+            stream.flush().await?;
+            return Ok(copied);
+        }
+        let waited = throttle.consume(n).await;
+        stream.excuse(waited);
+        stream.write_all(&buf[..n]).await?;
+        copied += n as u64;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use tempdir::TempDir;
+
+    use crate::store::{FsBlobStore, MemoryBlobStore};
+
+    #[test]
+    fn test_error_statuses() {
+        let status = |e: Box<dyn Error + Send + Sync>| Status::of(e.as_ref());
+        assert_eq!(status(TcpfsError::NotFound("gone".into()).into()), Some(Status::NotFound));
+        assert_eq!(status(TcpfsError::Conflict("taken".into()).into()), Some(Status::Conflict));
+        assert_eq!(status(TcpfsError::Quota("full".into()).into()), Some(Status::QuotaExceeded));
+        assert_eq!(status(TcpfsError::Protocol("bad".into()).into()), Some(Status::BadRequest));
+        assert_eq!(status(TcpfsError::Db("broken".into()).into()), Some(Status::Internal));
+        assert_eq!(status(io::Error::from(ErrorKind::StorageFull).into()), Some(Status::QuotaExceeded));
+        // the connection is gone, there is nobody to answer
+        assert_eq!(status(io::Error::from(ErrorKind::BrokenPipe).into()), None);
+        assert_eq!(status(TcpfsError::from(io::Error::from(ErrorKind::BrokenPipe)).into()), None);
+        assert_eq!(status("anything else".into()), Some(Status::Internal));
+    }
+
+    #[test]
+    fn test_object_locks() {
+        let locks = ObjectLocks::default();
+        let held = locks.lock("b", "k");
+        // other objects don't wait
+        drop(locks.lock("b", "other"));
+        std::thread::scope(|s| {
+            let waiting = s.spawn(|| drop(locks.lock("b", "k")));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!waiting.is_finished());
+            drop(held);
+            waiting.join().unwrap();
+        });
+        assert!(locks.held.lock().unwrap().is_empty());
+    }
+
+    fn handler(acl_mode: AclMode, store: Arc<dyn BlobStore>) -> Arc<RequestHandler> {
+        Arc::new(RequestHandler::new(config(acl_mode, store)))
+    }
+
+    fn config(acl_mode: AclMode, store: Arc<dyn BlobStore>) -> HandlerConfig {
+        let metadata = meta_sqlite::SqliteMetadataStore::open(None).unwrap();
+        metadata.upsert_principal("admin", &acl::hash_api_key(b"admin-key"), true).unwrap();
+        HandlerConfig {
+            metadata: Arc::new(metadata),
+            store,
+            acl_mode,
+            token_secret: b"secret".to_vec(),
+            master_key: None,
+            dedup: false,
+            pack_threshold: 0,
+            rates: RateConfig::default(),
+            bucket_quota: 0,
+        }
+    }
+
+    /// sends `request` as its own connection and returns the whole response
+    async fn exchange(handler: &Arc<RequestHandler>, listener: &TcpListener, request: &[u8]) -> Vec<u8> {
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(request).await.unwrap();
+        let (sock, _) = listener.accept().await.unwrap();
+        let _ = handler.handle_client(ClientStream::plain(sock)).await;
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    /// an UPLOAD of `data` with its checksum
+    fn upload_request(bucket: &[u8; 16], key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut request = vec![0x01];
+        request.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        request.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        request.extend_from_slice(bucket);
+        request.extend_from_slice(key);
+        request.extend_from_slice(data);
+        request.extend_from_slice(&Sha256::digest(data));
+        request
+    }
+
+    /// a DOWNLOAD of the whole object
+    fn download_request(bucket: &[u8; 16], key: &[u8]) -> Vec<u8> {
+        let mut request = vec![0x02];
+        request.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        request.extend_from_slice(bucket);
+        request.extend_from_slice(key);
+        request
+    }
+
+    /// like `exchange`, returns the status and message
+    async fn round_trip(handler: &Arc<RequestHandler>, listener: &TcpListener, request: &[u8]) -> (u8, String) {
+        let response = exchange(handler, listener, request).await;
+        (response[0], String::from_utf8_lossy(response.get(5..).unwrap_or_default()).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_errors_reach_the_client() {
+        let handler = handler(AclMode::DefaultDeny, Arc::new(MemoryBlobStore::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        // another principal with the admin's key
+        let mut request = vec![0x07];
+        request.extend_from_slice(&9u32.to_be_bytes());
+        request.extend_from_slice(b"admin-key");
+        request.extend_from_slice(&[0x0A, 0]);
+        request.extend_from_slice(&5u32.to_be_bytes());
+        request.extend_from_slice(&9u32.to_be_bytes());
+        request.extend_from_slice(b"other");
+        request.extend_from_slice(b"admin-key");
+        let (status, _) = round_trip(&handler, &listener, &request).await;
+        assert_eq!(status, Status::Conflict as u8);
+
+        let (status, message) = round_trip(&handler, &listener, &[0x42]).await;
+        assert_eq!((status, message.as_str()), (Status::BadRequest as u8, "unknown command 0x42"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_open_bucket_closes_on_its_first_grant() {
+        let handler = handler(AclMode::Open, Arc::new(MemoryBlobStore::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        handler.metadata.upsert_principal("ci", &acl::hash_api_key(b"ci-key"), false).unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        assert_eq!(exchange(&handler, &listener, &upload_request(&bucket, b"k", b"data")).await, [Status::Ok as u8]);
+        assert_eq!(exchange(&handler, &listener, &download_request(&bucket, b"k")).await, b"\0data");
+
+        let mut grant = vec![0x07];
+        grant.extend_from_slice(&9u32.to_be_bytes());
+        grant.extend_from_slice(b"admin-key");
+        grant.push(0x08);
+        grant.extend_from_slice(&bucket);
+        grant.push(acl::READ);
+        grant.extend_from_slice(&2u32.to_be_bytes());
+        grant.extend_from_slice(b"ci");
+        assert_eq!(exchange(&handler, &listener, &grant).await, [Status::Ok as u8]);
+
+        // anonymous clients are out now, the principal granted read isn't
+        assert_eq!(round_trip(&handler, &listener, &download_request(&bucket, b"k")).await.0, Status::Denied as u8);
+        let mut download = vec![0x07];
+        download.extend_from_slice(&6u32.to_be_bytes());
+        download.extend_from_slice(b"ci-key");
+        download.extend_from_slice(&download_request(&bucket, b"k"));
+        assert_eq!(exchange(&handler, &listener, &download).await, b"\0data");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_body_larger_than_the_pipe() {
+        let handler = handler(AclMode::Open, Arc::new(MemoryBlobStore::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let data: Vec<u8> = (0..PIPE_SIZE * 3 + 17).map(|i| i as u8).collect();
+
+        let upload = upload_request(&bucket, b"big", &data);
+        assert_eq!(exchange(&handler, &listener, &upload).await, [Status::Ok as u8]);
+
+        let download = download_request(&bucket, b"big");
+        let response = exchange(&handler, &listener, &download).await;
+        assert_eq!(response[0], Status::Ok as u8);
+        assert!(response[1..] == data[..]);
+    }
+
+    #[tokio::test]
+    async fn test_ranged_download_from_files() {
+        let dir = TempDir::new("tcpfs-download").unwrap();
+        let handler = handler(AclMode::Open, Arc::new(FsBlobStore::open(dir.path()).unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let data = b"0123456789";
+
+        let upload = upload_request(&bucket, b"k", data);
+        assert_eq!(exchange(&handler, &listener, &upload).await, [Status::Ok as u8]);
+
+        // the length is clamped to the end of the object
+        for (offset, length, expected) in [(3u64, 4u64, &b"3456"[..]), (6, 100, b"6789"), (10, 1, b"")] {
+            let mut download = vec![0x0E];
+            download.extend_from_slice(&1u32.to_be_bytes());
+            download.extend_from_slice(&bucket);
+            download.extend_from_slice(&offset.to_be_bytes());
+            download.extend_from_slice(&length.to_be_bytes());
+            download.extend_from_slice(b"k");
+            let response = exchange(&handler, &listener, &download).await;
+            assert_eq!((response[0], &response[1..]), (Status::Ok as u8, expected));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compressed_transfers() {
+        let dir = TempDir::new("tcpfs-compression").unwrap();
+        let handler = handler(AclMode::Open, Arc::new(FsBlobStore::open(dir.path()).unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let data: Vec<u8> = b"{\"level\":\"info\",\"msg\":\"hello\"}\n".repeat(5000);
+        let compress = |codec, data: &[u8]| {
+            let mut encoder = compression::Encoder::new(codec, Vec::new()).unwrap();
+            io::Write::write_all(&mut encoder, data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let decompress = |codec, data: &[u8]| {
+            let mut decoded = Vec::new();
+            compression::decoder(codec, data).unwrap().read_to_end(&mut decoded).unwrap();
+            decoded
+        };
+
+        // the bucket keeps lz4
+        let mut option = vec![0x07];
+        option.extend_from_slice(&9u32.to_be_bytes());
+        option.extend_from_slice(b"admin-key");
+        option.push(0x0F);
+        option.extend_from_slice(&bucket);
+        option.extend_from_slice(&[0x02, Compression::Lz4 as u8]);
+        assert_eq!(exchange(&handler, &listener, &option).await, [Status::Ok as u8]);
+
+        // sent as zstd, the checksum is over the original bytes
+        let body = compress(Compression::Zstd, &data);
+        let mut upload = vec![0x81, Compression::Zstd as u8];
+        upload.extend_from_slice(&1u32.to_be_bytes());
+        upload.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        upload.extend_from_slice(&bucket);
+        upload.extend_from_slice(b"k");
+        upload.extend_from_slice(&body);
+        upload.extend_from_slice(&Sha256::digest(&data));
+        assert_eq!(exchange(&handler, &listener, &upload).await, [Status::Ok as u8]);
+        let bucket_id = uuid::Uuid::from_bytes(bucket).to_string();
+        let stored = handler.metadata.get_object(&bucket_id, "k").unwrap().unwrap();
+        assert_eq!((stored.object.file_size, stored.compression), (data.len() as i64, Compression::Lz4));
+
+        let mut download = download_request(&bucket, b"k");
+        let response = exchange(&handler, &listener, &download).await;
+        assert!(response[0] == Status::Ok as u8 && response[1..] == data[..]);
+
+        // as stored, and a range recompressed
+        download[0] = 0x82;
+        download.insert(1, Compression::Lz4 as u8);
+        let response = exchange(&handler, &listener, &download).await;
+        assert!(response[0] == Status::Ok as u8 && decompress(Compression::Lz4, &response[1..]) == data);
+
+        let mut ranged = vec![0x8E, Compression::Zstd as u8];
+        ranged.extend_from_slice(&1u32.to_be_bytes());
+        ranged.extend_from_slice(&bucket);
+        ranged.extend_from_slice(&1000u64.to_be_bytes());
+        ranged.extend_from_slice(&100u64.to_be_bytes());
+        ranged.extend_from_slice(b"k");
+        let response = exchange(&handler, &listener, &ranged).await;
+        assert!(response[0] == Status::Ok as u8 && decompress(Compression::Zstd, &response[1..]) == data[1000..1100]);
+
+        // a corrupt body is refused
+        let mut bad = upload.clone();
+        bad[28] ^= 0xFF;
+        assert_ne!(exchange(&handler, &listener, &bad).await[0], Status::Ok as u8);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_encrypted_at_rest() {
+        let handler = Arc::new(RequestHandler::new(HandlerConfig {
+            master_key: Some(MasterKey::from_bytes(&[7; 32]).unwrap()),
+            ..config(AclMode::Open, Arc::new(MemoryBlobStore::new()))
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let bucket_id = uuid::Uuid::from_bytes(bucket).to_string();
+        handler.metadata.set_bucket_encrypted(&bucket_id, true).unwrap();
+
+        for key in ["a", "b"] {
+            let upload = upload_request(&bucket, key.as_bytes(), key.as_bytes());
+            assert_eq!(exchange(&handler, &listener, &upload).await, [Status::Ok as u8]);
+            let response = exchange(&handler, &listener, &download_request(&bucket, key.as_bytes())).await;
+            assert_eq!(response, [&[Status::Ok as u8], key.as_bytes()].concat());
+        }
+
+        // a wrapped key only opens for the object it was made for
+        let a = handler.metadata.get_object(&bucket_id, "a").unwrap().unwrap();
+        let b = handler.metadata.get_object(&bucket_id, "b").unwrap().unwrap();
+        let master_key = handler.master_key.as_ref().unwrap();
+        assert!(master_key.unwrap_data_key(a.wrapped_key.as_ref().unwrap(), &a.object.path).is_ok());
+        assert!(master_key.unwrap_data_key(a.wrapped_key.as_ref().unwrap(), &b.object.path).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compressed_at_rest() {
+        let handler = handler(AclMode::Open, Arc::new(MemoryBlobStore::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let data: Vec<u8> = (0..300_000u32).flat_map(|i| format!("{:08}\n", i / 7).into_bytes()).collect();
+
+        let mut option = vec![0x07];
+        option.extend_from_slice(&9u32.to_be_bytes());
+        option.extend_from_slice(b"admin-key");
+        option.push(0x0F);
+        option.extend_from_slice(&bucket);
+        option.extend_from_slice(&[0x02, Compression::Zstd as u8]);
+        assert_eq!(exchange(&handler, &listener, &option).await, [Status::Ok as u8]);
+
+        let upload = upload_request(&bucket, b"k", &data);
+        assert_eq!(exchange(&handler, &listener, &upload).await, [Status::Ok as u8]);
+
+        // a range deep into the object comes out of the frame holding it
+        let offset = 2_500_000u64;
+        let mut ranged = vec![0x0E];
+        ranged.extend_from_slice(&1u32.to_be_bytes());
+        ranged.extend_from_slice(&bucket);
+        ranged.extend_from_slice(&offset.to_be_bytes());
+        ranged.extend_from_slice(&50u64.to_be_bytes());
+        ranged.extend_from_slice(b"k");
+        let response = exchange(&handler, &listener, &ranged).await;
+        assert!(response[0] == Status::Ok as u8 && response[1..] == data[offset as usize..offset as usize + 50]);
+
+        let mut stat = vec![0x10];
+        stat.extend_from_slice(&bucket);
+        let response = exchange(&handler, &listener, &stat).await;
+        let field = |i: usize| u64::from_be_bytes(response[1 + i * 8..9 + i * 8].try_into().unwrap());
+        assert_eq!((response.len(), field(0), field(2)), (33, data.len() as u64, data.len() as u64));
+        assert_eq!(field(1), field(3));
+        assert!(field(3) < data.len() as u64 / 10, "{}", field(3));
+    }
+
+    #[tokio::test]
+    async fn test_multipart_upload() {
+        let handler = handler(AclMode::Open, Arc::new(MemoryBlobStore::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let parts: [&[u8]; 3] = [&[1; 70_000], &[2; 70_000], b"tail"];
+
+        let mut start = vec![0x14];
+        start.extend_from_slice(&3u32.to_be_bytes());
+        start.extend_from_slice(&bucket);
+        start.extend_from_slice(b"big");
+        let response = exchange(&handler, &listener, &start).await;
+        assert_eq!(response[0], Status::Ok as u8);
+        let id = &response[1..17];
+
+        let part = |number: u32, data: &[u8], checksum: &[u8]| {
+            let mut request = vec![0x15];
+            request.extend_from_slice(id);
+            request.extend_from_slice(&number.to_be_bytes());
+            request.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            request.extend_from_slice(data);
+            request.extend_from_slice(checksum);
+            request
+        };
+        // in any order, a part that doesn't match its checksum is refused
+        for number in [3, 1, 2] {
+            let data = parts[number as usize - 1];
+            assert_eq!(exchange(&handler, &listener, &part(number, data, &Sha256::digest(data))).await, [Status::Ok as u8]);
+        }
+        let bad = part(4, b"junk", &[0; 32]);
+        assert_eq!(round_trip(&handler, &listener, &bad).await.0, Status::BadRequest as u8);
+
+        let download = download_request(&bucket, b"big");
+        // nothing to see until it is complete
+        assert_eq!(round_trip(&handler, &listener, &download).await.0, Status::NotFound as u8);
+
+        let mut complete = vec![0x16];
+        complete.extend_from_slice(id);
+        complete.extend_from_slice(&3u32.to_be_bytes());
+        for (number, data) in parts.iter().enumerate() {
+            complete.extend_from_slice(&(number as u32 + 1).to_be_bytes());
+            complete.extend_from_slice(&Sha256::digest(data));
+        }
+        let response = exchange(&handler, &listener, &complete).await;
+        assert_eq!(response, [&[Status::Ok as u8][..], &140_004u64.to_be_bytes()].concat());
+
+        let response = exchange(&handler, &listener, &download).await;
+        assert!(response[0] == Status::Ok as u8 && response[1..] == parts.concat()[..]);
+        // the upload is gone
+        assert_eq!(round_trip(&handler, &listener, &complete).await.0, Status::NotFound as u8);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_chunked_upload() {
+        let handler = Arc::new(RequestHandler::new(HandlerConfig {
+            bucket_quota: 100_000,
+            ..config(AclMode::Open, Arc::new(MemoryBlobStore::new()))
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let chunked = |key: &[u8], chunks: &[&[u8]], trailer: &[u8]| {
+            let mut request = vec![0x18];
+            request.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            request.extend_from_slice(&bucket);
+            request.extend_from_slice(key);
+            for chunk in chunks {
+                request.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+                request.extend_from_slice(chunk);
+            }
+            request.extend_from_slice(&0u32.to_be_bytes());
+            request.extend_from_slice(trailer);
+            request
+        };
+        let chunks: [&[u8]; 3] = [&[1; 30_000], &[2; 30_000], &[3; 30_000]];
+
+        // the checksum is optional, when it is sent it has to match
+        let checksum = [&[1][..], &Sha256::digest(chunks.concat())].concat();
+        assert_eq!(round_trip(&handler, &listener, &chunked(b"a", &chunks, &[1; 33])).await.0, Status::BadRequest as u8);
+        assert_eq!(exchange(&handler, &listener, &chunked(b"a", &chunks, &checksum)).await, [Status::Ok as u8]);
+        let download = download_request(&bucket, b"a");
+        let response = exchange(&handler, &listener, &download).await;
+        assert!(response[0] == Status::Ok as u8 && response[1..] == chunks.concat()[..]);
+        // a second upload of a key adds an object beside the first and both count
+        assert_eq!(round_trip(&handler, &listener, &chunked(b"a", &chunks, &[0])).await.0, Status::QuotaExceeded as u8);
+
+        // 10_000 bytes left, a known length is refused before the body is read
+        let data = [4; 20_000];
+        let upload = upload_request(&bucket, b"b", &data);
+        assert_eq!(round_trip(&handler, &listener, &upload).await.0, Status::QuotaExceeded as u8);
+
+        // and a chunked one as soon as it goes over, the rest of the body is never read
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(&chunked(b"b", &[&data[..8_000], &data[8_000..]], &[0])).await.unwrap();
+        let (sock, _) = listener.accept().await.unwrap();
+        let error = handler.handle_client(ClientStream::plain(sock)).await.err().unwrap();
+        assert_eq!(Status::of(error.as_ref()), Some(Status::QuotaExceeded));
+        assert_eq!(round_trip(&handler, &listener, &download_request(&bucket, b"b")).await.0, Status::NotFound as u8);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_uploads_share_the_quota() {
+        let handler = Arc::new(RequestHandler::new(HandlerConfig {
+            bucket_quota: 20,
+            ..config(AclMode::Open, Arc::new(MemoryBlobStore::new()))
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let bucket_id = uuid::Uuid::from_bytes(bucket).to_string();
+
+        // the first upload's body is still on its way while the second one comes in
+        let first = upload_request(&bucket, b"a", &[1; 15]);
+        let (header, body) = first.split_at(1 + 4 + 4 + 16 + 1);
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(header).await.unwrap();
+        let (sock, _) = listener.accept().await.unwrap();
+        let serving = tokio::spawn({
+            let handler = handler.clone();
+            async move { handler.handle_client(ClientStream::plain(sock)).await.unwrap() }
+        });
+        while handler.quota.reserved(&bucket_id) < 15 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let second = upload_request(&bucket, b"b", &[2; 10]);
+        assert_eq!(round_trip(&handler, &listener, &second).await.0, Status::QuotaExceeded as u8);
+
+        client.write_all(body).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        serving.await.unwrap();
+        assert_eq!(response, [Status::Ok as u8]);
+        assert_eq!((handler.metadata.get_bucket_size(&bucket_id).unwrap(), handler.quota.reserved(&bucket_id)), (15, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_staged_parts_count_against_the_quota() {
+        let handler = Arc::new(RequestHandler::new(HandlerConfig {
+            bucket_quota: 100_000,
+            ..config(AclMode::Open, Arc::new(MemoryBlobStore::new()))
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+
+        let mut start = vec![0x14];
+        start.extend_from_slice(&3u32.to_be_bytes());
+        start.extend_from_slice(&bucket);
+        start.extend_from_slice(b"big");
+        let response = exchange(&handler, &listener, &start).await;
+        let id = &response[1..17];
+        let part = |number: u32, data: &[u8]| {
+            let mut request = vec![0x15];
+            request.extend_from_slice(id);
+            request.extend_from_slice(&number.to_be_bytes());
+            request.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            request.extend_from_slice(data);
+            request.extend_from_slice(&Sha256::digest(data));
+            request
+        };
+        let parts: [&[u8]; 2] = [&[1; 60_000], &[2; 40_000]];
+        assert_eq!(exchange(&handler, &listener, &part(1, parts[0])).await, [Status::Ok as u8]);
+
+        // what is staged is taken, for other parts and for plain uploads alike
+        assert_eq!(round_trip(&handler, &listener, &part(2, &[2; 50_000])).await.0, Status::QuotaExceeded as u8);
+        let data = [3; 50_000];
+        let upload = upload_request(&bucket, b"b", &data);
+        assert_eq!(round_trip(&handler, &listener, &upload).await.0, Status::QuotaExceeded as u8);
+
+        // the parts fill the bucket to the byte and don't count twice when they become the object
+        assert_eq!(exchange(&handler, &listener, &part(2, parts[1])).await, [Status::Ok as u8]);
+        let mut complete = vec![0x16];
+        complete.extend_from_slice(id);
+        complete.extend_from_slice(&2u32.to_be_bytes());
+        for (number, data) in parts.iter().enumerate() {
+            complete.extend_from_slice(&(number as u32 + 1).to_be_bytes());
+            complete.extend_from_slice(&Sha256::digest(data));
+        }
+        let response = exchange(&handler, &listener, &complete).await;
+        assert_eq!(response, [&[Status::Ok as u8][..], &100_000u64.to_be_bytes()].concat());
+        assert_eq!(handler.quota.reserved(&uuid::Uuid::from_bytes(bucket).to_string()), 0);
+        // the object holds the bucket's room from here on
+        let upload = upload_request(&bucket, b"b", &[3]);
+        assert_eq!(round_trip(&handler, &listener, &upload).await.0, Status::QuotaExceeded as u8);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_append() {
+        // packed to start with, the first append moves it to a blob of its own
+        let handler = Arc::new(RequestHandler::new(HandlerConfig {
+            pack_threshold: 1024,
+            ..config(AclMode::Open, Arc::new(MemoryBlobStore::new()))
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let append = |expected: u64, data: &[u8], checksum: &[u8]| {
+            let mut request = vec![0x19];
+            request.extend_from_slice(&3u32.to_be_bytes());
+            request.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            request.extend_from_slice(&bucket);
+            request.extend_from_slice(&expected.to_be_bytes());
+            request.extend_from_slice(b"log");
+            request.extend_from_slice(data);
+            request.extend_from_slice(checksum);
+            request
+        };
+        let ok = |size: u64| [&[Status::Ok as u8][..], &size.to_be_bytes()].concat();
+
+        // a missing key is created
+        assert_eq!(round_trip(&handler, &listener, &append(6, b"hello ", &Sha256::digest(b"hello "))).await.0, Status::NotFound as u8);
+        assert_eq!(exchange(&handler, &listener, &append(0, b"hello ", &Sha256::digest(b"hello "))).await, ok(6));
+        assert_eq!(exchange(&handler, &listener, &append(6, b"world", &Sha256::digest(b"world"))).await, ok(11));
+        // a writer that is behind is refused, one that doesn't care isn't
+        let (status, message) = round_trip(&handler, &listener, &append(6, b"!", &Sha256::digest(b"!"))).await;
+        assert_eq!((status, message.as_str()), (Status::Conflict as u8, "object is 11 bytes, not 6"));
+        assert_eq!(round_trip(&handler, &listener, &append(11, b"!", &[0; 32])).await.0, Status::BadRequest as u8);
+        assert_eq!(exchange(&handler, &listener, &append(u64::MAX, b"!", &Sha256::digest(b"!"))).await, ok(12));
+
+        let download = download_request(&bucket, b"log");
+        assert_eq!(exchange(&handler, &listener, &download).await, b"\0hello world!");
+        let bucket_id = uuid::Uuid::from_bytes(bucket).to_string();
+        assert_eq!(handler.metadata.get_usage(Some(&bucket_id)).unwrap().logical, 12);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_appends_share_the_quota() {
+        let handler = Arc::new(RequestHandler::new(HandlerConfig {
+            bucket_quota: 20,
+            ..config(AclMode::Open, Arc::new(MemoryBlobStore::new()))
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let bucket_id = uuid::Uuid::from_bytes(bucket).to_string();
+        let append = |key: &[u8], data: &[u8]| {
+            let mut request = vec![0x19];
+            request.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            request.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            request.extend_from_slice(&bucket);
+            request.extend_from_slice(&u64::MAX.to_be_bytes());
+            request.extend_from_slice(key);
+            request.extend_from_slice(data);
+            request.extend_from_slice(&Sha256::digest(data));
+            request
+        };
+
+        // both would fit on their own, the second comes in while the first one's data is on its way
+        let first = append(b"a", &[1; 15]);
+        let (header, body) = first.split_at(1 + 32 + 1);
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(header).await.unwrap();
+        let (sock, _) = listener.accept().await.unwrap();
+        let serving = tokio::spawn({
+            let handler = handler.clone();
+            async move { handler.handle_client(ClientStream::plain(sock)).await.unwrap() }
+        });
+        while handler.quota.reserved(&bucket_id) < 15 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(round_trip(&handler, &listener, &append(b"b", &[2; 10])).await.0, Status::QuotaExceeded as u8);
+
+        client.write_all(body).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        serving.await.unwrap();
+        assert_eq!(response, [&[Status::Ok as u8][..], &15u64.to_be_bytes()].concat());
+        assert_eq!((handler.metadata.get_bucket_size(&bucket_id).unwrap(), handler.quota.reserved(&bucket_id)), (15, 0));
+        // what is left is there to take
+        assert_eq!(round_trip(&handler, &listener, &append(b"b", &[2; 5])).await.0, Status::Ok as u8);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits() {
+        let handler = handler(AclMode::Open, Arc::new(MemoryBlobStore::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bucket = uuid::Uuid::new_v4().as_u128().to_be_bytes();
+        let mut auth = vec![0x07];
+        auth.extend_from_slice(&9u32.to_be_bytes());
+        auth.extend_from_slice(b"admin-key");
+
+        let mut set = auth.clone();
+        set.extend_from_slice(&[0x12, Scope::Bucket as u8]);
+        set.extend_from_slice(&bucket);
+        set.extend_from_slice(&40_000u64.to_be_bytes());
+        set.extend_from_slice(&0u64.to_be_bytes());
+        set.extend_from_slice(&0u32.to_be_bytes());
+        assert_eq!(exchange(&handler, &listener, &set).await, [Status::Ok as u8]);
+        // not for anyone but admins
+        assert_eq!(round_trip(&handler, &listener, &set[auth.len()..]).await.0, Status::Denied as u8);
+
+        let mut list = auth.clone();
+        list.push(0x13);
+        let response = exchange(&handler, &listener, &list).await;
+        assert_eq!(response[..5], [Status::Ok as u8, 0, 0, 0, 4]);
+        // the defaults, then the bucket
+        let limit = &response[5 + 3 * 37..];
+        assert_eq!(limit[0], Scope::Bucket as u8);
+        assert_eq!(limit[1..17], bucket);
+        assert_eq!(limit[17..25], 40_000u64.to_be_bytes());
+
+        // a second's worth is free, the rest is paid for by waiting
+        let data = vec![7; 60 * 1024];
+        let upload = upload_request(&bucket, b"k", &data);
+        let started = std::time::Instant::now();
+        assert_eq!(exchange(&handler, &listener, &upload).await, [Status::Ok as u8]);
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_slow_clients_are_closed() {
+        let handler = handler(AclMode::Open, Arc::new(MemoryBlobStore::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let timeouts = timeouts::Timeouts {
+            idle: Duration::from_millis(100),
+            header: Duration::from_millis(100),
+            min_rate: 1000,
+            grace: Duration::from_millis(100),
+        };
+
+        let upload = upload_request(&uuid::Uuid::new_v4().as_u128().to_be_bytes(), b"k", &[0; 100_000]);
+        // nothing, half a header, a body that stalls after 10 bytes
+        for request in [&[][..], &upload[..3], &upload[..36]] {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            client.write_all(request).await.unwrap();
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut stream = ClientStream::plain(sock);
+            stream.set_timeouts(timeouts);
+            let started = std::time::Instant::now();
+            handler.handle_client(stream).await.unwrap();
+            assert!(started.elapsed() < Duration::from_secs(5));
+
+            // closed without an answer, the client is still connected
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert!(response.is_empty());
+        }
+    }
 }
@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone)]
+pub struct LimitConfig {
+    /// connections served at once, the next ones wait for a slot
+    pub max_connections: usize,
+    /// connections from one address, served and waiting together
+    pub max_per_ip: usize,
+    /// connections waiting for a slot, anything beyond that is turned away as busy
+    pub max_queued: usize,
+    /// what a busy client is told to wait before trying again
+    pub retry_after: Duration,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        LimitConfig {
+            max_connections: 1024,
+            max_per_ip: 64,
+            max_queued: 256,
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Default)]
+struct LimitState {
+    queued: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Decides which accepted connections get served, so one client (or a flood of them)
+/// can't take every worker and file descriptor
+pub struct ConnectionLimits {
+    config: LimitConfig,
+    active: Arc<Semaphore>,
+    state: Mutex<LimitState>,
+}
+
+impl ConnectionLimits {
+    pub fn new(config: LimitConfig) -> Arc<ConnectionLimits> {
+        Arc::new(ConnectionLimits {
+            active: Arc::new(Semaphore::new(config.max_connections)),
+            config,
+            state: Mutex::new(LimitState::default()),
+        })
+    }
+
+    pub fn retry_after(&self) -> Duration {
+        self.config.retry_after
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LimitState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait for a slot for a connection from `ip`, None when the client should be told to come back later.
+    /// the slot is given back when dropped
+    pub async fn admit(self: &Arc<Self>, ip: IpAddr) -> Option<Slot> {
+        let mut slot = {
+            let mut state = self.lock();
+            let count = state.per_ip.entry(ip).or_insert(0);
+            if *count >= self.config.max_per_ip {
+                return None;
+            }
+            *count += 1;
+            Slot { limits: self.clone(), ip, _permit: None }
+        };
+
+        let permit = match self.active.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let _queued = {
+                    let mut state = self.lock();
+                    if state.queued >= self.config.max_queued {
+                        return None;
+                    }
+                    state.queued += 1;
+                    Queued(self)
+                };
+                // the semaphore is never closed
+                self.active.clone().acquire_owned().await.ok()?
+            }
+        };
+        slot._permit = Some(permit);
+        Some(slot)
+    }
+}
+
+/// A connection being served, counted against its address until dropped
+pub struct Slot {
+    limits: Arc<ConnectionLimits>,
+    ip: IpAddr,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = self.limits.lock();
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// counts a connection waiting in `admit`, also when the wait is abandoned
+struct Queued<'a>(&'a ConnectionLimits);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.lock().queued -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_admission() {
+        let limits = ConnectionLimits::new(LimitConfig {
+            max_connections: 2,
+            max_per_ip: 2,
+            max_queued: 1,
+            retry_after: Duration::from_millis(10),
+        });
+        let (a, b, c): (IpAddr, IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), "10.0.0.3".parse().unwrap());
+
+        let first = limits.admit(a).await.unwrap();
+        let _second = limits.admit(a).await.unwrap();
+        // a third from the same address is refused straight away
+        assert!(limits.admit(a).await.is_none());
+
+        // every slot is taken, one more may wait for a free one
+        let waiting = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.admit(b).await.is_some() }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(limits.lock().queued, 1);
+        assert!(limits.admit(c).await.is_none());
+
+        drop(first);
+        assert!(waiting.await.unwrap());
+        let state = limits.lock();
+        assert_eq!((state.queued, state.per_ip.get(&a), state.per_ip.get(&b)), (0, Some(&1), None));
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use meta_store::TcpfsError;
+
+use crate::{quota::Reservation, store::StagedBlob};
+
+/*
+MULTIPART UPLOADS:
+a large object goes up in parts over as many connections as the client likes:
+    INITIATE  reserves an upload id for (bucket, key)
+    PART      stages one numbered part, checked against its own length and SHA-256, sending a number again replaces it
+    COMPLETE  names the parts (and their checksums) that make up the object, in order
+    ABORT     drops the upload and its parts
+parts are staged blobs that never get persisted, an aborted or abandoned upload leaves nothing behind
+(and what a crash leaves in the staging area is swept on startup). COMPLETE stitches the parts into one
+blob through the same path as an UPLOAD body (compression, encryption, packing, dedup) and commits the
+metadata last, so the object shows up whole or not at all. the upload is only dropped once that worked,
+a COMPLETE that fails puts the parts back and can be retried. each part holds a reservation of its length
+against the bucket's quota (see `quota`) from before its body is read until the part is gone, the listed
+ones until the object they make up is committed, so COMPLETE has nothing left to check. the registry lives
+in memory, uploads don't survive a restart and ones left alone for EXPIRY are dropped
+*/
+
+/// part numbers run from 1 to this
+pub const MAX_PARTS: u32 = 10_000;
+
+/// uploads nobody touched for this long are dropped
+pub const EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct Part {
+    pub staged: Box<dyn StagedBlob>,
+    pub checksum: [u8; 32],
+    pub size: u64,
+    /// its length, held in the bucket for as long as the part is around
+    pub reservation: Reservation,
+}
+
+struct Upload {
+    bucket_id: String,
+    key: String,
+    parts: BTreeMap<u32, Part>,
+    touched: Instant,
+    /// the listed parts are out being stitched, see `complete`
+    completing: bool,
+}
+
+#[derive(Default)]
+pub struct Uploads {
+    uploads: Mutex<HashMap<u128, Upload>>,
+}
+
+impl Uploads {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u128, Upload>> {
+        self.uploads.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// registers a new upload, dropping expired ones on the way
+    pub fn start(&self, bucket_id: &str, key: &str) -> u128 {
+        let id = uuid::Uuid::new_v4().as_u128();
+        let expired: Vec<Upload> = {
+            let mut uploads = self.lock();
+            let stale: Vec<u128> = uploads
+                .iter()
+                .filter(|(_, u)| !u.completing && u.touched.elapsed() > EXPIRY)
+                .map(|(id, _)| *id)
+                .collect();
+            let expired = stale.iter().filter_map(|id| uploads.remove(id)).collect();
+            uploads.insert(
+                id,
+                Upload {
+                    bucket_id: bucket_id.to_string(),
+                    key: key.to_string(),
+                    parts: BTreeMap::new(),
+                    touched: Instant::now(),
+                    completing: false,
+                },
+            );
+            expired
+        };
+        // dropping the parts deletes their staged files, not while holding the lock
+        drop(expired);
+        id
+    }
+
+    /// (bucket, key) of the upload, None if there is no such upload
+    pub fn target(&self, id: u128) -> Option<(String, String)> {
+        self.lock().get(&id).map(|u| (u.bucket_id.clone(), u.key.clone()))
+    }
+
+    /// fails if the upload is gone (completed, aborted or expired) or being completed, the part is dropped then
+    pub fn add_part(&self, id: u128, number: u32, part: Part) -> Result<(), TcpfsError> {
+        let replaced = {
+            let mut uploads = self.lock();
+            match uploads.get_mut(&id) {
+                Some(upload) if upload.completing => return Err(TcpfsError::Conflict("upload is being completed".to_string())),
+                Some(upload) => {
+                    upload.touched = Instant::now();
+                    upload.parts.insert(number, part)
+                }
+                None => return Err(TcpfsError::NotFound("no such upload".to_string())),
+            }
+        };
+        drop(replaced);
+        Ok(())
+    }
+
+    /// Takes the listed parts out if `listed` (number, checksum) matches the parts the upload has.
+    /// the upload stays until `completed`, or gets them back with `restore` if stitching fails
+    pub fn complete(&self, id: u128, listed: &[(u32, [u8; 32])]) -> Result<Vec<Part>, TcpfsError> {
+        let refused = |message: &str| Err(TcpfsError::Protocol(message.to_string()));
+        let mut uploads = self.lock();
+        let upload = uploads.get_mut(&id).ok_or_else(|| TcpfsError::NotFound("no such upload".to_string()))?;
+        if upload.completing {
+            return Err(TcpfsError::Conflict("upload is being completed".to_string()));
+        }
+        if listed.is_empty() {
+            return refused("no parts listed");
+        }
+        if listed.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return refused("parts have to be listed in ascending order");
+        }
+        for (number, checksum) in listed {
+            match upload.parts.get(number) {
+                Some(part) if part.checksum == *checksum => {}
+                Some(_) => return refused(&format!("part {} checksum mismatch", number)),
+                None => return refused(&format!("part {} missing", number)),
+            }
+        }
+        upload.completing = true;
+        upload.touched = Instant::now();
+        Ok(listed.iter().map(|(number, _)| upload.parts.remove(number).unwrap()).collect())
+    }
+
+    /// the object is committed, parts that were not listed are dropped with the upload
+    pub fn completed(&self, id: u128) {
+        let upload = self.lock().remove(&id);
+        drop(upload);
+    }
+
+    /// puts the parts `complete` handed out back, the upload can be completed again
+    pub fn restore(&self, id: u128, listed: &[(u32, [u8; 32])], parts: Vec<Part>) {
+        let mut uploads = self.lock();
+        // aborted in the meantime, the parts go
+        let Some(upload) = uploads.get_mut(&id) else { return };
+        upload.completing = false;
+        upload.touched = Instant::now();
+        for ((number, _), part) in listed.iter().zip(parts) {
+            upload.parts.insert(*number, part);
+        }
+    }
+
+    pub fn abort(&self, id: u128) -> bool {
+        let upload = self.lock().remove(&id);
+        upload.is_some()
+    }
+}
+
+/// The parts read back one after the other, as one body
+pub struct Concat<'a> {
+    readers: std::vec::IntoIter<Box<dyn Read + Send + 'a>>,
+    current: Option<Box<dyn Read + Send + 'a>>,
+}
+
+impl<'a> Concat<'a> {
+    pub fn new(parts: &'a mut [Part]) -> io::Result<Concat<'a>> {
+        let readers = parts.iter_mut().map(|part| part.staged.reader()).collect::<io::Result<Vec<_>>>()?;
+        Ok(Concat { readers: readers.into_iter(), current: None })
+    }
+}
+
+impl Read for Concat<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let reader = match &mut self.current {
+                Some(reader) => reader,
+                None => match self.readers.next() {
+                    Some(reader) => self.current.insert(reader),
+                    None => return Ok(0),
+                },
+            };
+            match reader.read(buf)? {
+                0 if !buf.is_empty() => self.current = None,
+                n => return Ok(n),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::Arc};
+
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::{
+        quota::Quota,
+        store::{BlobStore, MemoryBlobStore},
+    };
+
+    fn part(store: &MemoryBlobStore, quota: &Arc<Quota>, data: &[u8]) -> Part {
+        let mut staged = store.stage().unwrap();
+        staged.write_all(data).unwrap();
+        let reservation = quota.reserve("b", data.len() as u64).unwrap();
+        Part { staged, checksum: Sha256::digest(data).into(), size: data.len() as u64, reservation }
+    }
+
+    #[test]
+    fn test_uploads() {
+        let store = MemoryBlobStore::new();
+        let quota = Quota::new(100, Arc::new(meta_sqlite::SqliteMetadataStore::open(None).unwrap()));
+        let part = |data: &[u8]| part(&store, &quota, data);
+        let uploads = Uploads::default();
+        let id = uploads.start("b", "big");
+        assert_eq!(uploads.target(id), Some(("b".to_string(), "big".to_string())));
+
+        // out of order, 2 is sent twice and 3 is left out of the object
+        for (number, data) in [(2, &b"stale"[..]), (1, b"hello "), (2, b"world"), (3, b"!")] {
+            uploads.add_part(id, number, part(data)).unwrap();
+        }
+        // a part holds its length in the bucket, the one it replaced gave it back
+        assert_eq!(quota.reserved("b"), 12);
+
+        let sum = |data: &[u8]| -> [u8; 32] { Sha256::digest(data).into() };
+        let refused = |listed: &[(u32, [u8; 32])]| uploads.complete(id, listed).err().unwrap().to_string();
+        assert_eq!(refused(&[(1, sum(b"hello ")), (2, sum(b"stale"))]), "part 2 checksum mismatch");
+        assert_eq!(refused(&[(2, sum(b"world")), (1, sum(b"hello "))]), "parts have to be listed in ascending order");
+        assert_eq!(refused(&[(1, sum(b"hello ")), (4, sum(b"!"))]), "part 4 missing");
+
+        let listed = [(1, sum(b"hello ")), (2, sum(b"world"))];
+        let mut parts = uploads.complete(id, &listed).unwrap();
+        let mut body = Vec::new();
+        Concat::new(&mut parts).unwrap().read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"hello world");
+        // nothing changes while the parts are out
+        assert!(matches!(uploads.complete(id, &listed), Err(TcpfsError::Conflict(_))));
+        assert!(matches!(uploads.add_part(id, 4, part(b"late")), Err(TcpfsError::Conflict(_))));
+        assert_eq!(quota.reserved("b"), 12);
+
+        // stitching failed, they can be listed again
+        uploads.restore(id, &listed, parts);
+        let mut parts = uploads.complete(id, &listed).unwrap();
+        body.clear();
+        Concat::new(&mut parts).unwrap().read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"hello world");
+
+        // gone once completed
+        uploads.completed(id);
+        assert!(uploads.target(id).is_none());
+        assert_eq!(quota.reserved("b"), 11);
+        drop(parts);
+        assert_eq!(quota.reserved("b"), 0);
+        assert!(matches!(uploads.add_part(id, 4, part(b"late")), Err(TcpfsError::NotFound(_))));
+        assert!(!uploads.abort(id));
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use meta_store::MetadataStore;
+
+/*
+BLOB LAYOUT (locations within the BlobStore):
+<bucket_id>/<ab>/<cd>/<blob id>.data
+the blob id is a random uuid (simple form), ab and cd are its first four hex characters so
+a bucket fans out over 65536 directories instead of piling everything into one.
+blobs used to live at working_dir/<bucket_id>/<y>/<m>/<d>/<h>/<min>/<s>/<ns>/file.data,
+two uploads landing in the same nanosecond would overwrite each other, see `migrate_legacy_blobs`
+
+with deduplication on blobs are shared between buckets and named by their sha256:
+.blobs/<ab>/<cd>/<sha256 hex>
+the object row keeps a fresh (never written) location from `new_blob_location` so
+(bucket_id, path) stays unique, object_blobs points it at the shared blob
+*/
+
+const LEGACY_FILE_NAME: &str = "file.data";
+pub const BLOB_STORE_DIR: &str = ".blobs";
+
+pub fn new_blob_location(bucket_id: &str) -> String {
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    format!("{}/{}/{}/{}.data", bucket_id, &id[0..2], &id[2..4], id)
+}
+
+/// hex digest and location of a content addressed blob
+pub fn content_address(digest: &[u8]) -> (String, String) {
+    let hash: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    let location = format!("{}/{}/{}/{}", BLOB_STORE_DIR, &hash[0..2], &hash[2..4], hash);
+    (hash, location)
+}
+
+pub fn is_legacy_path(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == LEGACY_FILE_NAME)
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub moved: usize,
+    /// rows pointing at a timestamp path that doesn't exist on disk, left untouched
+    pub missing: usize,
+}
+
+/// Move every blob still at a timestamp path into the fan-out layout and update the object's path.
+/// Only for the filesystem store, `working_dir` is its root.
+/// The blob is hard linked first, the row updated, then the old name removed, so a crash at any
+/// point leaves the row pointing at a file that exists. Safe to run again after an interruption.
+pub fn migrate_legacy_blobs(
+    metadata: &dyn MetadataStore,
+    working_dir: &Path,
+    dry_run: bool,
+) -> Result<MigrationReport, Box<dyn Error>> {
+    let mut report = MigrationReport::default();
+    for obj in metadata.get_all_objects()? {
+        let old = PathBuf::from(&obj.path);
+        if !is_legacy_path(&old) {
+            continue;
+        }
+        if !old.is_file() {
+            eprintln!("{} (object {}) is missing, skipping", obj.path, obj.id);
+            report.missing += 1;
+            continue;
+        }
+
+        let location = new_blob_location(&obj.bucket_id);
+        let new = working_dir.join(&location);
+        println!("{} -> {}", old.display(), new.display());
+        if dry_run {
+            report.moved += 1;
+            continue;
+        }
+
+        fs::create_dir_all(new.parent().unwrap())?;
+        fs::hard_link(&old, &new)?;
+        metadata.update_object_path(obj.id.into(), &location)?;
+        fs::remove_file(&old)?;
+        remove_empty_parents(&old, &working_dir.join(&obj.bucket_id));
+        report.moved += 1;
+    }
+    Ok(report)
+}
+
+/// clean up the now empty timestamp directories, stopping at `stop`
+fn remove_empty_parents(path: &Path, stop: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == stop || !d.starts_with(stop) || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use meta_store::{Compression, NewObject, Placement};
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_new_blob_locations_are_unique() {
+        let a = new_blob_location("bucket");
+        let b = new_blob_location("bucket");
+        assert_ne!(a, b);
+        assert!(a.starts_with("bucket/"));
+        assert_eq!(Path::new(&a).components().count(), 4);
+        assert!(!is_legacy_path(Path::new(&a)));
+
+        let (hash, location) = content_address(&[0xab, 0xcd, 0x01]);
+        assert_eq!(hash, "abcd01");
+        assert_eq!(location, ".blobs/ab/cd/abcd01");
+    }
+
+    #[test]
+    fn test_migrate_legacy_blobs() {
+        let dir = TempDir::new("tcpfs-placement").unwrap();
+        let working_dir = dir.path().join("file_store");
+        let metadata = meta_sqlite::SqliteMetadataStore::open(None).unwrap();
+
+        let legacy = working_dir.join("bucket/2024/1/2/3/4/5/678/file.data");
+        fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        fs::write(&legacy, b"old blob").unwrap();
+        for (key, path) in [("a", legacy.to_str().unwrap()), ("gone", "/nowhere/file.data")] {
+            metadata
+                .insert_object(&NewObject {
+                    bucket_id: "bucket",
+                    key,
+                    path,
+                    size: 8,
+                    created_at: "2024-01-02T03:04:05+00:00",
+                    wrapped_key: None,
+                    compression: Compression::None,
+                    stored_size: 8,
+                    placement: Placement::Own,
+                })
+                .unwrap();
+        }
+
+        let report = migrate_legacy_blobs(&metadata, &working_dir, true).unwrap();
+        assert_eq!(report, MigrationReport { moved: 1, missing: 1 });
+        assert!(legacy.is_file());
+
+        let report = migrate_legacy_blobs(&metadata, &working_dir, false).unwrap();
+        assert_eq!(report, MigrationReport { moved: 1, missing: 1 });
+        let obj = metadata.get_object("bucket", "a").unwrap().unwrap().object;
+        assert!(!is_legacy_path(Path::new(&obj.path)));
+        assert_eq!(fs::read(working_dir.join(&obj.path)).unwrap(), b"old blob");
+        assert!(!legacy.exists());
+        assert!(!working_dir.join("bucket/2024").exists());
+
+        // nothing left to do
+        let report = migrate_legacy_blobs(&metadata, &working_dir, false).unwrap();
+        assert_eq!(report.moved, 0);
+    }
+}
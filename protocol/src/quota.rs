@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use meta_store::{MetadataStore, TcpfsError};
+
+/*
+BUCKET QUOTA:
+a bucket may hold TCPFS_BUCKET_QUOTA logical bytes. what its objects take comes from the metadata, bytes
+on their way in are held here: a writer reserves what it is about to add before the body is read and
+keeps the reservation until its object is committed (or it failed), then the metadata counts them. a
+body of unknown size reserves as it comes in and is refused as soon as the bucket can't take more.
+the check reads the stored size and the reservations under one lock, so writers racing into the same
+bucket can't each see the same room. a reservation is counted next to the object it turned into for
+a moment after the commit, that only ever errs on the full side
+*/
+
+/// reserved ahead of a body of unknown size at a time, so the metadata isn't asked on every read
+const RESERVE_AHEAD: u64 = 1024 * 1024;
+
+pub struct Quota {
+    /// logical bytes a bucket may hold, 0 is unlimited
+    limit: u64,
+    metadata: Arc<dyn MetadataStore>,
+    /// bytes held by writers, by bucket
+    reserved: Mutex<HashMap<String, u64>>,
+}
+
+impl Quota {
+    pub fn new(limit: u64, metadata: Arc<dyn MetadataStore>) -> Arc<Quota> {
+        Arc::new(Quota { limit, metadata, reserved: Mutex::new(HashMap::new()) })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+        self.reserved.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// how much more the bucket may take, None without a quota.
+    /// every object counts, uploading a key that exists adds another object next to it
+    pub fn room(&self, bucket_id: &str) -> meta_store::Result<Option<u64>> {
+        if self.limit == 0 {
+            return Ok(None);
+        }
+        let reserved = self.lock();
+        Ok(Some(self.room_in(&reserved, bucket_id)?))
+    }
+
+    fn room_in(&self, reserved: &HashMap<String, u64>, bucket_id: &str) -> meta_store::Result<u64> {
+        let taken = self.metadata.get_bucket_size(bucket_id)? + reserved.get(bucket_id).copied().unwrap_or(0);
+        Ok(self.limit.saturating_sub(taken))
+    }
+
+    /// Holds `bytes` in the bucket until the returned reservation is dropped, refused with a Quota
+    /// error if they don't fit. blocks on the metadata, call it on the blocking pool
+    pub fn reserve(self: &Arc<Self>, bucket_id: &str, bytes: u64) -> meta_store::Result<Reservation> {
+        let mut reservation = Reservation { quota: None, bucket_id: bucket_id.to_string(), bytes: 0 };
+        if self.limit > 0 {
+            reservation.quota = Some(self.clone());
+            self.add(&mut reservation, bytes)?;
+        }
+        Ok(reservation)
+    }
+
+    fn add(&self, reservation: &mut Reservation, bytes: u64) -> meta_store::Result<()> {
+        let mut reserved = self.lock();
+        if bytes > self.room_in(&reserved, &reservation.bucket_id)? {
+            return Err(TcpfsError::Quota("bucket quota exceeded".to_string()));
+        }
+        *reserved.entry(reservation.bucket_id.clone()).or_default() += bytes;
+        reservation.bytes += bytes;
+        Ok(())
+    }
+
+    /// bytes held in the bucket right now
+    pub fn reserved(&self, bucket_id: &str) -> u64 {
+        self.lock().get(bucket_id).copied().unwrap_or(0)
+    }
+}
+
+/// Bytes held in a bucket for a writer, given back when dropped
+pub struct Reservation {
+    /// None without a quota, nothing is held then
+    quota: Option<Arc<Quota>>,
+    bucket_id: String,
+    bytes: u64,
+}
+
+impl Reservation {
+    /// holds nothing, for bytes that are accounted for elsewhere
+    pub fn none() -> Reservation {
+        Reservation { quota: None, bucket_id: String::new(), bytes: 0 }
+    }
+
+    /// holds `bytes` more, refused with a Quota error if they don't fit
+    pub fn grow(&mut self, bytes: u64) -> meta_store::Result<()> {
+        match self.quota.clone() {
+            Some(quota) => quota.add(self, bytes),
+            None => {
+                self.bytes = self.bytes.saturating_add(bytes);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let Some(quota) = &self.quota else { return };
+        let mut reserved = quota.lock();
+        if let Some(held) = reserved.get_mut(&self.bucket_id) {
+            *held -= self.bytes;
+            if *held == 0 {
+                reserved.remove(&self.bucket_id);
+            }
+        }
+    }
+}
+
+/// Reads through `inner` and grows `reservation` to cover what came through, a read the bucket has
+/// no room for fails with ErrorKind::QuotaExceeded
+pub struct Reserving<'a, R: Read> {
+    inner: R,
+    reservation: &'a mut Reservation,
+    count: u64,
+}
+
+impl<'a, R: Read> Reserving<'a, R> {
+    pub fn new(inner: R, reservation: &'a mut Reservation) -> Self {
+        Reserving { inner, reservation, count: 0 }
+    }
+}
+
+impl<R: Read> Read for Reserving<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        if self.count > self.reservation.bytes {
+            let short = self.count - self.reservation.bytes;
+            // ahead if there is room for it, what is needed if there isn't
+            let grown = self.reservation.grow(short.max(RESERVE_AHEAD)).or_else(|_| self.reservation.grow(short));
+            match grown {
+                Ok(()) => {}
+                Err(TcpfsError::Quota(message)) => return Err(io::Error::new(ErrorKind::QuotaExceeded, message)),
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use meta_store::{NewObject, Placement};
+
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let metadata = Arc::new(meta_sqlite::SqliteMetadataStore::open(None).unwrap());
+        metadata
+            .insert_object(&NewObject {
+                bucket_id: "b",
+                key: "k",
+                path: "b/k",
+                size: 5,
+                created_at: "2024-01-01T00:00:00Z",
+                wrapped_key: None,
+                placement: Placement::Own,
+                compression: Default::default(),
+                stored_size: 5,
+            })
+            .unwrap();
+        let quota = Quota::new(20, metadata.clone());
+        assert_eq!(quota.room("b").unwrap(), Some(15));
+
+        // what is held comes off the room until it is given back, other buckets don't mind
+        let first = quota.reserve("b", 10).unwrap();
+        assert_eq!(quota.room("b").unwrap(), Some(5));
+        assert!(matches!(quota.reserve("b", 6), Err(TcpfsError::Quota(_))));
+        let second = quota.reserve("b", 5).unwrap();
+        assert!(quota.reserve("other", 20).is_ok());
+        assert_eq!(quota.reserved("b"), 15);
+        drop(first);
+        drop(second);
+        assert_eq!((quota.reserved("b"), quota.room("b").unwrap()), (0, Some(15)));
+
+        // without a quota nothing is held or refused
+        let unlimited = Quota::new(0, metadata);
+        let reservation = unlimited.reserve("b", u64::MAX).unwrap();
+        assert_eq!((unlimited.reserved("b"), unlimited.room("b").unwrap()), (0, None));
+        drop(reservation);
+    }
+
+    #[test]
+    fn test_reserving() {
+        let metadata = Arc::new(meta_sqlite::SqliteMetadataStore::open(None).unwrap());
+        let quota = Quota::new(RESERVE_AHEAD + 10, metadata);
+
+        // a step ahead while there is room, then what is needed to the last byte
+        let mut reservation = quota.reserve("b", 0).unwrap();
+        let body = vec![1; RESERVE_AHEAD as usize + 10];
+        let mut reader = Reserving::new(&body[..], &mut reservation);
+        assert_eq!(io::copy(&mut reader, &mut io::sink()).unwrap(), RESERVE_AHEAD + 10);
+        assert_eq!(quota.reserved("b"), RESERVE_AHEAD + 10);
+
+        // the bucket is full, the next byte is refused
+        let mut late = quota.reserve("b", 0).unwrap();
+        let mut reader = Reserving::new(&body[..1], &mut late);
+        assert_eq!(io::copy(&mut reader, &mut io::sink()).unwrap_err().kind(), ErrorKind::QuotaExceeded);
+        drop(reservation);
+        assert_eq!(quota.reserved("b"), 0);
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/*
+RATE LIMITS:
+token buckets on bytes/sec and requests/sec at three levels, a request has to get past every one that applies:
+    global:  everything the server moves
+    key:     each principal (api key or client cert) on its own, anonymous and presigned requests skip it
+    bucket:  each bucket on its own
+the defaults come from the environment (see server main). SET RATE LIMIT changes a default or overrides
+it for one principal or bucket until the server restarts, RATE LIMITS lists what is in effect.
+a token bucket holds a second's worth, a request that takes more than is left waits until the debt is paid.
+byte limited transfers go through the buffered copy loops in `lib.rs`, not sendfile or io_uring
+*/
+
+/// limiters kept before idle ones are swept, ids are the client's choice so the map can't just grow
+const SWEEP_AT: usize = 4096;
+
+/// 0 is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rate {
+    pub bytes_per_sec: u64,
+    pub requests_per_sec: u64,
+}
+
+impl Rate {
+    fn is_unlimited(&self) -> bool {
+        self.bytes_per_sec == 0 && self.requests_per_sec == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateConfig {
+    pub global: Rate,
+    /// applies to every principal separately
+    pub per_key: Rate,
+    /// applies to every bucket separately
+    pub per_bucket: Rate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Global = 0,
+    Key = 1,
+    Bucket = 2,
+}
+
+impl Scope {
+    pub fn from_u8(value: u8) -> Option<Scope> {
+        match value {
+            0 => Some(Scope::Global),
+            1 => Some(Scope::Key),
+            2 => Some(Scope::Bucket),
+            _ => None,
+        }
+    }
+}
+
+struct TokenBucket {
+    rate: u64,
+    /// tokens left (negative is debt) as of the instant
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        TokenBucket { rate, state: Mutex::new((rate as f64, Instant::now())) }
+    }
+
+    /// takes `n` tokens, gives back how long to wait until they are paid for
+    fn take(&self, n: u64) -> Duration {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let rate = self.rate as f64;
+        let (tokens, last) = *state;
+        let tokens = (tokens + now.duration_since(last).as_secs_f64() * rate).min(rate) - n as f64;
+        *state = (tokens, now);
+        match tokens < 0.0 {
+            true => Duration::from_secs_f64(-tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// refilled all the way, a fresh bucket would behave the same
+    fn is_full(&self) -> bool {
+        let (tokens, last) = *self.state.lock().unwrap_or_else(|e| e.into_inner());
+        tokens + last.elapsed().as_secs_f64() * self.rate as f64 >= self.rate as f64
+    }
+}
+
+struct Limiter {
+    bytes: Option<TokenBucket>,
+    requests: Option<TokenBucket>,
+}
+
+impl Limiter {
+    fn new(rate: Rate) -> Limiter {
+        let bucket = |rate| (rate > 0).then(|| TokenBucket::new(rate));
+        Limiter { bytes: bucket(rate.bytes_per_sec), requests: bucket(rate.requests_per_sec) }
+    }
+
+    fn is_full(&self) -> bool {
+        [&self.bytes, &self.requests].into_iter().flatten().all(TokenBucket::is_full)
+    }
+}
+
+struct RateState {
+    defaults: RateConfig,
+    overrides: HashMap<(Scope, String), Rate>,
+    /// created on first use, dropped when the limits change or once idle, see `SWEEP_AT`
+    limiters: HashMap<(Scope, String), Arc<Limiter>>,
+    sweep_at: usize,
+}
+
+pub struct RateLimits {
+    state: Mutex<RateState>,
+}
+
+impl RateLimits {
+    pub fn new(defaults: RateConfig) -> RateLimits {
+        RateLimits {
+            state: Mutex::new(RateState { defaults, overrides: HashMap::new(), limiters: HashMap::new(), sweep_at: SWEEP_AT }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RateState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// an empty `name` changes the default for the scope, global has nothing but the default
+    pub fn set(&self, scope: Scope, name: &str, rate: Rate) {
+        let mut state = self.lock();
+        match (scope, name) {
+            (Scope::Global, _) => state.defaults.global = rate,
+            (Scope::Key, "") => state.defaults.per_key = rate,
+            (Scope::Bucket, "") => state.defaults.per_bucket = rate,
+            _ => {
+                state.overrides.insert((scope, name.to_string()), rate);
+            }
+        }
+        // running transfers keep the limiters they have, new requests get the new rates
+        state.limiters.clear();
+    }
+
+    /// The defaults (with empty names) followed by the overrides
+    pub fn list(&self) -> Vec<(Scope, String, Rate)> {
+        let state = self.lock();
+        let mut limits = vec![
+            (Scope::Global, String::new(), state.defaults.global),
+            (Scope::Key, String::new(), state.defaults.per_key),
+            (Scope::Bucket, String::new(), state.defaults.per_bucket),
+        ];
+        let mut overrides: Vec<_> = state.overrides.iter().map(|((scope, name), rate)| (*scope, name.clone(), *rate)).collect();
+        overrides.sort_by(|a, b| (a.0 as u8, &a.1).cmp(&(b.0 as u8, &b.1)));
+        limits.extend(overrides);
+        limits
+    }
+
+    fn limiter(state: &mut RateState, scope: Scope, name: &str) -> Option<Arc<Limiter>> {
+        let rate = match state.overrides.get(&(scope, name.to_string())) {
+            Some(rate) => *rate,
+            None => match scope {
+                Scope::Global => state.defaults.global,
+                Scope::Key => state.defaults.per_key,
+                Scope::Bucket => state.defaults.per_bucket,
+            },
+        };
+        if rate.is_unlimited() {
+            return None;
+        }
+        // a limiter nobody holds that has refilled is the same as a new one
+        if state.limiters.len() >= state.sweep_at {
+            state.limiters.retain(|_, limiter| Arc::strong_count(limiter) > 1 || !limiter.is_full());
+            state.sweep_at = SWEEP_AT.max(2 * state.limiters.len());
+        }
+        let limiter = state.limiters.entry((scope, name.to_string())).or_insert_with(|| Arc::new(Limiter::new(rate)));
+        Some(limiter.clone())
+    }
+
+    /// Wait until a request by `key` (the principal) on `bucket` is allowed,
+    /// the throttle paces the bytes it moves
+    pub async fn admit(&self, key: Option<&str>, bucket: &str) -> Throttle {
+        let limiters: Vec<_> = {
+            let mut state = self.lock();
+            [
+                Self::limiter(&mut state, Scope::Global, ""),
+                key.and_then(|key| Self::limiter(&mut state, Scope::Key, key)),
+                Self::limiter(&mut state, Scope::Bucket, bucket),
+            ]
+            .into_iter()
+            .flatten()
+            .collect()
+        };
+        let wait = limiters.iter().filter_map(|l| l.requests.as_ref()).map(|r| r.take(1)).max().unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        Throttle { limiters: limiters.into_iter().filter(|l| l.bytes.is_some()).collect() }
+    }
+}
+
+/// The byte limits one request is held to
+pub struct Throttle {
+    limiters: Vec<Arc<Limiter>>,
+}
+
+impl Throttle {
+    pub fn is_limited(&self) -> bool {
+        !self.limiters.is_empty()
+    }
+
+    /// pays for `n` bytes, gives back how long that took
+    pub async fn consume(&self, n: usize) -> Duration {
+        let wait = self.limiters.iter().filter_map(|l| l.bytes.as_ref()).map(|b| b.take(n as u64)).max().unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(600), Duration::ZERO);
+        assert_eq!(bucket.take(400), Duration::ZERO);
+        // 500 short, paid off in half a second
+        let wait = bucket.take(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500), "{:?}", wait);
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let limits = RateLimits::new(RateConfig {
+            global: Rate { bytes_per_sec: 0, requests_per_sec: 1000 },
+            per_key: Rate::default(),
+            per_bucket: Rate { bytes_per_sec: 1_000_000, requests_per_sec: 0 },
+        });
+        assert!(limits.admit(Some("backup"), "b1").await.is_limited());
+
+        // the backup key gets its own budget, the bucket default is turned off
+        limits.set(Scope::Key, "backup", Rate { bytes_per_sec: 10, requests_per_sec: 0 });
+        limits.set(Scope::Bucket, "", Rate::default());
+        assert!(!limits.admit(Some("other"), "b1").await.is_limited());
+        let throttle = limits.admit(Some("backup"), "b1").await;
+        assert_eq!(throttle.consume(10).await, Duration::ZERO);
+
+        let listed = limits.list();
+        assert_eq!(listed.len(), 4);
+        assert_eq!(listed[3], (Scope::Key, "backup".to_string(), Rate { bytes_per_sec: 10, requests_per_sec: 0 }));
+    }
+
+    #[tokio::test]
+    async fn test_idle_limiters_are_swept() {
+        let limits = RateLimits::new(RateConfig {
+            per_bucket: Rate { bytes_per_sec: 1_000_000, requests_per_sec: 1_000_000 },
+            ..RateConfig::default()
+        });
+        // one a running transfer holds stays, full or not
+        let held = limits.admit(None, "held").await;
+        assert!(held.is_limited());
+
+        for n in 0..3 * SWEEP_AT {
+            limits.admit(None, &n.to_string()).await;
+        }
+        let state = limits.lock();
+        assert!(state.limiters.len() <= SWEEP_AT, "{}", state.limiters.len());
+        assert!(state.limiters.contains_key(&(Scope::Bucket, "held".to_string())));
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    error::Error,
+    io::{self, Read},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use meta_store::{MetadataStore, Segment, SegmentMove};
+
+use crate::store::BlobStore;
+
+/*
+SEGMENTS:
+objects below the packing threshold are appended to shared segment blobs instead of getting a
+blob (and with the filesystem store a directory tree) each:
+.segments/<uuid>.seg
++---------------------+---------------------+------ ... ------+
+| object a (length a) | object b (length b) |                 |
++---------------------+---------------------+------ ... ------+
+the metadata store records (segment, offset, length) per object. the newest unsealed segment takes
+appends until it reaches SEGMENT_SIZE, then it is sealed and a new one is started.
+deleting an object only lowers the segment's live_bytes, `compact` copies what is left of
+mostly dead segments into sealed segments of its own (so uploads keep appending to the active one
+meanwhile) and forgets them. the files stay for RETIRE_GRACE, a download that looked the object
+up just before can still open it, and are removed by a later compaction. a restart in between
+leaves them behind like a failed delete would.
+*/
+
+pub const SEGMENT_DIR: &str = ".segments";
+pub const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+/// sealed segments with at most this share of live bytes get compacted
+pub const COMPACT_LIVE_RATIO: f64 = 0.5;
+/// how long a compacted segment's file outlives it
+pub const RETIRE_GRACE: Duration = Duration::from_secs(10 * 60);
+
+pub struct Appended {
+    pub segment_id: i64,
+    pub offset: u64,
+    pub length: u64,
+}
+
+pub struct SegmentStore {
+    store: Arc<dyn BlobStore>,
+    metadata: Arc<dyn MetadataStore>,
+    max_size: u64,
+    lock: Mutex<()>,
+    /// one compaction at a time, their targets look compactable until they are filled
+    compacting: Mutex<()>,
+    grace: Duration,
+    /// files of compacted segments and when they were retired
+    retired: Mutex<Vec<(String, Instant)>>,
+}
+
+impl SegmentStore {
+    pub fn new(store: Arc<dyn BlobStore>, metadata: Arc<dyn MetadataStore>) -> Self {
+        SegmentStore {
+            store,
+            metadata,
+            max_size: SEGMENT_SIZE,
+            lock: Mutex::new(()),
+            compacting: Mutex::new(()),
+            grace: RETIRE_GRACE,
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Has to be held from `append` until the entry is committed, otherwise compaction could
+    /// remove a segment that is about to get a new entry
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn create_segment(&self) -> Result<Segment, Box<dyn Error + Send + Sync>> {
+        let location = format!("{}/{}.seg", SEGMENT_DIR, uuid::Uuid::new_v4().simple());
+        Ok(self.metadata.create_segment(&location)?)
+    }
+
+    /// Append everything from `src` to the active segment. The caller inserts the object with
+    /// `Placement::Packed` before letting go of the lock.
+    pub fn append(&self, _held: &MutexGuard<'_, ()>, src: &mut dyn Read) -> Result<Appended, Box<dyn Error + Send + Sync>> {
+        let segment = match self.metadata.get_active_segment()? {
+            Some(segment) if (segment.size as u64) < self.max_size => segment,
+            Some(full) => {
+                self.metadata.seal_segment(full.id)?;
+                self.create_segment()?
+            }
+            None => self.create_segment()?,
+        };
+
+        // the store appends at its own end, not segment.size: bytes from an append that
+        // never committed are simply skipped
+        let (offset, length) = self.store.append(&segment.path, src)?;
+        Ok(Appended { segment_id: segment.id, offset, length })
+    }
+
+    /// A segment only compaction writes to, sealed right away so uploads never pick it.
+    /// the lock is only held to create it
+    fn compaction_target(&self) -> Result<Segment, Box<dyn Error + Send + Sync>> {
+        let _held = self.lock();
+        let segment = self.create_segment()?;
+        self.metadata.seal_segment(segment.id)?;
+        Ok(segment)
+    }
+
+    /// Rewrite mostly dead segments, returns the number of bytes freed
+    pub fn compact(&self, max_live_ratio: f64) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let _compacting = self.compacting.lock().unwrap_or_else(|e| e.into_inner());
+        self.remove_retired();
+        let mut reclaimed = 0;
+        // (segment, bytes written to it)
+        let mut target: Option<(Segment, u64)> = None;
+        for segment in self.metadata.get_compactable_segments(max_live_ratio)? {
+            let mut moves = Vec::new();
+            let mut moved_bytes = 0;
+            for (object_id, offset, length) in self.metadata.get_segment_entries(segment.id)? {
+                let (to, written) = match target.take() {
+                    Some((to, written)) if written < self.max_size => (to, written),
+                    _ => (self.compaction_target()?, 0),
+                };
+                let mut entry = self.store.get(&segment.path, Some((offset as u64, length as u64)))?;
+                let (offset, length) = self.store.append(&to.path, &mut entry)?;
+                moved_bytes += length;
+                moves.push(SegmentMove { object_id, segment_id: to.id, offset: offset as i64 });
+                target = Some((to, written + length));
+            }
+            self.metadata.relocate_segment(segment.id, &moves)?;
+
+            // a target that never got written to has no file
+            reclaimed += match self.store.stat(&segment.path) {
+                Ok(size) => size.saturating_sub(moved_bytes),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => Err(e)?,
+            };
+            self.retired.lock().unwrap_or_else(|e| e.into_inner()).push((segment.path, Instant::now()));
+        }
+        Ok(reclaimed)
+    }
+
+    /// delete the files of segments compacted longer than `grace` ago
+    fn remove_retired(&self) {
+        let due: Vec<String> = {
+            let mut retired = self.retired.lock().unwrap_or_else(|e| e.into_inner());
+            let (due, kept) = retired.drain(..).partition(|(_, at)| at.elapsed() >= self.grace);
+            *retired = kept;
+            due.into_iter().map(|(path, _)| path).collect()
+        };
+        for path in due {
+            match self.store.delete(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => eprintln!("failed to remove {}: {:?}", path, e),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use meta_store::{Compression, NewObject, Placement};
+
+    use crate::store::MemoryBlobStore;
+
+    use super::*;
+
+    fn read_object(store: &dyn BlobStore, metadata: &dyn MetadataStore, key: &str) -> Vec<u8> {
+        let found = metadata.get_object("bucket", key).unwrap().unwrap();
+        let mut out = Vec::new();
+        store.get(&found.location, found.range).unwrap().read_to_end(&mut out).unwrap();
+        out
+    }
+
+    /// an upload of `data` packed into the active segment
+    fn insert_packed(segments: &SegmentStore, metadata: &dyn MetadataStore, key: &str, data: &[u8]) {
+        let held = segments.lock();
+        let appended = segments.append(&held, &mut &data[..]).unwrap();
+        let placeholder = format!("/store/{}", key);
+        metadata
+            .insert_object(&NewObject {
+                bucket_id: "bucket",
+                key,
+                path: &placeholder,
+                size: data.len() as i64,
+                created_at: "2024-01-01T00:00:00+00:00",
+                wrapped_key: None,
+                compression: Compression::None,
+                stored_size: data.len() as i64,
+                placement: Placement::Packed {
+                    segment_id: appended.segment_id,
+                    offset: appended.offset as i64,
+                    length: appended.length as i64,
+                },
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_append_and_compact() {
+        let metadata: Arc<dyn MetadataStore> = Arc::new(meta_sqlite::SqliteMetadataStore::open(None).unwrap());
+        let blobs = MemoryBlobStore::new();
+        let mut segments = SegmentStore::new(Arc::new(blobs.clone()), metadata.clone());
+        // tiny segments so every object after the first starts a new one
+        segments.max_size = 1;
+
+        for (key, data) in [("a", &b"first"[..]), ("b", b"second"), ("c", b"third")] {
+            insert_packed(&segments, metadata.as_ref(), key, data);
+        }
+        assert_eq!(read_object(&blobs, metadata.as_ref(), "b"), b"second");
+        let first = metadata.get_object("bucket", "a").unwrap().unwrap().location;
+
+        metadata.delete_object("bucket", "a").unwrap();
+
+        // "a"'s segment is all dead, "b"'s is sealed and fully live, "c"'s is still active
+        assert_eq!(segments.compact(COMPACT_LIVE_RATIO).unwrap(), 5);
+        // its file outlives it for a while
+        assert!(blobs.stat(&first).is_ok());
+
+        // compacting everything moves "b" along, the next compaction after the grace removes the file
+        segments.grace = Duration::ZERO;
+        let second = metadata.get_object("bucket", "b").unwrap().unwrap().location;
+        assert_eq!(segments.compact(1.0).unwrap(), 0);
+        assert!(blobs.stat(&first).is_err());
+        assert_eq!(read_object(&blobs, metadata.as_ref(), "b"), b"second");
+        assert_eq!(read_object(&blobs, metadata.as_ref(), "c"), b"third");
+        assert_eq!(segments.compact(1.0).unwrap(), 0);
+        assert!(blobs.stat(&second).is_err());
+    }
+
+    #[test]
+    fn test_download_during_compaction() {
+        let metadata: Arc<dyn MetadataStore> = Arc::new(meta_sqlite::SqliteMetadataStore::open(None).unwrap());
+        let blobs = MemoryBlobStore::new();
+        let segments = SegmentStore::new(Arc::new(blobs.clone()), metadata.clone());
+        for (key, data) in [("a", &b"first first"[..]), ("b", b"second")] {
+            insert_packed(&segments, metadata.as_ref(), key, data);
+        }
+        metadata.seal_segment(metadata.get_active_segment().unwrap().unwrap().id).unwrap();
+        metadata.delete_object("bucket", "a").unwrap();
+
+        // a download looked "b" up, then compaction moved it before the blob was opened.
+        // packed uploads go on while it runs
+        let found = metadata.get_object("bucket", "b").unwrap().unwrap();
+        std::thread::scope(|s| {
+            let compacting = s.spawn(|| segments.compact(COMPACT_LIVE_RATIO).unwrap());
+            insert_packed(&segments, metadata.as_ref(), "c", b"third");
+            assert_eq!(compacting.join().unwrap(), 11);
+        });
+        assert_ne!(metadata.get_object("bucket", "b").unwrap().unwrap().location, found.location);
+
+        let mut out = Vec::new();
+        blobs.get(&found.location, found.range).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"second");
+        assert_eq!(read_object(&blobs, metadata.as_ref(), "b"), b"second");
+        assert_eq!(read_object(&blobs, metadata.as_ref(), "c"), b"third");
+    }
+}
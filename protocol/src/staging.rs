@@ -0,0 +1,217 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+/// uploads are written under working_dir/.staging and renamed into place once complete,
+/// it lives in the working dir so the rename never crosses a filesystem
+pub const STAGING_DIR: &str = ".staging";
+
+pub fn staging_dir(working_dir: &Path) -> PathBuf {
+    working_dir.join(STAGING_DIR)
+}
+
+/// A file being uploaded, removed again on drop unless it was persisted
+pub struct StagedFile {
+    path: PathBuf,
+    file: fs::File,
+    persisted: bool,
+}
+
+impl StagedFile {
+    pub fn create(working_dir: &Path) -> io::Result<StagedFile> {
+        let dir = staging_dir(working_dir);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.part", uuid::Uuid::new_v4()));
+        let file = fs::File::create_new(&path)?;
+        Ok(StagedFile { path, file, persisted: false })
+    }
+
+    pub fn file(&mut self) -> &mut fs::File {
+        &mut self.file
+    }
+
+    /// fsync the data, rename it to `destination` and fsync the directory so the rename survives a crash
+    pub fn persist(mut self, destination: &Path) -> io::Result<()> {
+        self.file.sync_all()?;
+        let parent = destination.parent().unwrap();
+        fs::create_dir_all(parent)?;
+        fs::rename(&self.path, destination)?;
+        self.persisted = true;
+        sync_dir(parent)
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Remove whatever a crash left behind in the staging dir, returns how many files went
+pub fn sweep(working_dir: &Path) -> io::Result<usize> {
+    let entries = match fs::read_dir(staging_dir(working_dir)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Counts and sha256 hashes everything read through it
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+    count: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: Sha256::new(), count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Counts what is read through it, for bodies that are hashed after decompression
+pub struct CountingReader<R: Read> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        CountingReader { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Counts what is written through it, the size of a blob that is compressed on the way in
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_staged_file_lifecycle() {
+        let dir = TempDir::new("tcpfs-staging").unwrap();
+
+        // dropped without persisting, nothing is left behind
+        let mut staged = StagedFile::create(dir.path()).unwrap();
+        staged.file().write_all(b"partial").unwrap();
+        drop(staged);
+        assert_eq!(fs::read_dir(staging_dir(dir.path())).unwrap().count(), 0);
+
+        let destination = dir.path().join("bucket/a/file.data");
+        let mut staged = StagedFile::create(dir.path()).unwrap();
+        staged.file().write_all(b"complete").unwrap();
+        staged.persist(&destination).unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), b"complete");
+        assert_eq!(fs::read_dir(staging_dir(dir.path())).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let dir = TempDir::new("tcpfs-staging").unwrap();
+        assert_eq!(sweep(dir.path()).unwrap(), 0);
+
+        fs::create_dir_all(staging_dir(dir.path())).unwrap();
+        fs::write(staging_dir(dir.path()).join("left-over.part"), b"crash").unwrap();
+        assert_eq!(sweep(dir.path()).unwrap(), 1);
+        assert_eq!(fs::read_dir(staging_dir(dir.path())).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_hashing_reader() {
+        let mut reader = HashingReader::new(&b"abc"[..]);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(reader.count(), 3);
+        assert_eq!(
+            hex(&reader.finish()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
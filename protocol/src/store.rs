@@ -0,0 +1,372 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::staging::{self, StagedFile};
+
+/*
+BLOB STORES:
+everything the protocol reads or writes goes through a BlobStore, blobs are addressed by a
+location string that is kept in the MetadataStore (object, shared blob and segment paths).
+locations are relative to the store, e.g. "<bucket>/<ab>/<cd>/<id>.data", see `placement`.
+rows written before stores existed hold absolute paths, the filesystem store still opens those.
+*/
+
+/// Read + Seek, what downloads and ranges need
+pub trait BlobReader: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> BlobReader for T {}
+
+/// A blob being written, it only becomes visible once persisted and is discarded if dropped
+pub trait StagedBlob: Write + Send {
+    /// read back everything written so far, from the start
+    fn reader(&mut self) -> io::Result<Box<dyn Read + Send + '_>>;
+
+    /// make the blob durable and visible at `location`, replacing whatever was there
+    fn persist(self: Box<Self>, location: &str) -> io::Result<()>;
+
+    /// the file being written, for stores that stage to files. writes to it have to start at 0
+    fn file(&mut self) -> Option<&fs::File> {
+        None
+    }
+}
+
+pub trait BlobStore: Send + Sync {
+    fn stage(&self) -> io::Result<Box<dyn StagedBlob>>;
+
+    /// `range` is (offset, length), the reader sees it as if it was the whole blob
+    fn get(&self, location: &str, range: Option<(u64, u64)>) -> io::Result<Box<dyn BlobReader>>;
+
+    /// Like `get` for stores that keep blobs in files: the file with the (offset, length) of the blob
+    /// in it, so downloads can hand it to the kernel (see `zerocopy`). None if the store has no files
+    fn open_file(&self, _location: &str, _range: Option<(u64, u64)>) -> io::Result<Option<(fs::File, u64, u64)>> {
+        Ok(None)
+    }
+
+    /// append to the blob at `location`, creating it if needed. durable on return,
+    /// gives back the (offset, length) that was written
+    fn append(&self, location: &str, src: &mut dyn Read) -> io::Result<(u64, u64)>;
+
+    /// write `src` into the existing blob at `location` from `offset` on, dropping whatever was past it.
+    /// durable on return, gives back the number of bytes written
+    fn write_at(&self, location: &str, offset: u64, src: &mut dyn Read) -> io::Result<u64>;
+
+    fn delete(&self, location: &str) -> io::Result<()>;
+
+    /// size in bytes, NotFound if there is no such blob
+    fn stat(&self, location: &str) -> io::Result<u64>;
+}
+
+/// Blobs as files under a root directory, uploads are staged under root/.staging
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    /// Open the store, removing whatever a crash left in the staging dir
+    pub fn open(root: &Path) -> io::Result<FsBlobStore> {
+        let swept = staging::sweep(root)?;
+        if swept > 0 {
+            println!("Removed {} interrupted uploads", swept);
+        }
+        Ok(FsBlobStore { root: root.to_path_buf() })
+    }
+
+    /// absolute locations (from before stores existed) are used as they are
+    pub fn resolve(&self, location: &str) -> PathBuf {
+        self.root.join(location)
+    }
+
+    fn open_section(&self, location: &str, range: Option<(u64, u64)>) -> io::Result<(fs::File, u64, u64)> {
+        let file = fs::File::open(self.resolve(location))?;
+        let (start, length) = match range {
+            Some(range) => range,
+            None => (0, file.metadata()?.len()),
+        };
+        Ok((file, start, length))
+    }
+}
+
+struct FsStagedBlob {
+    file: StagedFile,
+    root: PathBuf,
+}
+
+impl Write for FsStagedBlob {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.file().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.file().flush()
+    }
+}
+
+impl StagedBlob for FsStagedBlob {
+    fn reader(&mut self) -> io::Result<Box<dyn Read + Send + '_>> {
+        let file = self.file.file();
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Box::new(file))
+    }
+
+    fn persist(self: Box<Self>, location: &str) -> io::Result<()> {
+        self.file.persist(&self.root.join(location))
+    }
+
+    fn file(&mut self) -> Option<&fs::File> {
+        Some(self.file.file())
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn stage(&self) -> io::Result<Box<dyn StagedBlob>> {
+        Ok(Box::new(FsStagedBlob {
+            file: StagedFile::create(&self.root)?,
+            root: self.root.clone(),
+        }))
+    }
+
+    fn get(&self, location: &str, range: Option<(u64, u64)>) -> io::Result<Box<dyn BlobReader>> {
+        let (file, start, length) = self.open_section(location, range)?;
+        Ok(Box::new(Section::new(file, start, length)?))
+    }
+
+    fn open_file(&self, location: &str, range: Option<(u64, u64)>) -> io::Result<Option<(fs::File, u64, u64)>> {
+        self.open_section(location, range).map(Some)
+    }
+
+    fn append(&self, location: &str, src: &mut dyn Read) -> io::Result<(u64, u64)> {
+        let path = self.resolve(location);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let offset = file.metadata()?.len();
+        let length = io::copy(src, &mut file)?;
+        file.sync_data()?;
+        Ok((offset, length))
+    }
+
+    fn write_at(&self, location: &str, offset: u64, src: &mut dyn Read) -> io::Result<u64> {
+        let mut file = fs::OpenOptions::new().write(true).open(self.resolve(location))?;
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let length = io::copy(src, &mut file)?;
+        file.sync_data()?;
+        Ok(length)
+    }
+
+    fn delete(&self, location: &str) -> io::Result<()> {
+        fs::remove_file(self.resolve(location))
+    }
+
+    fn stat(&self, location: &str) -> io::Result<u64> {
+        let metadata = fs::metadata(self.resolve(location))?;
+        match metadata.is_file() {
+            true => Ok(metadata.len()),
+            false => Err(io::Error::new(io::ErrorKind::NotFound, "not a blob")),
+        }
+    }
+}
+
+/// Everything in a map, gone when the process exits. Meant for tests
+#[derive(Default, Clone)]
+pub struct MemoryBlobStore {
+    blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn blobs(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<u8>>> {
+        self.blobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn not_found(location: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no blob at {}", location))
+}
+
+struct MemoryStagedBlob {
+    buf: Vec<u8>,
+    store: MemoryBlobStore,
+}
+
+impl Write for MemoryStagedBlob {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StagedBlob for MemoryStagedBlob {
+    fn reader(&mut self) -> io::Result<Box<dyn Read + Send + '_>> {
+        Ok(Box::new(&self.buf[..]))
+    }
+
+    fn persist(self: Box<Self>, location: &str) -> io::Result<()> {
+        self.store.blobs().insert(location.to_string(), self.buf);
+        Ok(())
+    }
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn stage(&self) -> io::Result<Box<dyn StagedBlob>> {
+        Ok(Box::new(MemoryStagedBlob { buf: Vec::new(), store: self.clone() }))
+    }
+
+    fn get(&self, location: &str, range: Option<(u64, u64)>) -> io::Result<Box<dyn BlobReader>> {
+        let blobs = self.blobs();
+        let blob = blobs.get(location).ok_or_else(|| not_found(location))?;
+        let (start, length) = range.unwrap_or((0, blob.len() as u64));
+        let start = (start as usize).min(blob.len());
+        let end = (start + length as usize).min(blob.len());
+        Ok(Box::new(Cursor::new(blob[start..end].to_vec())))
+    }
+
+    fn append(&self, location: &str, src: &mut dyn Read) -> io::Result<(u64, u64)> {
+        let mut data = Vec::new();
+        src.read_to_end(&mut data)?;
+        let mut blobs = self.blobs();
+        let blob = blobs.entry(location.to_string()).or_default();
+        let offset = blob.len() as u64;
+        blob.extend_from_slice(&data);
+        Ok((offset, data.len() as u64))
+    }
+
+    fn write_at(&self, location: &str, offset: u64, src: &mut dyn Read) -> io::Result<u64> {
+        let mut data = Vec::new();
+        src.read_to_end(&mut data)?;
+        let mut blobs = self.blobs();
+        let blob = blobs.get_mut(location).ok_or_else(|| not_found(location))?;
+        blob.resize(offset as usize, 0);
+        blob.extend_from_slice(&data);
+        Ok(data.len() as u64)
+    }
+
+    fn delete(&self, location: &str) -> io::Result<()> {
+        self.blobs().remove(location).map(|_| ()).ok_or_else(|| not_found(location))
+    }
+
+    fn stat(&self, location: &str) -> io::Result<u64> {
+        self.blobs().get(location).map(|b| b.len() as u64).ok_or_else(|| not_found(location))
+    }
+}
+
+/// `length` bytes of a file starting at `start`, readable and seekable as if it was the whole file
+pub struct Section {
+    file: fs::File,
+    start: u64,
+    length: u64,
+    pos: u64,
+}
+
+impl Section {
+    pub fn new(mut file: fs::File, start: u64, length: u64) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Section { file, start, length, pos: 0 })
+    }
+}
+
+impl Read for Section {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.length.saturating_sub(self.pos);
+        let max = buf.len().min(remaining as usize);
+        let n = self.file.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Section {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => p as i128,
+            SeekFrom::End(p) => self.length as i128 + p as i128,
+            SeekFrom::Current(p) => self.pos as i128 + p as i128,
+        };
+        if target < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start"));
+        }
+        self.file.seek(SeekFrom::Start(self.start + target as u64))?;
+        self.pos = target as u64;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn read_all(store: &dyn BlobStore, location: &str, range: Option<(u64, u64)>) -> Vec<u8> {
+        let mut out = Vec::new();
+        store.get(location, range).unwrap().read_to_end(&mut out).unwrap();
+        out
+    }
+
+    /// the behaviour every store has to share
+    fn conformance(store: &dyn BlobStore) {
+        // nothing is visible before persist, dropping discards
+        let mut staged = store.stage().unwrap();
+        staged.write_all(b"dropped").unwrap();
+        drop(staged);
+
+        let mut staged = store.stage().unwrap();
+        staged.write_all(b"hello world").unwrap();
+        let mut read_back = Vec::new();
+        staged.reader().unwrap().read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, b"hello world");
+        assert_eq!(store.stat("a/b/c.data").unwrap_err().kind(), io::ErrorKind::NotFound);
+        staged.persist("a/b/c.data").unwrap();
+
+        assert_eq!(store.stat("a/b/c.data").unwrap(), 11);
+        assert_eq!(read_all(store, "a/b/c.data", None), b"hello world");
+        assert_eq!(read_all(store, "a/b/c.data", Some((6, 5))), b"world");
+
+        let mut reader = store.get("a/b/c.data", Some((6, 5))).unwrap();
+        reader.seek(SeekFrom::Start(2)).unwrap();
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "rld");
+
+        assert_eq!(store.append("seg", &mut &b"one"[..]).unwrap(), (0, 3));
+        assert_eq!(store.append("seg", &mut &b"two"[..]).unwrap(), (3, 3));
+        assert_eq!(read_all(store, "seg", Some((3, 3))), b"two");
+        // the tail past the offset goes
+        assert_eq!(store.write_at("seg", 4, &mut &b"en"[..]).unwrap(), 2);
+        assert_eq!(read_all(store, "seg", None), b"oneten");
+        assert!(store.write_at("missing", 0, &mut &b"x"[..]).is_err());
+
+        store.delete("a/b/c.data").unwrap();
+        assert_eq!(store.stat("a/b/c.data").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(store.get("a/b/c.data", None).is_err());
+        assert!(store.delete("a/b/c.data").is_err());
+    }
+
+    #[test]
+    fn test_fs_store() {
+        let dir = TempDir::new("tcpfs-store").unwrap();
+        let store = FsBlobStore::open(dir.path()).unwrap();
+        conformance(&store);
+        assert_eq!(fs::read_dir(staging::staging_dir(dir.path())).unwrap().count(), 0);
+
+        // absolute locations from before stores existed
+        let legacy = dir.path().join("legacy.data");
+        fs::write(&legacy, b"old").unwrap();
+        assert_eq!(read_all(&store, legacy.to_str().unwrap(), None), b"old");
+    }
+
+    #[test]
+    fn test_memory_store() {
+        conformance(&MemoryBlobStore::new());
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::server::TlsStream;
+
+use crate::{
+    acl,
+    timeouts::{Pace, Timeouts, Watch},
+};
+
+enum Transport {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// An accepted client connection, either plain tcp or wrapped in tls.
+/// waiting on it is bounded by the `timeouts` phases once they are set
+pub struct ClientStream {
+    transport: Transport,
+    watch: Watch,
+}
+
+impl ClientStream {
+    pub fn plain(stream: TcpStream) -> ClientStream {
+        ClientStream { transport: Transport::Plain(stream), watch: Watch::new() }
+    }
+
+    pub fn tls(stream: TlsStream<TcpStream>) -> ClientStream {
+        ClientStream { transport: Transport::Tls(Box::new(stream)), watch: Watch::new() }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.transport, Transport::Tls(_))
+    }
+
+    /// starts the idle clock, without this the stream waits forever
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.watch.set(timeouts);
+    }
+
+    pub(crate) fn idle(&mut self) {
+        self.watch.idle();
+    }
+
+    pub(crate) fn header(&mut self) {
+        self.watch.header();
+    }
+
+    pub(crate) fn transfer(&mut self) {
+        self.watch.transfer();
+    }
+
+    /// for transfers that go around the stream, set during the transfer phase
+    pub(crate) fn pace(&self) -> Option<Pace> {
+        self.watch.pace()
+    }
+
+    pub(crate) fn excuse(&mut self, waited: Duration) {
+        self.watch.excuse(waited);
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn tcp(&self) -> &TcpStream {
+        match &self.transport {
+            Transport::Plain(s) => s,
+            Transport::Tls(s) => s.get_ref().0,
+        }
+    }
+
+    /// sha256 hex digest of the verified client certificate, only set for mutual tls
+    pub fn peer_cert_fingerprint(&self) -> Option<String> {
+        match &self.transport {
+            Transport::Plain(_) => None,
+            Transport::Tls(s) => s
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first().map(|cert| acl::hash_api_key(cert.as_ref()))),
+        }
+    }
+
+    /// the result of polling the transport, or the timeout if it has to wait past the deadline
+    fn watched<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Pending => self.watch.poll_expired(cx).map(Err),
+            ready => ready,
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = match &mut this.transport {
+            Transport::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Transport::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        };
+        this.watch.progress(buf.filled().len() - before);
+        this.watched(cx, poll)
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = match &mut this.transport {
+            Transport::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Transport::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        };
+        if let Poll::Ready(Ok(n)) = poll {
+            this.watch.progress(n);
+        }
+        this.watched(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = match &mut this.transport {
+            Transport::Plain(s) => Pin::new(s).poll_flush(cx),
+            Transport::Tls(s) => Pin::new(s).poll_flush(cx),
+        };
+        this.watched(cx, poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = match &mut this.transport {
+            Transport::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Transport::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        };
+        this.watched(cx, poll)
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    future::Future,
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::time::Sleep;
+
+/*
+TIMEOUTS:
+a connection goes through phases, each with its own limit on how long we wait for the client:
+    idle:     accepted (or done with the AUTH/TOKEN preamble), waiting for the next command byte
+    header:   from the command byte until the header and key are in, and small answers are out
+    transfer: a body going either way, it has to average `min_rate` bytes/sec once `grace` is over
+the clock only counts while we wait on the socket, time spent in the metadata store or on disk is
+not held against the client. a connection that runs out of time is closed (slow loris).
+sendfile and io_uring bypass the stream, they get a `Pace` and keep to it themselves
+*/
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub idle: Duration,
+    pub header: Duration,
+    /// bytes/sec, 0 turns the transfer check off
+    pub min_rate: u64,
+    pub grace: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            idle: Duration::from_secs(30),
+            header: Duration::from_secs(10),
+            min_rate: 16 * 1024,
+            grace: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A transfer has to keep up with `min_rate` once `grace` is over
+#[derive(Debug, Clone, Copy)]
+pub struct Pace {
+    start: Instant,
+    grace: Duration,
+    min_rate: u64,
+}
+
+impl Pace {
+    pub fn new(grace: Duration, min_rate: u64) -> Pace {
+        Pace { start: Instant::now(), grace, min_rate }
+    }
+
+    /// when the transfer is too slow if it is still at `done` bytes
+    pub fn deadline(&self, done: u64) -> Instant {
+        self.start + self.grace + Duration::from_secs_f64(done as f64 / self.min_rate as f64)
+    }
+}
+
+pub fn too_slow() -> io::Error {
+    io::Error::new(ErrorKind::TimedOut, "transfer below the minimum rate")
+}
+
+/// `io` with the time `pace` leaves for a transfer at `done` bytes
+pub async fn paced<T>(pace: Option<Pace>, done: u64, io: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    match pace {
+        Some(pace) => tokio::time::timeout_at(pace.deadline(done).into(), io).await.map_err(|_| too_slow())?,
+        None => io.await,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Limit {
+    None,
+    Until(Instant, &'static str),
+    Pace(Pace),
+}
+
+/// The deadline a `ClientStream` is waiting under, see the phases above
+pub(crate) struct Watch {
+    timeouts: Option<Timeouts>,
+    limit: Limit,
+    /// bytes moved since the transfer started
+    done: u64,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl Watch {
+    pub fn new() -> Watch {
+        Watch { timeouts: None, limit: Limit::None, done: 0, timer: None }
+    }
+
+    pub fn set(&mut self, timeouts: Timeouts) {
+        self.timeouts = Some(timeouts);
+        self.idle();
+    }
+
+    pub fn idle(&mut self) {
+        if let Some(t) = self.timeouts {
+            self.limit = Limit::Until(Instant::now() + t.idle, "idle timeout");
+        }
+    }
+
+    pub fn header(&mut self) {
+        if let Some(t) = self.timeouts {
+            self.limit = Limit::Until(Instant::now() + t.header, "header timeout");
+        }
+    }
+
+    pub fn transfer(&mut self) {
+        let pace = self.timeouts.filter(|t| t.min_rate > 0).map(|t| Pace::new(t.grace, t.min_rate));
+        self.limit = pace.map_or(Limit::None, Limit::Pace);
+        self.done = 0;
+    }
+
+    pub fn pace(&self) -> Option<Pace> {
+        match self.limit {
+            Limit::Pace(pace) => Some(pace),
+            _ => None,
+        }
+    }
+
+    /// time the server held the transfer back (rate limits), not the client's fault
+    pub fn excuse(&mut self, waited: Duration) {
+        if let Limit::Pace(pace) = &mut self.limit {
+            pace.start += waited;
+        }
+    }
+
+    pub fn progress(&mut self, n: usize) {
+        self.done += n as u64;
+    }
+
+    /// called when the stream has to wait, Ready once the client ran out of time
+    pub fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        let (deadline, error) = match self.limit {
+            Limit::None => return Poll::Pending,
+            Limit::Until(deadline, message) => (deadline, io::Error::new(ErrorKind::TimedOut, message)),
+            Limit::Pace(pace) => (pace.deadline(self.done), too_slow()),
+        };
+        let deadline = deadline.into();
+        let timer = self.timer.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+        if timer.deadline() != deadline {
+            timer.as_mut().reset(deadline);
+        }
+        timer.as_mut().poll(cx).map(|_| error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pace() {
+        let pace = Pace::new(Duration::from_secs(2), 1000);
+        assert_eq!(pace.deadline(0), pace.start + Duration::from_secs(2));
+        assert_eq!(pace.deadline(500), pace.start + Duration::from_millis(2500));
+        assert_eq!(pace.deadline(3000), pace.start + Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    error::Error,
+    fs,
+    io::BufReader,
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, StreamOwned,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::stream::ClientStream;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error + Send + Sync>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        Err(format!("no certificates found in {}", path.display()))?;
+    }
+    Ok(certs)
+}
+
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Box<dyn Error + Send + Sync>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(key),
+        None => Err(format!("no private key found in {}", path.display()))?,
+    }
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore, Box<dyn Error + Send + Sync>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// Server side config. When `client_ca` is set client certificates signed by it are verified
+/// and can be mapped to principals, clients without one can still fall back to api keys.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>, Box<dyn Error + Send + Sync>> {
+    let builder = ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca)?), provider())
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(builder.with_single_cert(load_certs(cert)?, load_key(key)?)?))
+}
+
+/// Run the handshake up front so the peer certificate is known before any command is read
+pub async fn accept(
+    config: Arc<ServerConfig>,
+    sock: tokio::net::TcpStream,
+) -> Result<ClientStream, Box<dyn Error + Send + Sync>> {
+    let stream = TlsAcceptor::from(config).accept(sock).await?;
+    Ok(ClientStream::tls(stream))
+}
+
+/// Client side config trusting `ca`, with an optional (cert, key) pair for mutual tls
+pub fn client_config(
+    ca: &Path,
+    identity: Option<(&Path, &Path)>,
+) -> Result<Arc<ClientConfig>, Box<dyn Error + Send + Sync>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(load_roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+pub fn connect(
+    config: Arc<ClientConfig>,
+    addr: &str,
+    server_name: &str,
+) -> Result<StreamOwned<ClientConnection, TcpStream>, Box<dyn Error + Send + Sync>> {
+    let name = ServerName::try_from(server_name.to_string())?;
+    let conn = ClientConnection::new(config, name)?;
+    Ok(StreamOwned::new(conn, TcpStream::connect(addr)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use sha2::{Digest, Sha256};
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{acl, ratelimit::RateConfig, store::MemoryBlobStore, HandlerConfig, MetadataStore, RequestHandler, Status};
+
+    fn write_pem(dir: &Path, name: &str, pem: &str) -> std::path::PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, pem).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_mutual_tls_round_trip() {
+        let dir = TempDir::new("tcpfs-tls").unwrap();
+
+        // self signed ca that issues both the server and the client certificate
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca, &ca_key)
+            .unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let client_cert = CertificateParams::new(vec!["ci".to_string()])
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+
+        let ca_path = write_pem(dir.path(), "ca.pem", &ca.pem());
+        let server_cert_path = write_pem(dir.path(), "server.pem", &server_cert.pem());
+        let server_key_path = write_pem(dir.path(), "server.key", &server_key.serialize_pem());
+        let client_cert_path = write_pem(dir.path(), "client.pem", &client_cert.pem());
+        let client_key_path = write_pem(dir.path(), "client.key", &client_key.serialize_pem());
+
+        // the client cert is the only credential, nothing is open by default
+        let bucket = uuid::Uuid::new_v4();
+        let metadata = meta_redb::RedbMetadataStore::in_memory().unwrap();
+        metadata.upsert_principal("ci", "unused", false).unwrap();
+        metadata.add_principal_cert(&acl::hash_api_key(client_cert.der()), "ci").unwrap();
+        metadata.grant_permissions(&bucket.to_string(), "ci", acl::READ | acl::WRITE).unwrap();
+
+        let handler = Arc::new(RequestHandler::new(HandlerConfig {
+            metadata: Arc::new(metadata),
+            store: Arc::new(MemoryBlobStore::new()),
+            acl_mode: acl::AclMode::DefaultDeny,
+            token_secret: b"secret".to_vec(),
+            master_key: None,
+            dedup: false,
+            pack_threshold: 0,
+            rates: RateConfig::default(),
+            bucket_quota: 0,
+        }));
+        let config = server_config(&server_cert_path, &server_key_path, Some(&ca_path)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // the server side is async, the client below is the plain blocking one
+        let server = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                for _ in 0..2 {
+                    let (sock, _) = listener.accept().await.unwrap();
+                    let stream = accept(config.clone(), sock).await.unwrap();
+                    handler.handle_client(stream).await.unwrap();
+                }
+            })
+        });
+
+        let client = client_config(&ca_path, Some((&client_cert_path, &client_key_path))).unwrap();
+        let data = b"over the wire, encrypted";
+        let key = b"some/key";
+
+        let mut upload = connect(client.clone(), &addr, "localhost").unwrap();
+        upload.write_all(&[0x01]).unwrap();
+        upload.write_all(&(key.len() as u32).to_be_bytes()).unwrap();
+        upload.write_all(&(data.len() as u32).to_be_bytes()).unwrap();
+        upload.write_all(&bucket.as_u128().to_be_bytes()).unwrap();
+        upload.write_all(key).unwrap();
+        upload.write_all(data).unwrap();
+        upload.write_all(&Sha256::digest(data)).unwrap();
+        let mut status = [0; 1];
+        upload.read_exact(&mut status).unwrap();
+        assert_eq!(status[0], Status::Ok as u8);
+
+        let mut download = connect(client, &addr, "localhost").unwrap();
+        download.write_all(&[0x02]).unwrap();
+        download.write_all(&(key.len() as u32).to_be_bytes()).unwrap();
+        download.write_all(&bucket.as_u128().to_be_bytes()).unwrap();
+        download.write_all(key).unwrap();
+        let mut response = Vec::new();
+        download.read_to_end(&mut response).unwrap();
+        assert_eq!(response[0], Status::Ok as u8);
+        assert_eq!(&response[1..], data);
+
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAC_LENGTH: usize = 32;
+
+/// tokens can't outlive a week no matter what the client asks for
+pub const MAX_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/*
+PRESIGNED TOKEN (base64url, no padding):
++----------------------+----------------------+----------------------+----------------------------+
+| Operation (8 bits)   | Expires At (64 bits) | bucket_id (128 bits) | Key Length (32 bits)       |
++----------------------+----------------------+----------------------+----------------------------+
++-----------------------------------------------------------------------------------------+
+|               Key (variable length)  |  HMAC-SHA256 of everything before (256 bits)      |
++-----------------------------------------------------------------------------------------+
+expires at is unix seconds, operation is acl::READ (download) or acl::WRITE (upload)
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub operation: u8,
+    pub expires_at: u64,
+    pub bucket_id: uuid::Uuid,
+    pub key: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::Malformed => write!(f, "malformed token"),
+            TokenError::BadSignature => write!(f, "invalid token signature"),
+            TokenError::Expired => write!(f, "token expired"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn mac(secret: &[u8]) -> HmacSha256 {
+    // hmac accepts keys of any length
+    HmacSha256::new_from_slice(secret).unwrap()
+}
+
+pub fn mint(secret: &[u8], grant: &Grant) -> String {
+    let mut payload = Vec::with_capacity(29 + grant.key.len() + MAC_LENGTH);
+    payload.push(grant.operation);
+    payload.extend_from_slice(&grant.expires_at.to_be_bytes());
+    payload.extend_from_slice(&grant.bucket_id.as_u128().to_be_bytes());
+    payload.extend_from_slice(&(grant.key.len() as u32).to_be_bytes());
+    payload.extend_from_slice(grant.key.as_bytes());
+
+    let mut mac = mac(secret);
+    mac.update(&payload);
+    payload.extend_from_slice(&mac.finalize().into_bytes());
+    URL_SAFE_NO_PAD.encode(payload)
+}
+
+pub fn verify(secret: &[u8], token: &[u8], now: u64) -> Result<Grant, TokenError> {
+    let raw = URL_SAFE_NO_PAD.decode(token).map_err(|_| TokenError::Malformed)?;
+    if raw.len() < 29 + MAC_LENGTH {
+        return Err(TokenError::Malformed);
+    }
+    let (payload, signature) = raw.split_at(raw.len() - MAC_LENGTH);
+
+    let mut mac = mac(secret);
+    mac.update(payload);
+    mac.verify_slice(signature).map_err(|_| TokenError::BadSignature)?;
+
+    let key_length = u32::from_be_bytes(payload[25..29].try_into().unwrap()) as usize;
+    if payload.len() != 29 + key_length {
+        return Err(TokenError::Malformed);
+    }
+    let grant = Grant {
+        operation: payload[0],
+        expires_at: u64::from_be_bytes(payload[1..9].try_into().unwrap()),
+        bucket_id: uuid::Uuid::from_u128(u128::from_be_bytes(payload[9..25].try_into().unwrap())),
+        key: String::from_utf8(payload[29..].to_vec()).map_err(|_| TokenError::Malformed)?,
+    };
+    if grant.expires_at <= now {
+        return Err(TokenError::Expired);
+    }
+    Ok(grant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl;
+
+    fn grant(expires_at: u64) -> Grant {
+        Grant {
+            operation: acl::READ,
+            expires_at,
+            bucket_id: uuid::Uuid::new_v4(),
+            key: "builds/artifact.tar".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let g = grant(1_000);
+        let token = mint(b"secret", &g);
+        assert_eq!(verify(b"secret", token.as_bytes(), 999).unwrap(), g);
+    }
+
+    #[test]
+    fn test_rejects() {
+        let token = mint(b"secret", &grant(1_000));
+        assert_eq!(verify(b"secret", token.as_bytes(), 1_000), Err(TokenError::Expired));
+        assert_eq!(verify(b"other", token.as_bytes(), 0), Err(TokenError::BadSignature));
+        assert_eq!(verify(b"secret", b"!!", 0), Err(TokenError::Malformed));
+
+        // flip a byte of the payload, the signature no longer matches
+        let mut raw = URL_SAFE_NO_PAD.decode(&token).unwrap();
+        raw[0] = acl::WRITE;
+        let tampered = URL_SAFE_NO_PAD.encode(raw);
+        assert_eq!(verify(b"secret", tampered.as_bytes(), 0), Err(TokenError::BadSignature));
+    }
+}
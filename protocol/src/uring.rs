@@ -0,0 +1,386 @@
+#[cfg(not(target_os = "linux"))]
+compile_error!("the io-uring feature is linux only");
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{self, ErrorKind},
+    os::fd::{AsFd, AsRawFd, RawFd},
+    sync::OnceLock,
+    time::Instant,
+};
+
+use io_uring::{
+    opcode, squeue,
+    types::{Fd, SubmitArgs, Timespec},
+    IoUring,
+};
+use tokio::net::TcpStream;
+
+use crate::timeouts::{too_slow, Pace};
+
+/*
+IO_URING TRANSFERS (cargo feature io-uring):
+plain uploads staged in files and plain downloads from files are moved by a small ring on the
+blocking pool instead of a copy loop, so the socket and the disk work at the same time:
+    upload:   recv chunk n+1 from the socket while chunk n is written to the staged file
+    download: read the next chunks from the file while chunk n is sent
+socket operations are linked behind a poll, the sockets are non blocking (they belong to tokio).
+waits are bounded by the transfer's `Pace`, a client that falls behind gets TimedOut and the ring
+cancels what is in flight.
+without the feature, or when the kernel refuses io_uring, the default paths are used.
+downloads with a warm page cache are faster with sendfile (see benches/download.rs), what the ring
+buys is that cold reads wait on the blocking pool instead of stalling an async worker.
+*/
+
+/// bytes per operation
+const CHUNK: usize = 256 * 1024;
+/// buffers per transfer, also the most file operations in flight
+const DEPTH: usize = 4;
+
+// user_data is the operation in the low byte and the buffer index above it
+const POLL: u64 = 0;
+const SOCKET: u64 = 1;
+const FILE: u64 = 2;
+const CANCEL: u64 = 3;
+
+fn tag(op: u64, buf: usize) -> u64 {
+    op | (buf as u64) << 8
+}
+
+/// false when the kernel (or a seccomp filter) refuses io_uring, checked once
+pub fn available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| match IoUring::new(2) {
+        Ok(_) => true,
+        Err(e) => {
+            println!("io_uring unavailable, using the default transfers: {}", e);
+            false
+        }
+    })
+}
+
+fn check(result: i32) -> io::Result<usize> {
+    match result {
+        r if r < 0 => Err(io::Error::from_raw_os_error(-r)),
+        r => Ok(r as usize),
+    }
+}
+
+/// A ring that knows what it has in flight. Buffers handed to it have to outlive it,
+/// dropping it cancels whatever is left and waits for the kernel to let go of them
+struct Ring {
+    ring: IoUring,
+    in_flight: HashSet<u64>,
+}
+
+impl Ring {
+    fn new() -> io::Result<Ring> {
+        // room for a linked poll and op per buffer, plus their cancellations
+        let ring = IoUring::new((DEPTH * 4) as u32)?;
+        Ok(Ring { ring, in_flight: HashSet::new() })
+    }
+
+    fn is_idle(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    /// # Safety: the buffers the entries point at have to outlive the ring
+    unsafe fn push(&mut self, entries: &[squeue::Entry]) -> io::Result<()> {
+        self.ring
+            .submission()
+            .push_multiple(entries)
+            .map_err(|_| io::Error::other("io_uring submission queue full"))?;
+        self.in_flight.extend(entries.iter().map(|e| e.get_user_data()));
+        Ok(())
+    }
+
+    /// `op` once `sock` is ready for `events`
+    unsafe fn push_socket(&mut self, sock: RawFd, events: i16, op: squeue::Entry) -> io::Result<()> {
+        let poll = opcode::PollAdd::new(Fd(sock), events as u32)
+            .build()
+            .flags(squeue::Flags::IO_LINK)
+            .user_data(POLL | (op.get_user_data() & !0xff));
+        self.push(&[poll, op])
+    }
+
+    /// submit and wait for at least one completion, gives back (user_data, result).
+    /// TimedOut once `deadline` passes
+    fn wait(&mut self, deadline: Option<Instant>) -> io::Result<Vec<(u64, i32)>> {
+        let submitted = match deadline {
+            Some(deadline) => {
+                let left = deadline.checked_duration_since(Instant::now()).ok_or_else(too_slow)?;
+                let timeout = Timespec::from(left);
+                self.ring.submitter().submit_with_args(1, &SubmitArgs::new().timespec(&timeout))
+            }
+            None => self.ring.submit_and_wait(1),
+        };
+        match submitted {
+            Err(e) if e.kind() == ErrorKind::Interrupted => return Ok(Vec::new()),
+            // checked again on the next wait
+            Err(e) if e.raw_os_error() == Some(libc::ETIME) => return Ok(Vec::new()),
+            result => result?,
+        };
+        let done: Vec<_> = self.ring.completion().map(|c| (c.user_data(), c.result())).collect();
+        Ok(done.into_iter().filter(|(user_data, _)| self.in_flight.remove(user_data)).collect())
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        let cancels: Vec<_> = self
+            .in_flight
+            .iter()
+            .map(|&user_data| opcode::AsyncCancel::new(user_data).build().user_data(CANCEL))
+            .collect();
+        let _ = unsafe { self.ring.submission().push_multiple(&cancels) };
+        while !self.is_idle() {
+            if let Err(e) = self.wait(None) {
+                // there is no telling whether the kernel is done with the buffers, they can't be freed safely
+                eprintln!("io_uring failed while cancelling a transfer: {}", e);
+                std::process::abort();
+            }
+        }
+    }
+}
+
+/// Receive up to `length` bytes from `sock` and write them to `file` starting at `offset`,
+/// `on_data` sees every chunk in order. Returns the bytes received, short if the peer stopped sending
+pub fn receive(
+    sock: RawFd,
+    file: &File,
+    offset: u64,
+    length: u64,
+    pace: Option<Pace>,
+    mut on_data: impl FnMut(&[u8]),
+) -> io::Result<u64> {
+    // declared before the ring so they are dropped after it
+    let mut bufs = vec![vec![0u8; CHUNK]; DEPTH];
+    let mut ring = Ring::new()?;
+    let mut free: Vec<usize> = (0..DEPTH).collect();
+    // per buffer being written: (file position, start, end) of what is left
+    let mut writing: Vec<Option<(u64, usize, usize)>> = vec![None; DEPTH];
+    let mut receiving = false;
+    let mut eof = false;
+    let mut received = 0;
+
+    let write_op = |buf: &[u8], (position, start, end): (u64, usize, usize), i: usize| {
+        opcode::Write::new(Fd(file.as_raw_fd()), buf[start..].as_ptr(), (end - start) as u32)
+            .offset(position)
+            .build()
+            .user_data(tag(FILE, i))
+    };
+
+    loop {
+        // one receive at a time, the socket is a stream
+        if !receiving && !eof && received < length {
+            if let Some(i) = free.pop() {
+                let want = (length - received).min(CHUNK as u64) as u32;
+                let recv = opcode::Recv::new(Fd(sock), bufs[i].as_mut_ptr(), want).build().user_data(tag(SOCKET, i));
+                unsafe { ring.push_socket(sock, libc::POLLIN, recv)? };
+                receiving = true;
+            }
+        }
+        if ring.is_idle() {
+            return Ok(received);
+        }
+
+        for (user_data, result) in ring.wait(pace.map(|p| p.deadline(received)))? {
+            let i = (user_data >> 8) as usize;
+            match user_data & 0xff {
+                POLL => {
+                    check(result)?;
+                }
+                SOCKET => {
+                    receiving = false;
+                    match check(result) {
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => free.push(i),
+                        Err(e) => return Err(e),
+                        Ok(0) => {
+                            eof = true;
+                            free.push(i);
+                        }
+                        Ok(n) => {
+                            on_data(&bufs[i][..n]);
+                            writing[i] = Some((offset + received, 0, n));
+                            received += n as u64;
+                            unsafe { ring.push(&[write_op(&bufs[i], writing[i].unwrap(), i)])? };
+                        }
+                    }
+                }
+                _ => {
+                    let n = check(result)?;
+                    let (position, start, end) = writing[i].take().unwrap();
+                    if n == 0 {
+                        return Err(ErrorKind::WriteZero.into());
+                    }
+                    if start + n < end {
+                        writing[i] = Some((position + n as u64, start + n, end));
+                        unsafe { ring.push(&[write_op(&bufs[i], writing[i].unwrap(), i)])? };
+                    } else {
+                        free.push(i);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Send `length` bytes of `file` starting at `offset` to `sock`. Returns the bytes sent,
+/// short if the file ends early (regular files only read short at their end)
+pub fn send(file: &File, offset: u64, length: u64, sock: RawFd, pace: Option<Pace>) -> io::Result<u64> {
+    // declared before the ring so they are dropped after it
+    let mut bufs = vec![vec![0u8; CHUNK]; DEPTH];
+    let mut ring = Ring::new()?;
+    let mut free: Vec<usize> = (0..DEPTH).collect();
+    // per buffer being read: (position relative to offset, bytes asked for)
+    let mut reading: Vec<Option<(u64, usize)>> = vec![None; DEPTH];
+    // read and waiting for their turn, by position: (buffer, length)
+    let mut filled = BTreeMap::new();
+    // the buffer being sent: (buffer, start, end)
+    let mut sending: Option<(usize, usize, usize)> = None;
+    let mut next_read = 0;
+    let mut eof = false;
+    let mut sent = 0;
+
+    let send_op = |buf: &[u8], start: usize, end: usize, i: usize| {
+        opcode::Send::new(Fd(sock), buf[start..].as_ptr(), (end - start) as u32)
+            .flags(libc::MSG_NOSIGNAL)
+            .build()
+            .user_data(tag(SOCKET, i))
+    };
+
+    loop {
+        while !eof && next_read < length {
+            let Some(i) = free.pop() else { break };
+            let want = (length - next_read).min(CHUNK as u64) as usize;
+            let read = opcode::Read::new(Fd(file.as_raw_fd()), bufs[i].as_mut_ptr(), want as u32)
+                .offset(offset + next_read)
+                .build()
+                .user_data(tag(FILE, i));
+            unsafe { ring.push(&[read])? };
+            reading[i] = Some((next_read, want));
+            next_read += want as u64;
+        }
+        // sends go out in order, one at a time
+        if sending.is_none() {
+            if let Some((i, n)) = filled.remove(&sent) {
+                sending = Some((i, 0, n));
+                unsafe { ring.push_socket(sock, libc::POLLOUT, send_op(&bufs[i], 0, n, i))? };
+            }
+        }
+        if ring.is_idle() {
+            return Ok(sent);
+        }
+
+        for (user_data, result) in ring.wait(pace.map(|p| p.deadline(sent)))? {
+            let i = (user_data >> 8) as usize;
+            match user_data & 0xff {
+                POLL => {
+                    check(result)?;
+                }
+                FILE => {
+                    let n = check(result)?;
+                    let (position, want) = reading[i].take().unwrap();
+                    if n > 0 {
+                        filled.insert(position, (i, n));
+                    } else {
+                        free.push(i);
+                    }
+                    // nothing after a short read is sent, the chunks read past it stay in `filled`
+                    eof |= n < want;
+                }
+                _ => {
+                    let (i, start, end) = sending.take().unwrap();
+                    let start = match check(result) {
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => start,
+                        Err(e) => return Err(e),
+                        Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                        Ok(n) => start + n,
+                    };
+                    if start < end {
+                        sending = Some((i, start, end));
+                        unsafe { ring.push_socket(sock, libc::POLLOUT, send_op(&bufs[i], start, end, i))? };
+                    } else {
+                        sent += end as u64;
+                        free.push(i);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `send` from an async handler, runs on the blocking pool with its own handle on the socket
+pub async fn send_file(file: File, offset: u64, length: u64, sock: &TcpStream, pace: Option<Pace>) -> io::Result<u64> {
+    let sock = sock.as_fd().try_clone_to_owned()?;
+    tokio::task::spawn_blocking(move || send(&file, offset, length, sock.as_raw_fd(), pace)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_receive_and_send() {
+        if !available() {
+            return;
+        }
+        let dir = TempDir::new("tcpfs-uring").unwrap();
+        let data: Vec<u8> = (0..CHUNK * DEPTH * 2 + 123).map(|i| (i % 251) as u8).collect();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // socket -> file, with the socket non blocking like the ones tokio hands out
+        let sender = std::thread::spawn({
+            let data = data.clone();
+            move || std::net::TcpStream::connect(addr).unwrap().write_all(&data).unwrap()
+        });
+        let (sock, _) = listener.accept().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let file = File::create_new(dir.path().join("received")).unwrap();
+        let mut seen = Vec::new();
+        let received = receive(sock.as_raw_fd(), &file, 3, data.len() as u64 + 10, None, |chunk| seen.extend_from_slice(chunk));
+        sender.join().unwrap();
+        assert_eq!(received.unwrap(), data.len() as u64);
+        assert!(seen == data);
+        let on_disk = std::fs::read(dir.path().join("received")).unwrap();
+        assert!(on_disk[3..] == data[..]);
+
+        // file -> socket, asking for more than there is
+        let receiver = std::thread::spawn(move || {
+            let mut out = Vec::new();
+            std::net::TcpStream::connect(addr).unwrap().read_to_end(&mut out).unwrap();
+            out
+        });
+        let (sock, _) = listener.accept().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let file = File::open(dir.path().join("received")).unwrap();
+        assert_eq!(send(&file, 3, u64::MAX - 3, sock.as_raw_fd(), None).unwrap(), data.len() as u64);
+        drop(sock);
+        assert!(receiver.join().unwrap() == data);
+    }
+
+    #[test]
+    fn test_stalled_receive() {
+        if !available() {
+            return;
+        }
+        let dir = TempDir::new("tcpfs-uring").unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(&[1; 10]).unwrap();
+        let (sock, _) = listener.accept().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let file = File::create_new(dir.path().join("stalled")).unwrap();
+
+        let pace = Pace::new(std::time::Duration::from_millis(50), 1000);
+        let error = receive(sock.as_raw_fd(), &file, 0, 1000, Some(pace), |_| ()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        drop(client);
+    }
+}
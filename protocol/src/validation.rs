@@ -0,0 +1,152 @@
+use std::{fmt, io};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest key (in bytes) we accept, checked before anything is allocated
+pub const MAX_KEY_LENGTH: u32 = 1024;
+/// Upper bound for the other length prefixed fields: api keys, tokens, principal names, certs
+pub const MAX_FIELD_LENGTH: u32 = 64 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    TooLong { length: u32, max: u32 },
+    Empty,
+    InvalidUtf8,
+    ControlCharacter,
+    Traversal,
+    EmptySegment,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::TooLong { length, max } => {
+                write!(f, "field is {} bytes, at most {} are allowed", length, max)
+            }
+            ValidationError::Empty => write!(f, "key is empty"),
+            ValidationError::InvalidUtf8 => write!(f, "not valid utf-8"),
+            ValidationError::ControlCharacter => write!(f, "contains control characters"),
+            ValidationError::Traversal => write!(f, "key contains '.' or '..' segments"),
+            ValidationError::EmptySegment => write!(f, "key has a leading, trailing or repeated '/'"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Read a length prefixed field, refusing (without reading it) anything longer than `max`
+pub async fn read_field<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    length: u32,
+    max: u32,
+) -> io::Result<Result<Vec<u8>, ValidationError>> {
+    if length > max {
+        return Ok(Err(ValidationError::TooLong { length, max }));
+    }
+    let mut buf = vec![0; length as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(Ok(buf))
+}
+
+pub async fn read_key<R: AsyncRead + Unpin>(stream: &mut R, length: u32) -> io::Result<Result<String, ValidationError>> {
+    Ok(read_field(stream, length, MAX_KEY_LENGTH).await?.and_then(parse_key))
+}
+
+/// like `read_key` but an empty prefix (the bucket root) is fine
+pub async fn read_prefix<R: AsyncRead + Unpin>(stream: &mut R, length: u32) -> io::Result<Result<String, ValidationError>> {
+    Ok(read_field(stream, length, MAX_KEY_LENGTH).await?.and_then(parse_prefix))
+}
+
+/// principal names, utf-8 without control characters
+pub async fn read_name<R: AsyncRead + Unpin>(stream: &mut R, length: u32) -> io::Result<Result<String, ValidationError>> {
+    Ok(read_field(stream, length, MAX_FIELD_LENGTH).await?.and_then(|raw| {
+        let name = String::from_utf8(raw).map_err(|_| ValidationError::InvalidUtf8)?;
+        match name.chars().any(|c| c.is_control()) {
+            true => Err(ValidationError::ControlCharacter),
+            false => Ok(name),
+        }
+    }))
+}
+
+fn checked(raw: Vec<u8>) -> Result<String, ValidationError> {
+    let key = String::from_utf8(raw).map_err(|_| ValidationError::InvalidUtf8)?;
+    if key.chars().any(|c| c.is_control()) {
+        return Err(ValidationError::ControlCharacter);
+    }
+    if key.is_empty() {
+        return Err(ValidationError::Empty);
+    }
+    // backslashes are looked at as separators too so "..\\" can't sneak through on windows
+    if key.split(['/', '\\']).any(|segment| segment == "." || segment == "..") {
+        return Err(ValidationError::Traversal);
+    }
+    if key.split('/').any(str::is_empty) {
+        return Err(ValidationError::EmptySegment);
+    }
+    Ok(key)
+}
+
+/// Keys are '/' joined segments and are stored as they come. anything that would have to be
+/// rewritten to fit (a leading, trailing or repeated slash) is refused, so no two keys name the same object
+pub fn parse_key(raw: Vec<u8>) -> Result<String, ValidationError> {
+    checked(raw)
+}
+
+/// Checked the same way as keys, a trailing '/' is optional. empty is the root
+pub fn parse_prefix(mut raw: Vec<u8>) -> Result<String, ValidationError> {
+    if raw.is_empty() {
+        return Ok(String::new());
+    }
+    if raw.last() == Some(&b'/') {
+        raw.pop();
+    }
+    Ok(format!("{}/", checked(raw)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(s: &str) -> Result<String, ValidationError> {
+        parse_key(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(key("logs/a.txt").unwrap(), "logs/a.txt");
+        // taken as they come, never rewritten into another key
+        assert_eq!(key("logs\\a.txt").unwrap(), "logs\\a.txt");
+        assert_eq!(key("..."), Ok("...".to_string()));
+        for s in ["/logs/a.txt", "logs//a.txt", "logs/a.txt/", "//"] {
+            assert_eq!(key(s), Err(ValidationError::EmptySegment));
+        }
+
+        assert_eq!(key(""), Err(ValidationError::Empty));
+        assert_eq!(key("../etc/passwd"), Err(ValidationError::Traversal));
+        assert_eq!(key("a/./b"), Err(ValidationError::Traversal));
+        assert_eq!(key("a\\..\\b"), Err(ValidationError::Traversal));
+        assert_eq!(key("a\0b"), Err(ValidationError::ControlCharacter));
+        assert_eq!(parse_key(vec![0xff, 0xfe]), Err(ValidationError::InvalidUtf8));
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        assert_eq!(parse_prefix(b"".to_vec()).unwrap(), "");
+        assert_eq!(parse_prefix(b"path/one".to_vec()).unwrap(), "path/one/");
+        assert_eq!(parse_prefix(b"path/one/".to_vec()).unwrap(), "path/one/");
+        assert_eq!(parse_prefix(b"/".to_vec()), Err(ValidationError::Empty));
+        assert_eq!(parse_prefix(b"/path/one".to_vec()), Err(ValidationError::EmptySegment));
+    }
+
+    #[tokio::test]
+    async fn test_length_checked_before_reading() {
+        // nothing to read, so this would fail with an io error if it tried
+        let mut empty: &[u8] = &[];
+        assert_eq!(
+            read_key(&mut empty, u32::MAX).await.unwrap(),
+            Err(ValidationError::TooLong { length: u32::MAX, max: MAX_KEY_LENGTH })
+        );
+        let mut data: &[u8] = b"a/b";
+        assert_eq!(read_key(&mut data, 3).await.unwrap().unwrap(), "a/b");
+    }
+}
@@ -0,0 +1,127 @@
+use std::{fs::File, io};
+
+use tokio::net::TcpStream;
+
+use crate::timeouts::{paced, Pace};
+
+/*
+ZERO COPY DOWNLOADS:
+plain (not encrypted) blobs that live in files are sent with sendfile(2) on linux, the bytes go
+from the page cache to the socket without passing through userspace. tls needs the bytes to
+encrypt them and encrypted blobs have to be decrypted, those take the buffered path in `lib.rs`.
+sendfile blocks while it reads pages that aren't cached, so the calls run on the blocking pool.
+other platforms get a buffered copy here too.
+*/
+
+/// most handed to one sendfile call, so a big download doesn't hold a blocking thread for long
+const MAX_CHUNK: u64 = 4 * 1024 * 1024;
+
+/// Send `length` bytes of `file` starting at `offset` to `sock`, returns what was sent.
+/// less than `length` means the file is shorter than it should be. a client that reads slower
+/// than `pace` gets a TimedOut error
+#[cfg(target_os = "linux")]
+pub async fn send_file(file: &File, offset: u64, length: u64, sock: &TcpStream, pace: Option<Pace>) -> io::Result<u64> {
+    use std::{
+        os::fd::{AsFd, AsRawFd},
+        sync::Arc,
+    };
+    use tokio::io::Interest;
+
+    // sendfile reads the file itself and blocks on the disk when the pages aren't cached, so each
+    // call runs on the blocking pool with its own handles and the worker only waits for the socket
+    let handles = Arc::new((file.try_clone()?, sock.as_fd().try_clone_to_owned()?));
+    let mut position = offset as libc::off_t;
+    let mut sent = 0;
+    while sent < length {
+        let chunk = (length - sent).min(MAX_CHUNK) as usize;
+        paced(pace, sent, sock.writable()).await?;
+        let handles = handles.clone();
+        let sending = tokio::task::spawn_blocking(move || {
+            let (file, sock) = &*handles;
+            // the socket is non blocking, sendfile says WouldBlock when it is full
+            match unsafe { libc::sendfile(sock.as_raw_fd(), file.as_raw_fd(), &mut position, chunk) } {
+                n if n < 0 => (position, Err(io::Error::last_os_error())),
+                n => (position, Ok(n as u64)),
+            }
+        });
+        let (moved, result) = paced(pace, sent, async { Ok(sending.await?) }).await?;
+        position = moved;
+        match result {
+            Ok(0) => break,
+            Ok(n) => sent += n,
+            // forget the readiness so the next writable() waits for room
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let _ = sock.try_io(Interest::WRITABLE, || Err::<(), _>(e));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn send_file(file: &File, offset: u64, length: u64, sock: &TcpStream, pace: Option<Pace>) -> io::Result<u64> {
+    use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+    let mut file = file.try_clone()?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = file.take(length);
+    let mut buf = vec![0; 64 * 1024];
+    let mut sent = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(sent);
+        }
+        let mut pending = &buf[..n];
+        while !pending.is_empty() {
+            paced(pace, sent, sock.writable()).await?;
+            match sock.try_write(pending) {
+                Ok(written) => pending = &pending[written..],
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        sent += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempdir::TempDir;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_file() {
+        let dir = TempDir::new("tcpfs-zerocopy").unwrap();
+        let data: Vec<u8> = (0..MAX_CHUNK + 1000).map(|i| (i % 251) as u8).collect();
+        let path = dir.path().join("blob");
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let file = File::open(&path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (sock, _) = listener.accept().await.unwrap();
+
+        let receiving = tokio::spawn(async move {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        // a range in the middle, then past the end of the file
+        let length = data.len() as u64 - 20;
+        assert_eq!(send_file(&file, 10, length, &sock, None).await.unwrap(), length);
+        assert_eq!(send_file(&file, 5, 100, &sock, None).await.unwrap(), 100);
+        assert_eq!(send_file(&file, data.len() as u64 - 3, 10, &sock, None).await.unwrap(), 3);
+        drop(sock);
+
+        let received = receiving.await.unwrap();
+        assert!(received[..length as usize] == data[10..data.len() - 10]);
+        assert!(received[length as usize..length as usize + 100] == data[5..105]);
+        assert_eq!(received[length as usize + 100..], data[data.len() - 3..]);
+    }
+}
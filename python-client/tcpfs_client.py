@@ -1,20 +1,104 @@
 from enum import Enum
+import hashlib
 from pathlib import Path
 import struct
 from typing import Optional
 import uuid
 import socket
+import ssl
 import argparse
+import sys
 import pdb
 
 
+class Status(Enum):
+    OK = 0x00
+    DENIED = 0x01
+    NOT_FOUND = 0x02
+    BAD_REQUEST = 0x03
+    INTERNAL = 0x04
+    CONFLICT = 0x05
+    QUOTA_EXCEEDED = 0x06
+    BUSY = 0x07
+
+
+class TcpfsError(Exception):
+    pass
+
+
+class ServerBusy(TcpfsError):
+    def __init__(self, retry_after_ms: int):
+        super().__init__(f"BUSY: retry after {retry_after_ms} ms")
+        self.retry_after_ms = retry_after_ms
+
+
+def auth_preamble(api_key: Optional[str], token: Optional[str] = None) -> bytes:
+    """AUTH (0x07) is sent in front of a command so it runs as the key's principal,
+    a presigned TOKEN (0x0D) stands in for it on a single upload or download"""
+    if token is not None:
+        raw = token.encode('ascii')
+        return struct.pack('>BI', 0x0D, len(raw)) + raw
+    if api_key is None:
+        return b""
+    key = api_key.encode('utf-8')
+    return struct.pack('>BI', 0x07, len(key)) + key
+
+
+def presign(host: str, port: int, bucket_id: uuid.UUID, key: str, operation: str, ttl: int,
+            api_key: Optional[str] = None) -> str:
+    op = {"download": 0x01, "upload": 0x02}[operation]
+    key_bytes = key.encode('utf-8')
+    request = auth_preamble(api_key) + struct.pack('>BBII16s', 0x0C, op, ttl, len(key_bytes),
+                                                   bucket_id.bytes) + key_bytes
+    with open_connection(host, port) as s:
+        s.sendall(request)
+        read_status(s)
+        length = struct.unpack('>I', recv_exact(s, 4))[0]
+        return recv_exact(s, length).decode('ascii')
+
+
+# set by --tls-ca, every connection is wrapped when present
+TLS_CONTEXT: Optional[ssl.SSLContext] = None
+
+
+def open_connection(host: str, port: int) -> socket.socket:
+    sock = socket.create_connection((host, port))
+    if TLS_CONTEXT is not None:
+        return TLS_CONTEXT.wrap_socket(sock, server_hostname=host)
+    return sock
+
+
+def recv_exact(s: socket.socket, n: int) -> bytes:
+    data = b""
+    while len(data) < n:
+        chunk = s.recv(n - len(data))
+        if not chunk:
+            raise TcpfsError("connection closed")
+        data += chunk
+    return data
+
+
+def read_status(s: socket.socket):
+    """every response starts with a status byte, errors carry a length prefixed message"""
+    status = Status(recv_exact(s, 1)[0])
+    if status == Status.BUSY:
+        raise ServerBusy(struct.unpack('>I', recv_exact(s, 4))[0])
+    if status != Status.OK:
+        length = struct.unpack('>I', recv_exact(s, 4))[0]
+        raise TcpfsError(f"{status.name}: {recv_exact(s, length).decode()}")
+
+
 class UploadRequest:
-    def __init__(self, relative_path: str, file_data: bytes, bucket_id: uuid.UUID):
+    # codec 0x01 (zstd) or 0x02 (lz4) sends `body`, file_data compressed with it, the checksum stays over file_data
+    def __init__(self, relative_path: str, file_data: bytes, bucket_id: uuid.UUID, codec: int = 0,
+                 body: Optional[bytes] = None):
         self.command_type = 0x01
         self.relative_path = relative_path.encode('utf-8')
         self.file_data = file_data
+        self.codec = codec
+        self.body = body if body is not None else file_data
         self.path_length = len(self.relative_path)
-        self.file_length = len(file_data)
+        self.file_length = len(self.body)
         self.bucket_id = bucket_id
 
     def to_bytes(self) -> bytes:
@@ -26,9 +110,12 @@ class UploadRequest:
                              self.path_length,
                              self.file_length,
                              self.bucket_id.bytes)
+        if self.codec:
+            # compressed flag on the command, the codec byte follows it
+            header = bytes([self.command_type | 0x80, self.codec]) + header[1:]
 
-        # Data: Relative Path (variable length) + File Data (variable length)
-        data = self.relative_path + self.file_data
+        # Data: Relative Path (variable length) + File Data (variable length) + SHA-256 of File Data (32 bytes)
+        data = self.relative_path + self.body + hashlib.sha256(self.file_data).digest()
 
         return header + data
     
@@ -72,34 +159,31 @@ class ListRequest:
         return header
 
 
-def send_upload_request(server_ip: str, server_port: int, upload_request: UploadRequest):
+def send_upload_request(server_ip: str, server_port: int, upload_request: UploadRequest,
+                        api_key: Optional[str] = None, token: Optional[str] = None):
     # Convert the request to bytes
-    request_bytes = upload_request.to_bytes()
+    request_bytes = auth_preamble(api_key, token) + upload_request.to_bytes()
 
     # Open a TCP socket
-    with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as s:
+    print(f"Connecting to {server_ip}:{server_port}...")
+    with open_connection(server_ip, server_port) as s:
         try:
-            # Connect to the server
-            print(f"Connecting to {server_ip}:{server_port}...")
-            s.connect((server_ip, server_port))
-
             # Send the upload request
             print("Sending upload request...")
             s.sendall(request_bytes)
 
-            # Wait for a response (optional)
-            response = s.recv(1024)
-            print(f"Response from server: {response.decode()}")
+            read_status(s)
+            print("upload complete")
         except Exception as e:
             print(f"Error occurred: {e}")
 
-def list_bucket(server_ip: str, server_port: int, list_request: ListRequest):
+def list_bucket(server_ip: str, server_port: int, list_request: ListRequest, api_key: Optional[str] = None):
     objects = {}
-    request_bytes = list_request.to_bytes()
-    with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as s:
-        print(f"Connecting to {server_ip}:{server_port}...")
-        s.connect((server_ip, server_port))
+    request_bytes = auth_preamble(api_key) + list_request.to_bytes()
+    print(f"Connecting to {server_ip}:{server_port}...")
+    with open_connection(server_ip, server_port) as s:
         s.sendall(request_bytes)
+        read_status(s)
         while True:
             # Read the 1-byte file/dir indicator
             print("fetching file indicator")
@@ -165,19 +249,24 @@ class Command(Enum):
 
 
 
-def send_download_request_and_receive_response(host, port, bucket_id, relative_path):
-    # Command type for DOWNLOAD (1 byte)
-    command_type = 0x02
+def send_download_request_and_receive_response(host, port, bucket_id, relative_path, api_key=None, token=None,
+                                                byte_range=None, codec=0):
+    # Command type for DOWNLOAD (1 byte), DOWNLOAD RANGE if a (offset, length) range is given.
+    # with a codec (0x01 zstd, 0x02 lz4) the data comes back compressed with it
+    command_type = 0x02 if byte_range is None else 0x0E
 
     # Convert the relative path to bytes and calculate its length
     relative_path_bytes = relative_path.encode('utf-8')
     path_length = len(relative_path_bytes)
 
     # Construct the DOWNLOAD REQUEST manually as bytes
-    request = bytearray()
+    request = bytearray(auth_preamble(api_key, token))
 
     # Append the command type (1 byte)
-    request.append(command_type)
+    if codec:
+        request += bytes([command_type | 0x80, codec])
+    else:
+        request.append(command_type)
 
     # Append the path length (4 bytes, big-endian)
     request += path_length.to_bytes(4, 'big')
@@ -185,6 +274,10 @@ def send_download_request_and_receive_response(host, port, bucket_id, relative_p
     # Append the bucket_id (16 bytes)
     request += uuid.UUID(bucket_id).bytes
 
+    if byte_range is not None:
+        request += byte_range[0].to_bytes(8, 'big')
+        request += byte_range[1].to_bytes(8, 'big')
+
     # Append the relative path (variable length)
     request += relative_path_bytes
 
@@ -193,9 +286,10 @@ def send_download_request_and_receive_response(host, port, bucket_id, relative_p
 
     try:
         # Create a socket connection to the server
-        with socket.create_connection((host, port)) as sock:
+        with open_connection(host, port) as sock:
             # Send the request
             sock.sendall(request)
+            read_status(sock)
 
             # Receive the file data (variable length)
             buffer_size = 4096  # You can adjust the buffer size
@@ -210,31 +304,202 @@ def send_download_request_and_receive_response(host, port, bucket_id, relative_p
     return file_data
 
 
+def set_bucket_option(host: str, port: int, bucket_id: uuid.UUID, option: int, value: int,
+                      api_key: Optional[str] = None):
+    request = bytearray(auth_preamble(api_key))
+    request.append(0x0F)
+    request += bucket_id.bytes
+    request += bytes([option, value])
+    with open_connection(host, port) as s:
+        s.sendall(request)
+        read_status(s)
+
+
+def stat(host: str, port: int, bucket_id: uuid.UUID, api_key: Optional[str] = None):
+    """(logical, physical, compressed logical, compressed physical) bytes used by a bucket, the nil uuid asks
+    for the whole store. the last two cover objects stored compressed, their quotient is the compression ratio"""
+    request = bytearray(auth_preamble(api_key))
+    request.append(0x10)
+    request += bucket_id.bytes
+    with open_connection(host, port) as s:
+        s.sendall(request)
+        read_status(s)
+        return struct.unpack('>QQQQ', recv_exact(s, 32))
+
+
+def compact(host: str, port: int, api_key: Optional[str] = None) -> int:
+    """rewrite mostly dead segments (admin), returns the bytes reclaimed"""
+    with open_connection(host, port) as s:
+        s.sendall(auth_preamble(api_key) + bytes([0x11]))
+        read_status(s)
+        return struct.unpack('>Q', recv_exact(s, 8))[0]
+
+
+RATE_SCOPES = {"global": 0, "key": 1, "bucket": 2}
+
+
+def set_rate_limit(host: str, port: int, scope: str, bytes_per_sec: int, requests_per_sec: int,
+                   name: str = "", bucket_id: uuid.UUID = uuid.UUID(int=0), api_key: Optional[str] = None):
+    """(admin) 0 is unlimited, an empty name or the nil bucket changes the default for the scope"""
+    name_bytes = name.encode()
+    request = bytearray(auth_preamble(api_key))
+    request += bytes([0x12, RATE_SCOPES[scope]])
+    request += bucket_id.bytes
+    request += struct.pack('>QQI', bytes_per_sec, requests_per_sec, len(name_bytes))
+    request += name_bytes
+    with open_connection(host, port) as s:
+        s.sendall(request)
+        read_status(s)
+
+
+def rate_limits(host: str, port: int, api_key: Optional[str] = None):
+    """(admin) [(scope, bucket_id, name, bytes/sec, requests/sec)], defaults first"""
+    scopes = {v: k for k, v in RATE_SCOPES.items()}
+    with open_connection(host, port) as s:
+        s.sendall(auth_preamble(api_key) + bytes([0x13]))
+        read_status(s)
+        (count,) = struct.unpack('>I', recv_exact(s, 4))
+        limits = []
+        for _ in range(count):
+            scope = recv_exact(s, 1)[0]
+            bucket_id = uuid.UUID(bytes=recv_exact(s, 16))
+            bytes_per_sec, requests_per_sec, name_length = struct.unpack('>QQI', recv_exact(s, 20))
+            name = recv_exact(s, name_length).decode()
+            limits.append((scopes[scope], bucket_id, name, bytes_per_sec, requests_per_sec))
+        return limits
+
+
+def multipart_upload(host: str, port: int, bucket_id: uuid.UUID, key: str, path: str,
+                     part_size: int = 64 * 1024 * 1024, connections: int = 8, api_key: Optional[str] = None) -> int:
+    """upload the file at `path` in parts over `connections` connections at once, returns the object size"""
+    from concurrent.futures import ThreadPoolExecutor
+
+    key_bytes = key.encode()
+    with open_connection(host, port) as s:
+        s.sendall(auth_preamble(api_key) + struct.pack('>BI16s', 0x14, len(key_bytes), bucket_id.bytes) + key_bytes)
+        read_status(s)
+        upload_id = recv_exact(s, 16)
+
+    size = Path(path).stat().st_size
+    numbers = range(1, max(1, -(-size // part_size)) + 1)
+
+    def send_part(number: int) -> bytes:
+        with open(path, 'rb') as f:
+            f.seek((number - 1) * part_size)
+            data = f.read(part_size)
+        checksum = hashlib.sha256(data).digest()
+        with open_connection(host, port) as s:
+            s.sendall(auth_preamble(api_key) + bytes([0x15]) + upload_id + struct.pack('>II', number, len(data)))
+            s.sendall(data + checksum)
+            read_status(s)
+        return checksum
+
+    try:
+        with ThreadPoolExecutor(connections) as pool:
+            checksums = list(pool.map(send_part, numbers))
+    except Exception:
+        with open_connection(host, port) as s:
+            s.sendall(auth_preamble(api_key) + bytes([0x17]) + upload_id)
+        raise
+
+    request = bytearray(auth_preamble(api_key))
+    request += bytes([0x16]) + upload_id + struct.pack('>I', len(checksums))
+    for number, checksum in zip(numbers, checksums):
+        request += struct.pack('>I', number) + checksum
+    with open_connection(host, port) as s:
+        s.sendall(request)
+        read_status(s)
+        return struct.unpack('>Q', recv_exact(s, 8))[0]
+
+
+def chunked_upload(host: str, port: int, bucket_id: uuid.UUID, key: str, stream,
+                   chunk_size: int = 1024 * 1024, api_key: Optional[str] = None, token: Optional[str] = None):
+    """upload everything read from the file-like `stream` without knowing its length up front"""
+    key_bytes = key.encode()
+    digest = hashlib.sha256()
+    with open_connection(host, port) as s:
+        s.sendall(auth_preamble(api_key, token) + struct.pack('>BI16s', 0x18, len(key_bytes), bucket_id.bytes) + key_bytes)
+        while chunk := stream.read(chunk_size):
+            digest.update(chunk)
+            s.sendall(struct.pack('>I', len(chunk)) + chunk)
+        # the empty chunk ends the body, the trailer carries the checksum
+        s.sendall(struct.pack('>IB', 0, 1) + digest.digest())
+        read_status(s)
+
+
+def append(host: str, port: int, bucket_id: uuid.UUID, key: str, data: bytes,
+           expected_length: Optional[int] = None, api_key: Optional[str] = None) -> int:
+    """add `data` to the end of the object, only if it is `expected_length` bytes when given. returns the new size"""
+    key_bytes = key.encode()
+    expected = expected_length if expected_length is not None else 2**64 - 1
+    header = struct.pack('>BII16sQ', 0x19, len(key_bytes), len(data), bucket_id.bytes, expected)
+    with open_connection(host, port) as s:
+        s.sendall(auth_preamble(api_key) + header + key_bytes + data + hashlib.sha256(data).digest())
+        read_status(s)
+        return struct.unpack('>Q', recv_exact(s, 8))[0]
+
+
 def main():
     # Argument parsing
     parser = argparse.ArgumentParser(description="Send an upload request to a TCPFS server.")
     parser.add_argument("--host", type=str, required=True, help="The server IP or hostname to connect to.")
     parser.add_argument("--port", type=int, required=True, help="The port on the server to connect to.")
+    parser.add_argument("--api-key", type=str, required=False, help="Authenticate as the principal owning this key.")
+    parser.add_argument("--tls-ca", type=str, required=False, help="Connect over TLS, trusting this CA bundle.")
+    parser.add_argument("--tls-cert", type=str, required=False, help="Client certificate for mutual TLS.")
+    parser.add_argument("--tls-key", type=str, required=False, help="Private key for --tls-cert.")
     
     subparsers = parser.add_subparsers(dest="command", help="tcpfs commands") 
     upload_parser = subparsers.add_parser(name="upload")
     upload_parser.add_argument("--key", type=str, required=True, help="The key of the file being uploaded. "
                                                                "This can be a path on the server")
-    upload_parser.add_argument("--file", type=str, required=True, help="The path to the file to be uploaded.")
+    upload_parser.add_argument("--file", type=str, required=True, help="The path to the file to be uploaded, "
+                                                                         "- streams stdin in chunks.")
     upload_parser.add_argument("--bucket", type=str, help="The bucket UUID (will be auto-generated if not provided).")
+    upload_parser.add_argument("--token", type=str, help="Presigned upload token, replaces --api-key.")
 
     download_parser = subparsers.add_parser(name="download")
     download_parser.add_argument("--key", type=str, required=True, help="They key of the file being uploaded")
     download_parser.add_argument("--bucket", type=str, required=True, help="bucket id, UUID")
     download_parser.add_argument("--destination", type=str, required=True, help="Where to write the file")
+    download_parser.add_argument("--token", type=str, help="Presigned download token, replaces --api-key.")
+    download_parser.add_argument("--offset", type=int, help="Only download from this byte on.")
+    download_parser.add_argument("--length", type=int, help="Only download this many bytes.")
+
+    encrypt_parser = subparsers.add_parser(name="encrypt-bucket", help="Turn encryption at rest on or off (admin).")
+    encrypt_parser.add_argument("--bucket", type=str, required=True)
+    encrypt_parser.add_argument("--off", action="store_true")
+
+    presign_parser = subparsers.add_parser(name="presign")
+    presign_parser.add_argument("--key", type=str, required=True)
+    presign_parser.add_argument("--bucket", type=str, required=True)
+    presign_parser.add_argument("--op", type=str, choices=["download", "upload"], default="download")
+    presign_parser.add_argument("--ttl", type=int, default=3600, help="Seconds until the token expires.")
+
+    stat_parser = subparsers.add_parser(name="stat", help="Logical and physical bytes used.")
+    stat_parser.add_argument("--bucket", type=str, default=str(uuid.UUID(int=0)),
+                             help="bucket id, the whole store (admin) if omitted")
+
+    subparsers.add_parser(name="compact", help="Reclaim space left by deleted packed objects (admin).")
 
     list_parser = subparsers.add_parser(name="list")
     list_parser.add_argument("--key", type=str, required=False, default=".")
     list_parser.add_argument("--bucket", type=str, required=True)
 
     args = parser.parse_args()
+    if args.tls_ca:
+        global TLS_CONTEXT
+        TLS_CONTEXT = ssl.create_default_context(cafile=args.tls_ca)
+        if args.tls_cert:
+            TLS_CONTEXT.load_cert_chain(args.tls_cert, args.tls_key)
     if args.command == "upload":
         print("uploading file")
+        if args.file == "-":
+            bucket_id = uuid.UUID(args.bucket) if args.bucket else uuid.uuid4()
+            print(bucket_id)
+            chunked_upload(args.host, args.port, bucket_id, args.key, sys.stdin.buffer, api_key=args.api_key,
+                           token=args.token)
+            return
         # Read the file data
         try:
             with open(args.file, 'rb') as f:
@@ -252,26 +517,43 @@ def main():
 
         # Command type is hardcoded to 0x01 for upload, can be changed if needed
 
-t       # Create an UploadRequest instance
+        # Create an UploadRequest instance
         upload_request = UploadRequest(args.key, file_data, bucket_id)
 
         # Send the upload request to the server
-        send_upload_request(args.host, args.port, upload_request)
+        send_upload_request(args.host, args.port, upload_request, args.api_key, args.token)
     
     if args.command == "download":
         print("download file")
-        ret = send_download_request_and_receive_response(args.host, args.port, args.bucket, args.key)
+        byte_range = None
+        if args.offset is not None or args.length is not None:
+            byte_range = (args.offset or 0, args.length if args.length is not None else 2**64 - 1)
+        ret = send_download_request_and_receive_response(args.host, args.port, args.bucket, args.key, args.api_key,
+                                                         args.token, byte_range)
         with open(args.destination, 'wb') as f:
             f.write(ret)
 
 
+    if args.command == "encrypt-bucket":
+        set_bucket_option(args.host, args.port, uuid.UUID(args.bucket), 0x01, 0 if args.off else 1, args.api_key)
+
+    if args.command == "stat":
+        logical, physical = stat(args.host, args.port, uuid.UUID(args.bucket), args.api_key)
+        print(f"logical: {logical} bytes, physical: {physical} bytes")
+
+    if args.command == "compact":
+        print(f"reclaimed {compact(args.host, args.port, args.api_key)} bytes")
+
+    if args.command == "presign":
+        print(presign(args.host, args.port, uuid.UUID(args.bucket), args.key, args.op, args.ttl, args.api_key))
+
     if args.command == "list":
         print("geting object list")
         print(args.bucket)
         bucket_id = uuid.UUID(args.bucket)
         print(bucket_id)
         lr = ListRequest(path_from=args.key, bucket_id=bucket_id)
-        objects = list_bucket(args.host, args.port, lr)
+        objects = list_bucket(args.host, args.port, lr, args.api_key)
 
 if __name__ == "__main__":
     main()
@@ -0,0 +1,33 @@
+use std::{env, error::Error, path::PathBuf};
+
+use meta_redb::RedbMetadataStore;
+use meta_sqlite::SqliteMetadataStore;
+use meta_store::MetadataStore;
+use protocol::placement;
+
+/// Moves blobs written under the old timestamp layout into the fan-out layout.
+/// usage: migrate_blobs <db_path> <working_dir> [--dry-run]
+/// run it with the server stopped, it is safe to re-run if interrupted.
+/// TCPFS_METADATA=redb has to match what the server runs with.
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+    let (db_path, working_dir) = match (args.get(1), args.get(2)) {
+        (Some(db_path), Some(working_dir)) => (db_path.clone(), PathBuf::from(working_dir)),
+        _ => Err("usage: migrate_blobs <db_path> <working_dir> [--dry-run]")?,
+    };
+    let dry_run = args.iter().skip(3).any(|a| a == "--dry-run");
+
+    let metadata: Box<dyn MetadataStore> = match env::var("TCPFS_METADATA").as_deref() {
+        Ok("sqlite") | Err(_) => Box::new(SqliteMetadataStore::open(Some(db_path))?),
+        Ok("redb") => Box::new(RedbMetadataStore::open(&PathBuf::from(db_path))?),
+        Ok(other) => Err(format!("unknown TCPFS_METADATA {}", other))?,
+    };
+    let report = placement::migrate_legacy_blobs(metadata.as_ref(), &working_dir, dry_run)?;
+    println!(
+        "{} {} blobs, {} missing",
+        if dry_run { "would move" } else { "moved" },
+        report.moved,
+        report.missing
+    );
+    Ok(())
+}
@@ -2,20 +2,48 @@ use std::{
     env,
     error::Error,
     fs,
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
     path::PathBuf,
-    time::SystemTime
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
-use chrono::Datelike;
-use chrono::Timelike;
-use chrono::prelude::{DateTime, Utc};
 
-use meta_sqlite;
-use protocol;
+use meta_redb::RedbMetadataStore;
+use meta_sqlite::{PoolConfig, SqliteMetadataStore};
+use meta_store::MetadataStore;
+use protocol::{
+    acl::{self, AclMode},
+    crypto::MasterKey,
+    limits::{ConnectionLimits, LimitConfig},
+    ratelimit::{Rate, RateConfig},
+    store::{BlobStore, FsBlobStore, MemoryBlobStore},
+    stream::ClientStream,
+    timeouts::Timeouts,
+    tls, HandlerConfig, RequestHandler,
+};
+use tokio::net::TcpListener;
 
+/// `name` parsed from the environment, `default` when it isn't set
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T, Box<dyn Error + Send + Sync>> {
+    match env::var(name) {
+        Ok(value) => Ok(value.parse().map_err(|_| format!("invalid {} {}", name, value))?),
+        Err(_) => Ok(default),
+    }
+}
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    // TCPFS_WORKERS threads run the connections (default one per core),
+    // TCPFS_BLOCKING_THREADS caps the pool metadata and disk calls run on
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Ok(workers) = env::var("TCPFS_WORKERS") {
+        runtime.worker_threads(workers.parse().map_err(|_| format!("invalid TCPFS_WORKERS {}", workers))?);
+    }
+    runtime.max_blocking_threads(env_or("TCPFS_BLOCKING_THREADS", 512)?);
+    runtime.build()?.block_on(run())
+}
+
+async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
     let args: Vec<String> = env::args().collect();
     let default_host = "127.0.01".to_string();
     let defualt_port = "8888".to_string();
@@ -23,11 +51,18 @@ fn main() -> Result<(), Box<dyn Error>> {
     let host = args.get(1).unwrap_or(&default_host);
     let port = args.get(2).unwrap_or(&defualt_port);
 
+    // TCPFS_METADATA=redb keeps metadata in an embedded redb file instead of sqlite
+    let metadata_backend = env::var("TCPFS_METADATA").unwrap_or("sqlite".to_string());
+    let default_db_name = match metadata_backend.as_str() {
+        "redb" => "metadata.redb",
+        _ => "metadata.db",
+    };
+
     let default_common_dir = ".tcpfs_store";
     let default_db_path = env::current_dir()
         .unwrap()
         .join(&default_common_dir)
-        .join("metadata.db")
+        .join(default_db_name)
         .to_string_lossy()
         .to_string();
     let db_path = args.get(3).unwrap_or(&default_db_path);
@@ -41,21 +76,184 @@ fn main() -> Result<(), Box<dyn Error>> {
         .to_string();
     let working_dir: PathBuf = PathBuf::from(args.get(4).unwrap_or(&default_working_dir));
 
+    // TCPFS_STORE=memory keeps blobs in memory (testing only), otherwise they go under working_dir
+    let store: Arc<dyn BlobStore> = match env::var("TCPFS_STORE").as_deref() {
+        Ok("memory") => Arc::new(MemoryBlobStore::new()),
+        Ok("fs") | Err(_) => Arc::new(FsBlobStore::open(&working_dir)?),
+        Ok(other) => Err(format!("unknown TCPFS_STORE {}", other))?,
+    };
+
     let addr = format!("{}:{}", host, port);
-    let listener = TcpListener::bind(addr.clone())?;
+    let listener = TcpListener::bind(addr.clone()).await?;
 
     println!("Server listening on port {addr}");
 
-    // FIXME: get con initializes the db, when inevitably we re-work the db connection stuff (maybe with a MVCC DB)
-    // we should refactor this to be clearer
-    let _ = meta_sqlite::get_connection(Some(db_path.clone()));
+    // TCPFS_DB_POOL_SIZE / TCPFS_DB_BUSY_TIMEOUT_MS tune the sqlite connection pool
+    let mut pool_config = PoolConfig::default();
+    if let Ok(size) = env::var("TCPFS_DB_POOL_SIZE") {
+        pool_config.max_size = size.parse().map_err(|_| format!("invalid TCPFS_DB_POOL_SIZE {}", size))?;
+    }
+    if let Ok(ms) = env::var("TCPFS_DB_BUSY_TIMEOUT_MS") {
+        let ms = ms.parse().map_err(|_| format!("invalid TCPFS_DB_BUSY_TIMEOUT_MS {}", ms))?;
+        pool_config.busy_timeout = Duration::from_millis(ms);
+    }
+
+    let metadata: Arc<dyn MetadataStore> = match metadata_backend.as_str() {
+        "sqlite" => Arc::new(SqliteMetadataStore::open_with(Some(db_path.clone()), pool_config)?),
+        "redb" => Arc::new(RedbMetadataStore::open(&PathBuf::from(db_path))?),
+        other => Err(format!("unknown TCPFS_METADATA {}", other))?,
+    };
+    println!("Metadata: {} at {}", metadata_backend, db_path);
+
+    // TCPFS_ACL_MODE=deny refuses anything that wasn't explicitly granted
+    let acl_mode = match env::var("TCPFS_ACL_MODE") {
+        Ok(mode) => AclMode::parse(&mode).ok_or(format!("unknown TCPFS_ACL_MODE {}", mode))?,
+        Err(_) => AclMode::Open,
+    };
+    println!("ACL mode: {:?}", acl_mode);
+
+    // bootstrap an admin so grants can be handed out over the wire
+    if let Ok(admin_key) = env::var("TCPFS_ADMIN_KEY") {
+        metadata.upsert_principal("admin", &acl::hash_api_key(admin_key.as_bytes()), true)?;
+    }
+
+    // TCPFS_TLS_CERT + TCPFS_TLS_KEY turn on tls, TCPFS_TLS_CLIENT_CA additionally verifies client certs
+    let tls_config = match (env::var("TCPFS_TLS_CERT"), env::var("TCPFS_TLS_KEY")) {
+        (Ok(cert), Ok(key)) => {
+            let client_ca = env::var("TCPFS_TLS_CLIENT_CA").ok().map(PathBuf::from);
+            println!("TLS enabled, client certificates: {}", client_ca.is_some());
+            Some(tls::server_config(
+                &PathBuf::from(cert),
+                &PathBuf::from(key),
+                client_ca.as_deref(),
+            )?)
+        }
+        (Err(_), Err(_)) => None,
+        _ => Err("TCPFS_TLS_CERT and TCPFS_TLS_KEY have to be set together")?,
+    };
+
+    // presigned tokens are signed with this, without it tokens stop working once the server restarts
+    let token_secret = match env::var("TCPFS_TOKEN_SECRET") {
+        Ok(secret) => secret.into_bytes(),
+        Err(_) => {
+            println!("TCPFS_TOKEN_SECRET not set, using a random secret");
+            rand::random::<[u8; 32]>().to_vec()
+        }
+    };
+
+    // TCPFS_MASTER_KEYFILE wraps the data keys of buckets with encryption turned on
+    let master_key = match env::var("TCPFS_MASTER_KEYFILE") {
+        Ok(path) => {
+            println!("Encryption at rest available, master key from {}", path);
+            Some(MasterKey::from_file(&PathBuf::from(path))?)
+        }
+        Err(_) => None,
+    };
+
+    // TCPFS_DEDUP=1 stores identical uploads once, only affects new uploads
+    let dedup = env::var("TCPFS_DEDUP").is_ok_and(|v| v == "1" || v == "true");
+    println!("Deduplication: {}", dedup);
+
+    // TCPFS_PACK_THRESHOLD=<bytes> packs smaller objects into segment files
+    let pack_threshold = match env::var("TCPFS_PACK_THRESHOLD") {
+        Ok(threshold) => threshold.parse::<u32>().map_err(|_| format!("invalid TCPFS_PACK_THRESHOLD {}", threshold))?,
+        Err(_) => 0,
+    };
+    println!("Packing objects below {} bytes", pack_threshold);
+
+    // TCPFS_BUCKET_QUOTA=<bytes> caps what each bucket holds, checked as upload bodies arrive
+    let bucket_quota: u64 = env_or("TCPFS_BUCKET_QUOTA", 0)?;
+    if bucket_quota > 0 {
+        println!("Buckets hold at most {} bytes", bucket_quota);
+    }
+
+    // TCPFS_RATE_BYTES / TCPFS_RATE_REQUESTS cap the whole server per second, TCPFS_KEY_RATE_* each principal
+    // and TCPFS_BUCKET_RATE_* each bucket. unset or 0 is unlimited, admins can change them with SET RATE LIMIT
+    let rate = |prefix: &str| -> Result<Rate, Box<dyn Error + Send + Sync>> {
+        Ok(Rate {
+            bytes_per_sec: env_or(&format!("{}_BYTES", prefix), 0)?,
+            requests_per_sec: env_or(&format!("{}_REQUESTS", prefix), 0)?,
+        })
+    };
+    let rates = RateConfig {
+        global: rate("TCPFS_RATE")?,
+        per_key: rate("TCPFS_KEY_RATE")?,
+        per_bucket: rate("TCPFS_BUCKET_RATE")?,
+    };
+    println!("Rate limits: {:?}", rates);
+
+    let handler = Arc::new(RequestHandler::new(HandlerConfig {
+        metadata,
+        store,
+        acl_mode,
+        token_secret,
+        master_key,
+        dedup,
+        pack_threshold,
+        rates,
+        bucket_quota,
+    }));
+
+    // TCPFS_MAX_CONNECTIONS caps the clients served at once, TCPFS_MAX_CONNECTIONS_PER_IP those from one address.
+    // up to TCPFS_MAX_QUEUED wait for a slot, the rest are told to retry after TCPFS_BUSY_RETRY_MS
+    let defaults = LimitConfig::default();
+    let limit_config = LimitConfig {
+        max_connections: env_or("TCPFS_MAX_CONNECTIONS", defaults.max_connections)?,
+        max_per_ip: env_or("TCPFS_MAX_CONNECTIONS_PER_IP", defaults.max_per_ip)?,
+        max_queued: env_or("TCPFS_MAX_QUEUED", defaults.max_queued)?,
+        retry_after: Duration::from_millis(env_or("TCPFS_BUSY_RETRY_MS", defaults.retry_after.as_millis() as u64)?),
+    };
+    println!("Connection limits: {:?}", limit_config);
+    let limits = ConnectionLimits::new(limit_config);
+
+    // TCPFS_IDLE_TIMEOUT_MS before a command arrives, TCPFS_HEADER_TIMEOUT_MS to send its header (and the tls handshake).
+    // bodies have to keep up with TCPFS_MIN_TRANSFER_RATE bytes/sec after TCPFS_TRANSFER_GRACE_MS, 0 turns that off
+    let defaults = Timeouts::default();
+    let timeouts = Timeouts {
+        idle: Duration::from_millis(env_or("TCPFS_IDLE_TIMEOUT_MS", defaults.idle.as_millis() as u64)?),
+        header: Duration::from_millis(env_or("TCPFS_HEADER_TIMEOUT_MS", defaults.header.as_millis() as u64)?),
+        min_rate: env_or("TCPFS_MIN_TRANSFER_RATE", defaults.min_rate)?,
+        grace: Duration::from_millis(env_or("TCPFS_TRANSFER_GRACE_MS", defaults.grace.as_millis() as u64)?),
+    };
+    println!("Timeouts: {:?}", timeouts);
 
     loop {
-        let (stream, _) = listener.accept()?;
-        let db_path = db_path.clone();
-        let working_dir = working_dir.clone();
-        std::thread::spawn(move || {
-            if let Err(e) = protocol::RequestHandler::handle_client(stream, Some(&db_path), &working_dir) {
+        let (stream, addr) = listener.accept().await?;
+        let handler = handler.clone();
+        let tls_config = tls_config.clone();
+        let limits = limits.clone();
+        tokio::spawn(async move {
+            // connections that won't be served don't get a tls handshake, the client just sees the
+            // connection close. otherwise anyone over the limits could still tie up a task per socket
+            let _slot = match limits.admit(addr.ip()).await {
+                Some(slot) => slot,
+                None => {
+                    println!("server busy, turned away {}", addr);
+                    if tls_config.is_none() {
+                        if let Err(e) = RequestHandler::turn_away(ClientStream::plain(stream), limits.retry_after()).await {
+                            eprintln!("Error handling client: {:?}", e);
+                        }
+                    }
+                    return;
+                }
+            };
+            let mut stream = match tls_config {
+                Some(config) => match tokio::time::timeout(timeouts.header, tls::accept(config, stream)).await {
+                    Ok(Ok(s)) => s,
+                    Ok(Err(e)) => {
+                        eprintln!("TLS handshake failed: {:?}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        println!("closing slow connection from {}: tls handshake timed out", addr);
+                        return;
+                    }
+                },
+                None => ClientStream::plain(stream),
+            };
+            stream.set_timeouts(timeouts);
+            let result = handler.handle_client(stream).await;
+            if let Err(e) = result {
                 eprintln!("Error handling client: {:?}", e);
             }
         });